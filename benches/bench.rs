@@ -1,6 +1,11 @@
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use myqueue::queue::{Queue, LockQueue, SingleVecLockQueue};
+use myqueue::queue::{Queue, LockQueue, SingleVecLockQueue, AdaptiveQueue, HazardQueue};
+use myqueue::lockfree_map::LockFreeHashMap;
+use myqueue::cuckoo_map::CuckooHashMap;
+use myqueue::workload::{KeyDistribution, Op, Workload};
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::thread::spawn;
 use std::sync::{Arc, Barrier};
 
@@ -118,6 +123,272 @@ fn bench_single_vec_lock_concurrent_queue(c: &mut Criterion) {
     });
 }
 
+//Benchmarking the adaptive queue
+fn bench_adaptive_queue(c: &mut Criterion) {
+    let queue = AdaptiveQueue::new();
+    c.bench_function("adaptive_queue", |b| {
+        b.iter(|| {
+            queue.enqueue(black_box(1000000));
+            queue.dequeue();
+        })
+    });
+}
+
+//Benchmarking the adaptive queue in a concurrent setting
+fn bench_adaptive_concurrent_queue(c: &mut Criterion) {
+    let queue = Arc::new(AdaptiveQueue::<usize>::new());
+    let barrier = Arc::new(Barrier::new(2));
+    
+    c.bench_function("adaptive_concurrent_queue", |b| {
+        b.iter(|| {
+            let barrier_clone = Arc::clone(&barrier);
+            let queue_clone1 = Arc::clone(&queue);
+            let queue_clone2 = Arc::clone(&queue);
+
+            let handle = spawn(move || {
+                barrier_clone.wait();
+                queue_clone1.enqueue(black_box(1000000));
+            });
+
+            // The main thread also waits on the barrier to ensure synchronization
+            barrier.wait();
+
+            // Perform dequeue operation in the main test thread
+            queue_clone2.dequeue();
+
+            handle.join().unwrap();
+        })
+    });
+}
+
+//Benchmarking the hazard pointer queue
+fn bench_hazard_queue(c: &mut Criterion) {
+    let queue = HazardQueue::new();
+    c.bench_function("hazard_queue", |b| {
+        b.iter(|| {
+            queue.enqueue(black_box(1000000));
+            queue.dequeue();
+        })
+    });
+}
+
+//Benchmarking the hazard pointer queue in a concurrent setting
+fn bench_hazard_concurrent_queue(c: &mut Criterion) {
+    let queue = Arc::new(HazardQueue::<usize>::new());
+    let barrier = Arc::new(Barrier::new(2));
+    
+    c.bench_function("hazard_concurrent_queue", |b| {
+        b.iter(|| {
+            let barrier_clone = Arc::clone(&barrier);
+            let queue_clone1 = Arc::clone(&queue);
+            let queue_clone2 = Arc::clone(&queue);
+
+            let handle = spawn(move || {
+                barrier_clone.wait();
+                queue_clone1.enqueue(black_box(1000000));
+            });
+
+            // The main thread also waits on the barrier to ensure synchronization
+            barrier.wait();
+
+            // Perform dequeue operation in the main test thread
+            queue_clone2.dequeue();
+
+            handle.join().unwrap();
+        })
+    });
+}
+
+//Benchmarking the lockfree hashmap
+fn bench_lockfree_hashmap(c: &mut Criterion) {
+    let map = LockFreeHashMap::new();
+    for i in 0..1000usize {
+        map.insert(i, i);
+    }
+    c.bench_function("lockfree_hashmap", |b| {
+        b.iter(|| {
+            map.insert(black_box(1000000), 1);
+            map.get(&black_box(500));
+            map.delete(&black_box(1000000));
+        })
+    });
+}
+
+//Benchmarking the lockfree hashmap in a concurrent setting
+fn bench_lockfree_concurrent_hashmap(c: &mut Criterion) {
+    let map = Arc::new(LockFreeHashMap::<usize, usize>::new());
+    let barrier = Arc::new(Barrier::new(2));
+
+    c.bench_function("lockfree_concurrent_hashmap", |b| {
+        b.iter(|| {
+            let barrier_clone = Arc::clone(&barrier);
+            let map_clone1 = Arc::clone(&map);
+            let map_clone2 = Arc::clone(&map);
+
+            let handle = spawn(move || {
+                barrier_clone.wait();
+                map_clone1.insert(black_box(1000000), 1);
+            });
+
+            // The main thread also waits on the barrier to ensure synchronization
+            barrier.wait();
+
+            // Perform lookup and removal in the main test thread
+            map_clone2.get(&black_box(1000000));
+            map_clone2.delete(&black_box(1000000));
+
+            handle.join().unwrap();
+        })
+    });
+}
+
+//Benchmarking the cuckoo hashmap
+fn bench_cuckoo_hashmap(c: &mut Criterion) {
+    let map = CuckooHashMap::new();
+    for i in 0..1000usize {
+        map.insert(i, i);
+    }
+    c.bench_function("cuckoo_hashmap", |b| {
+        b.iter(|| {
+            map.insert(black_box(1000000), 1);
+            map.get(&black_box(500));
+            map.remove(&black_box(1000000));
+        })
+    });
+}
+
+//Benchmarking the cuckoo hashmap in a concurrent setting
+fn bench_cuckoo_concurrent_hashmap(c: &mut Criterion) {
+    let map = Arc::new(CuckooHashMap::<usize, usize>::new());
+    let barrier = Arc::new(Barrier::new(2));
+
+    c.bench_function("cuckoo_concurrent_hashmap", |b| {
+        b.iter(|| {
+            let barrier_clone = Arc::clone(&barrier);
+            let map_clone1 = Arc::clone(&map);
+            let map_clone2 = Arc::clone(&map);
+
+            let handle = spawn(move || {
+                barrier_clone.wait();
+                map_clone1.insert(black_box(1000000), 1);
+            });
+
+            // The main thread also waits on the barrier to ensure synchronization
+            barrier.wait();
+
+            // Perform lookup and removal in the main test thread
+            map_clone2.get(&black_box(1000000));
+            map_clone2.remove(&black_box(1000000));
+
+            handle.join().unwrap();
+        })
+    });
+}
+
+//Benchmarking a single mutex-guarded hashmap as the lock based baseline
+fn bench_mutex_hashmap(c: &mut Criterion) {
+    let map = Mutex::new(HashMap::new());
+    for i in 0..1000usize {
+        map.lock().unwrap().insert(i, i);
+    }
+    c.bench_function("mutex_hashmap", |b| {
+        b.iter(|| {
+            map.lock().unwrap().insert(black_box(1000000), 1);
+            map.lock().unwrap().get(&black_box(500));
+            map.lock().unwrap().remove(&black_box(1000000));
+        })
+    });
+}
+
+//Benchmarking the mutex-guarded hashmap in a concurrent setting
+fn bench_mutex_concurrent_hashmap(c: &mut Criterion) {
+    let map = Arc::new(Mutex::new(HashMap::<usize, usize>::new()));
+    let barrier = Arc::new(Barrier::new(2));
+
+    c.bench_function("mutex_concurrent_hashmap", |b| {
+        b.iter(|| {
+            let barrier_clone = Arc::clone(&barrier);
+            let map_clone1 = Arc::clone(&map);
+            let map_clone2 = Arc::clone(&map);
+
+            let handle = spawn(move || {
+                barrier_clone.wait();
+                map_clone1.lock().unwrap().insert(black_box(1000000), 1);
+            });
+
+            // The main thread also waits on the barrier to ensure synchronization
+            barrier.wait();
+
+            // Perform lookup and removal in the main test thread
+            map_clone2.lock().unwrap().get(&black_box(1000000));
+            map_clone2.lock().unwrap().remove(&black_box(1000000));
+
+            handle.join().unwrap();
+        })
+    });
+}
+
+//Benchmarking the hashmaps under a skewed, read-mostly workload
+fn bench_zipf_hashmaps(c: &mut Criterion) {
+    let workload = Workload::new(10_000, 7)
+        .with_distribution(KeyDistribution::Zipf { exponent: 1.0 })
+        .with_read_ratio(0.9);
+    let ops: Vec<Op> = workload.stream().take(1000).map(|r| r.op).collect();
+
+    let lockfree = LockFreeHashMap::new();
+    let cuckoo = CuckooHashMap::new();
+    let mutex = Mutex::new(HashMap::new());
+    for key in 0..workload.num_keys() {
+        lockfree.insert(key, key);
+        cuckoo.insert(key, key);
+        mutex.lock().unwrap().insert(key, key);
+    }
+
+    c.bench_function("zipf_lockfree_hashmap", |b| {
+        b.iter(|| {
+            for op in &ops {
+                match *op {
+                    Op::Read(key) => {
+                        black_box(lockfree.get(&key));
+                    }
+                    Op::Write(key) => {
+                        lockfree.delete(&key);
+                        lockfree.insert(key, key);
+                    }
+                }
+            }
+        })
+    });
+    c.bench_function("zipf_cuckoo_hashmap", |b| {
+        b.iter(|| {
+            for op in &ops {
+                match *op {
+                    Op::Read(key) => {
+                        black_box(cuckoo.get(&key));
+                    }
+                    Op::Write(key) => {
+                        cuckoo.insert(key, key);
+                    }
+                }
+            }
+        })
+    });
+    c.bench_function("zipf_mutex_hashmap", |b| {
+        b.iter(|| {
+            for op in &ops {
+                match *op {
+                    Op::Read(key) => {
+                        black_box(mutex.lock().unwrap().get(&key).copied());
+                    }
+                    Op::Write(key) => {
+                        mutex.lock().unwrap().insert(key, key);
+                    }
+                }
+            }
+        })
+    });
+}
+
 criterion_group!(
     benches,
     bench_lockfree_queue,
@@ -125,6 +396,17 @@ criterion_group!(
     bench_single_vec_lock_queue,
     bench_lockfree_concurrent_queue,
     bench_lock_concurrent_queue,
-    bench_single_vec_lock_concurrent_queue
+    bench_single_vec_lock_concurrent_queue,
+    bench_adaptive_queue,
+    bench_adaptive_concurrent_queue,
+    bench_hazard_queue,
+    bench_hazard_concurrent_queue,
+    bench_lockfree_hashmap,
+    bench_cuckoo_hashmap,
+    bench_mutex_hashmap,
+    bench_lockfree_concurrent_hashmap,
+    bench_cuckoo_concurrent_hashmap,
+    bench_mutex_concurrent_hashmap,
+    bench_zipf_hashmaps
 );
 criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,177 @@
+// Concurrent crawler over a synthetic link graph, wiring several of the
+// crate's structures together:
+//
+// - `LockFreeHashMap` as the visited set (insert-if-absent decides which
+//   worker owns a URL),
+// - a bounded `Mailbox` as the shared frontier; when it is full, workers
+//   keep newly found URLs in a local backlog instead of blocking,
+// - `KeyedRateCounter` as a per-host politeness limit,
+// - a fixed group of scoped worker threads.
+//
+// Run with `cargo run --release --example crawler [workers] [pages-per-host]`.
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use myqueue::actor::{Mailbox, OverflowPolicy, SendError};
+use myqueue::lockfree_map::LockFreeHashMap;
+use myqueue::rate::KeyedRateCounter;
+use myqueue::registry;
+
+const HOSTS: u64 = 8;
+const LINKS_PER_PAGE: u64 = 6;
+const FRONTIER_CAPACITY: usize = 256;
+// Fetches allowed per host within each rate window.
+const HOST_LIMIT: u64 = 400;
+const RATE_WINDOW: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Url {
+    host: u64,
+    page: u64,
+}
+
+// Deterministic fake web: every page links to a few pseudo-random pages.
+fn fetch(url: Url, pages: u64) -> Vec<Url> {
+    // Stand-in for network latency.
+    thread::sleep(Duration::from_micros(20));
+    let mut seed = (url.host << 32 | url.page).wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..LINKS_PER_PAGE)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            Url {
+                host: seed % HOSTS,
+                page: (seed >> 8) % pages,
+            }
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Stats {
+    fetched: AtomicU64,
+    duplicate_links: AtomicU64,
+    throttled: AtomicU64,
+    backlogged: AtomicU64,
+}
+
+struct Crawler {
+    visited: Arc<LockFreeHashMap<Url, ()>>,
+    frontier: Arc<Mailbox<Url>>,
+    limiter: Arc<KeyedRateCounter<u64>>,
+    // URLs discovered but not fetched yet, wherever they are queued. The
+    // crawl is over when this drops to zero.
+    outstanding: AtomicUsize,
+    stats: Stats,
+    pages: u64,
+}
+
+impl Crawler {
+    fn discover(&self, url: Url, backlog: &mut Vec<Url>) {
+        if !self.visited.insert(url, ()) {
+            self.stats.duplicate_links.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+        match self.frontier.send(url) {
+            Ok(()) => {}
+            Err(SendError::Full(url)) => {
+                self.stats.backlogged.fetch_add(1, Ordering::Relaxed);
+                backlog.push(url);
+            }
+            Err(SendError::Closed(_)) => unreachable!("frontier closed with work outstanding"),
+        }
+    }
+
+    fn worker(&self) {
+        let mut backlog = Vec::new();
+        loop {
+            let url = match backlog.pop() {
+                Some(url) => url,
+                None => match self.frontier.recv_timeout(Duration::from_millis(5)) {
+                    Some(url) => url,
+                    None if self.frontier.is_closed() => return,
+                    None => continue,
+                },
+            };
+
+            if !self.limiter.try_acquire(url.host, HOST_LIMIT) {
+                self.stats.throttled.fetch_add(1, Ordering::Relaxed);
+                backlog.insert(0, url);
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+
+            for link in fetch(url, self.pages) {
+                self.discover(link, &mut backlog);
+            }
+            self.stats.fetched.fetch_add(1, Ordering::Relaxed);
+            if self.outstanding.fetch_sub(1, Ordering::SeqCst) == 1 {
+                self.frontier.close();
+            }
+        }
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let workers: usize = args.next().map_or(8, |a| a.parse().expect("workers"));
+    let pages: u64 = args.next().map_or(2_000, |a| a.parse().expect("pages"));
+
+    let crawler = Crawler {
+        visited: Arc::new(LockFreeHashMap::new()),
+        frontier: Arc::new(Mailbox::new(FRONTIER_CAPACITY, OverflowPolicy::Reject)),
+        limiter: Arc::new(KeyedRateCounter::new(RATE_WINDOW, 10)),
+        outstanding: AtomicUsize::new(0),
+        stats: Stats::default(),
+        pages,
+    };
+    registry::register(&crawler.visited);
+    registry::register(&crawler.frontier);
+    registry::register(&crawler.limiter);
+
+    let start = Instant::now();
+    let mut seed_backlog = Vec::new();
+    crawler.discover(Url { host: 0, page: 0 }, &mut seed_backlog);
+    assert!(seed_backlog.is_empty());
+
+    let elapsed = thread::scope(|s| {
+        // Progress report while the workers run.
+        s.spawn(|| {
+            while !crawler.frontier.is_closed() {
+                thread::sleep(Duration::from_millis(250));
+                println!(
+                    "fetched {:>6}  outstanding {:>6}  frontier {:>3}",
+                    crawler.stats.fetched.load(Ordering::Relaxed),
+                    crawler.outstanding.load(Ordering::SeqCst),
+                    crawler.frontier.len(),
+                );
+            }
+        });
+
+        let workers: Vec<_> = (0..workers).map(|_| s.spawn(|| crawler.worker())).collect();
+        workers.into_iter().for_each(|w| w.join().unwrap());
+        start.elapsed()
+    });
+    print!("\n{}", registry::dump_all_structures());
+
+    let fetched = crawler.stats.fetched.load(Ordering::Relaxed);
+    assert_eq!(fetched as usize, crawler.visited.len(), "every visited URL is fetched once");
+    println!();
+    println!("workers          {workers}");
+    println!("pages fetched    {fetched}");
+    println!(
+        "duplicate links  {}",
+        crawler.stats.duplicate_links.load(Ordering::Relaxed)
+    );
+    println!("throttled        {}", crawler.stats.throttled.load(Ordering::Relaxed));
+    println!("backlogged       {}", crawler.stats.backlogged.load(Ordering::Relaxed));
+    println!("elapsed          {elapsed:.2?}");
+    println!(
+        "throughput       {:.0} pages/s",
+        fetched as f64 / elapsed.as_secs_f64()
+    );
+}
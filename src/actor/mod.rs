@@ -0,0 +1,361 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::eviction::EvictionReason;
+use crate::queue::Queue;
+use crate::registry::{Diagnostics, StructureId};
+use crate::unwind;
+
+// What `Mailbox::send` does when the mailbox already holds `capacity`
+// messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum OverflowPolicy {
+    // Wait until the receiver makes room.
+    Block,
+    // Hand the message back to the sender.
+    Reject,
+    // Discard the message being sent.
+    DropNewest,
+    // Discard the oldest queued message to make room.
+    DropOldest,
+}
+
+#[derive(PartialEq, Eq)]
+pub enum SendError<M> {
+    Full(M),
+    Closed(M),
+}
+
+impl<M> SendError<M> {
+    pub fn into_inner(self) -> M {
+        match self {
+            SendError::Full(m) | SendError::Closed(m) => m,
+        }
+    }
+}
+
+impl<M> fmt::Debug for SendError<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full(_) => write!(f, "Full(..)"),
+            SendError::Closed(_) => write!(f, "Closed(..)"),
+        }
+    }
+}
+
+impl<M> fmt::Display for SendError<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full(_) => write!(f, "mailbox is full"),
+            SendError::Closed(_) => write!(f, "mailbox is closed"),
+        }
+    }
+}
+
+impl<M> std::error::Error for SendError<M> {}
+
+type EvictionListener<M> = Box<dyn Fn(M, EvictionReason) + Send + Sync>;
+
+// Bounded multi-producer mailbox on top of the lock-free `Queue`. Messages
+// travel through the queue without locks; the mutex/condvar pair is only
+// touched when the receiver (or a blocked sender) actually has to sleep.
+pub struct Mailbox<M> {
+    queue: Queue<M>,
+    len: AtomicUsize,
+    capacity: usize,
+    policy: OverflowPolicy,
+    closed: AtomicBool,
+    receiver_waiting: AtomicBool,
+    senders_waiting: AtomicUsize,
+    signal: Mutex<()>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    eviction_listener: Option<EvictionListener<M>>,
+    id: StructureId,
+}
+
+impl<M> Mailbox<M> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        assert!(capacity > 0, "capacity must be non-zero");
+        Mailbox {
+            queue: Queue::new(),
+            len: AtomicUsize::new(0),
+            capacity,
+            policy,
+            closed: AtomicBool::new(false),
+            receiver_waiting: AtomicBool::new(false),
+            senders_waiting: AtomicUsize::new(0),
+            signal: Mutex::new(()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            eviction_listener: None,
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    // Called with each message a full mailbox discards under `DropNewest`
+    // or `DropOldest`, with `EvictionReason::Capacity`.
+    pub fn with_eviction_listener(
+        mut self,
+        listener: impl Fn(M, EvictionReason) + Send + Sync + 'static,
+    ) -> Self {
+        self.eviction_listener = Some(Box::new(listener));
+        self
+    }
+
+    fn evicted(&self, msg: M) {
+        if let Some(listener) = &self.eviction_listener {
+            listener(msg, EvictionReason::Capacity);
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    // Stops accepting messages. Messages already queued can still be
+    // received; a receiver blocked on an empty mailbox is woken up.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _guard = self.signal.lock().unwrap();
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    pub fn send(&self, msg: M) -> Result<(), SendError<M>> {
+        loop {
+            if self.is_closed() {
+                return Err(SendError::Closed(msg));
+            }
+            let len = self.len.load(Ordering::SeqCst);
+            if len < self.capacity {
+                if self
+                    .len
+                    .compare_exchange(len, len + 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    break;
+                }
+                continue;
+            }
+            match self.policy {
+                OverflowPolicy::Reject => return Err(SendError::Full(msg)),
+                OverflowPolicy::DropNewest => {
+                    self.evicted(msg);
+                    return Ok(());
+                }
+                OverflowPolicy::DropOldest => {
+                    // Take over the oldest message's slot instead of
+                    // reserving a new one.
+                    if let Some(oldest) = self.queue.dequeue() {
+                        self.queue.enqueue(msg);
+                        self.wake_receiver();
+                        self.evicted(oldest);
+                        return Ok(());
+                    }
+                }
+                OverflowPolicy::Block => self.wait_for_space(),
+            }
+        }
+
+        self.queue.enqueue(msg);
+        self.wake_receiver();
+        Ok(())
+    }
+
+    // Non-blocking receive.
+    pub fn try_recv(&self) -> Option<M> {
+        let msg = self.queue.dequeue()?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        if self.senders_waiting.load(Ordering::SeqCst) > 0 {
+            let _guard = self.signal.lock().unwrap();
+            self.not_full.notify_one();
+        }
+        Some(msg)
+    }
+
+    // Blocks until a message arrives. Returns `None` once the mailbox is
+    // closed and drained.
+    pub fn recv(&self) -> Option<M> {
+        self.recv_until(None)
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<M> {
+        self.recv_until(Some(Instant::now() + timeout))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Option<M> {
+        loop {
+            if let Some(msg) = self.try_recv() {
+                return Some(msg);
+            }
+            if self.is_closed() {
+                // A sender may have slipped a message in before closing.
+                return self.try_recv();
+            }
+
+            let guard = self.signal.lock().unwrap();
+            self.receiver_waiting.store(true, Ordering::SeqCst);
+            // Re-check after announcing ourselves so a send that raced with
+            // the checks above cannot be missed.
+            if !self.is_empty() || self.is_closed() {
+                self.receiver_waiting.store(false, Ordering::SeqCst);
+                continue;
+            }
+            let guard = match deadline {
+                None => self.not_empty.wait(guard).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        self.receiver_waiting.store(false, Ordering::SeqCst);
+                        return None;
+                    }
+                    self.not_empty.wait_timeout(guard, deadline - now).unwrap().0
+                }
+            };
+            self.receiver_waiting.store(false, Ordering::SeqCst);
+            drop(guard);
+        }
+    }
+
+    fn wake_receiver(&self) {
+        if self.receiver_waiting.load(Ordering::SeqCst) {
+            let _guard = self.signal.lock().unwrap();
+            self.not_empty.notify_one();
+        }
+    }
+
+    fn wait_for_space(&self) {
+        let guard = self.signal.lock().unwrap();
+        self.senders_waiting.fetch_add(1, Ordering::SeqCst);
+        if self.len() >= self.capacity && !self.is_closed() {
+            let _guard = self.not_full.wait(guard).unwrap();
+        }
+        self.senders_waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Reads only atomics, so it never blocks behind senders or the receiver.
+impl<M> fmt::Debug for Mailbox<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mailbox")
+            .field("id", &self.id)
+            .field("len", &self.len())
+            .field("capacity", &self.capacity)
+            .field("policy", &self.policy)
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl<M: Send> Diagnostics for Mailbox<M> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "Mailbox"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+// Minimal actor: a value that owns its state and processes one message at a
+// time on its own thread.
+pub trait Actor: Send + 'static {
+    type Message: Send + 'static;
+
+    fn handle(&mut self, msg: Self::Message);
+
+    fn started(&mut self) {}
+
+    fn stopped(&mut self) {}
+}
+
+pub struct ActorRef<M> {
+    mailbox: Arc<Mailbox<M>>,
+}
+
+impl<M> ActorRef<M> {
+    pub fn send(&self, msg: M) -> Result<(), SendError<M>> {
+        self.mailbox.send(msg)
+    }
+
+    // Asks the actor to stop once it has handled the messages already
+    // queued.
+    pub fn stop(&self) {
+        self.mailbox.close();
+    }
+
+    pub fn mailbox(&self) -> &Mailbox<M> {
+        &self.mailbox
+    }
+}
+
+impl<M> Clone for ActorRef<M> {
+    fn clone(&self) -> Self {
+        ActorRef {
+            mailbox: Arc::clone(&self.mailbox),
+        }
+    }
+}
+
+// Runs `actor` on a dedicated thread fed by a new mailbox. The join handle
+// returns the actor after it stops, so its final state can be inspected. If
+// a handler panics the mailbox is closed and the panic surfaces through the
+// join handle.
+pub fn spawn<A: Actor>(
+    mut actor: A,
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (ActorRef<A::Message>, JoinHandle<A>) {
+    let mailbox = Arc::new(Mailbox::new(capacity, policy));
+    let inbox = Arc::clone(&mailbox);
+    let handle = thread::spawn(move || {
+        let result = unwind::catch(|| {
+            actor.started();
+            while let Some(msg) = inbox.recv() {
+                actor.handle(msg);
+            }
+            actor.stopped();
+        });
+        if let Err(payload) = result {
+            // Nobody will drain the mailbox any more; close it so blocked
+            // senders fail instead of waiting forever.
+            inbox.close();
+            unwind::resume(payload);
+        }
+        actor
+    });
+    (ActorRef { mailbox }, handle)
+}
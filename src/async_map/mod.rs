@@ -0,0 +1,294 @@
+// Hash map for async code.
+//
+// Each shard is guarded by an async mutex: a task that finds its shard locked
+// is parked with its waker instead of blocking the thread, so an executor's
+// worker threads keep running other tasks. The lock is taken on the first
+// poll whenever it is free, so uncontended operations complete without ever
+// returning `Pending`.
+//
+// `entry` returns a guard over one key that may be held across `.await`
+// points. While it is alive, other tasks touching keys in the same shard
+// wait for it, so keep such sections short.
+use std::cell::UnsafeCell;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::shard;
+use crate::sync::Mutex;
+
+const DEFAULT_SHARDS: usize = 16;
+
+struct LockState {
+    locked: bool,
+    // Parked lockers in arrival order, by ticket.
+    waiters: VecDeque<(u64, Waker)>,
+    next_ticket: u64,
+}
+
+// Mutex whose `lock` is a future. Waiters are woken in arrival order, but a
+// task polling at the moment the lock is released may take it first.
+struct AsyncMutex<T> {
+    state: Mutex<LockState>,
+    value: UnsafeCell<T>,
+}
+
+// The value is only reached through a guard, and guards are exclusive.
+unsafe impl<T: Send> Send for AsyncMutex<T> {}
+unsafe impl<T: Send> Sync for AsyncMutex<T> {}
+
+impl<T> AsyncMutex<T> {
+    fn new(value: T) -> Self {
+        AsyncMutex {
+            state: Mutex::new(LockState {
+                locked: false,
+                waiters: VecDeque::new(),
+                next_ticket: 0,
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            ticket: None,
+        }
+    }
+}
+
+struct Lock<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+    // Set while parked in the waiter queue.
+    ticket: Option<u64>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+        let mut state = mutex.state.lock().unwrap();
+        if !state.locked {
+            state.locked = true;
+            if let Some(ticket) = self.ticket.take() {
+                state.waiters.retain(|(t, _)| *t != ticket);
+            }
+            return Poll::Ready(AsyncMutexGuard {
+                mutex,
+                _value: PhantomData,
+            });
+        }
+        match self.ticket {
+            Some(ticket) => {
+                if let Some((_, waker)) = state.waiters.iter_mut().find(|(t, _)| *t == ticket) {
+                    waker.clone_from(cx.waker());
+                }
+            }
+            None => {
+                let ticket = state.next_ticket;
+                state.next_ticket += 1;
+                state.waiters.push_back((ticket, cx.waker().clone()));
+                self.ticket = Some(ticket);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Lock<'_, T> {
+    // A parked locker dropped before acquiring may have been woken by a
+    // release meant for it; pass the wake-up on so it is not lost.
+    fn drop(&mut self) {
+        let Some(ticket) = self.ticket else {
+            return;
+        };
+        let mut state = self.mutex.state.lock().unwrap();
+        state.waiters.retain(|(t, _)| *t != ticket);
+        if !state.locked {
+            if let Some((_, waker)) = state.waiters.front() {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+struct AsyncMutexGuard<'a, T> {
+    mutex: &'a AsyncMutex<T>,
+    // Makes the guard `Sync` only if `T` is, as it hands out `&T`.
+    _value: PhantomData<&'a mut T>,
+}
+
+impl<T> Deref for AsyncMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for AsyncMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for AsyncMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.mutex.state.lock().unwrap();
+        state.locked = false;
+        if let Some((_, waker)) = state.waiters.front() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+pub struct AsyncMap<K, V, S = RandomState> {
+    shards: Vec<AsyncMutex<HashMap<K, V>>>,
+    hasher: S,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq, V> AsyncMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(num_shards: usize) -> Self {
+        Self::with_shards_and_hasher(num_shards, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> AsyncMap<K, V, S> {
+    pub fn with_shards_and_hasher(num_shards: usize, hasher: S) -> Self {
+        AsyncMap {
+            shards: (0..shard::shard_count(num_shards))
+                .map(|_| AsyncMutex::new(HashMap::new()))
+                .collect(),
+            hasher,
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn shard(&self, key: &K) -> &AsyncMutex<HashMap<K, V>> {
+        let index = shard::shard_index(self.hasher.hash_one(key), self.shards.len());
+        &self.shards[index]
+    }
+
+    pub async fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.shard(key).lock().await.get(key).cloned()
+    }
+
+    pub async fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).lock().await.contains_key(key)
+    }
+
+    // Inserts or replaces the value for `key`, returning the previous value.
+    pub async fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).lock().await.insert(key, value)
+    }
+
+    pub async fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).lock().await.remove(key)
+    }
+
+    // Runs `f` on the value for `key` in place. `f` is synchronous, so the
+    // shard is never held across an `.await`; use `entry` for that.
+    pub async fn with_mut<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        self.shard(key).lock().await.get_mut(key).map(f)
+    }
+
+    // Exclusive access to `key`, which may be held across `.await` points.
+    pub async fn entry(&self, key: K) -> Entry<'_, K, V> {
+        let shard = self.shard(&key).lock().await;
+        Entry { shard, key }
+    }
+
+    // Locks one shard at a time, so the total is approximate while other
+    // tasks are writing.
+    pub async fn len(&self) -> usize {
+        let mut len = 0;
+        for shard in &self.shards {
+            len += shard.lock().await.len();
+        }
+        len
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+}
+
+impl<K: Hash + Eq, V> Default for AsyncMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Send, V: Send, S: Send + Sync> Diagnostics for AsyncMap<K, V, S> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "AsyncMap"
+    }
+
+    // Reading the size would mean waiting for every shard lock.
+    fn size(&self) -> Option<usize> {
+        None
+    }
+}
+
+// Exclusive hold of one key's shard, returned by `AsyncMap::entry`.
+pub struct Entry<'a, K, V> {
+    shard: AsyncMutexGuard<'a, HashMap<K, V>>,
+    key: K,
+}
+
+impl<K: Hash + Eq, V> Entry<'_, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> Option<&V> {
+        self.shard.get(&self.key)
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut V> {
+        self.shard.get_mut(&self.key)
+    }
+
+    // The value, inserting `V::default()` first if the key is absent.
+    pub fn or_default(&mut self) -> &mut V
+    where
+        K: Clone,
+        V: Default,
+    {
+        self.shard.entry(self.key.clone()).or_default()
+    }
+
+    pub fn insert(&mut self, value: V) -> Option<V>
+    where
+        K: Clone,
+    {
+        self.shard.insert(self.key.clone(), value)
+    }
+
+    pub fn remove(&mut self) -> Option<V> {
+        self.shard.remove(&self.key)
+    }
+}
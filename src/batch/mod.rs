@@ -0,0 +1,190 @@
+// Per-thread batching of writes across structures.
+//
+// A `BatchScope` buffers items per target structure and hands each buffer
+// over in one call, so a tight ingestion loop pays one lock acquisition (or
+// one CAS, for the lock-free `Queue`) per batch instead of per item. Buffers
+// are flushed when they reach the scope's batch size, on `flush`, and when
+// the scope is dropped.
+//
+// Items pushed to one structure arrive in push order. Across structures
+// there is no ordering: each structure sees its items only when its own
+// buffer is flushed.
+use std::any::{Any, TypeId};
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::mem;
+
+use crate::cuckoo_map::CuckooHashMap;
+use crate::lockfree_map::LockFreeHashMap;
+use crate::queue::{LockQueue, Queue, SingleVecLockQueue};
+
+const DEFAULT_MAX_BATCH: usize = 256;
+
+// A structure that accepts items in bulk.
+pub trait BatchSink: 'static {
+    type Item: 'static;
+
+    fn push_batch(&self, items: Vec<Self::Item>);
+}
+
+impl<T: 'static> BatchSink for Queue<T> {
+    type Item = T;
+
+    fn push_batch(&self, items: Vec<T>) {
+        self.enqueue_batch(items)
+    }
+}
+
+impl<T: 'static> BatchSink for LockQueue<T> {
+    type Item = T;
+
+    fn push_batch(&self, items: Vec<T>) {
+        self.enqueue_batch(items)
+    }
+}
+
+impl<T: 'static> BatchSink for SingleVecLockQueue<T> {
+    type Item = T;
+
+    fn push_batch(&self, items: Vec<T>) {
+        self.enqueue_batch(items)
+    }
+}
+
+// Entries whose key is already present are dropped, as with `insert`.
+impl<K, V, S> BatchSink for LockFreeHashMap<K, V, S>
+where
+    K: Hash + Eq + Send + 'static,
+    V: Send + 'static,
+    S: BuildHasher + 'static,
+{
+    type Item = (K, V);
+
+    fn push_batch(&self, items: Vec<(K, V)>) {
+        for (key, value) in items {
+            self.insert(key, value);
+        }
+    }
+}
+
+// Later entries for a key replace earlier ones, as with `insert`.
+impl<K, V, S> BatchSink for CuckooHashMap<K, V, S>
+where
+    K: Hash + Eq + 'static,
+    V: 'static,
+    S: BuildHasher + 'static,
+{
+    type Item = (K, V);
+
+    fn push_batch(&self, items: Vec<(K, V)>) {
+        self.insert_batch(items);
+    }
+}
+
+// Items buffered for one structure. The structure's type is erased so one
+// scope can serve several kinds of structures.
+struct Pending {
+    target: *const (),
+    sink_type: TypeId,
+    // A `Vec<S::Item>` for the structure's type `S`.
+    items: Box<dyn Any>,
+    flush: unsafe fn(*const (), &mut dyn Any),
+}
+
+// Safety: `target` must point to a live `S` and `items` must hold a
+// `Vec<S::Item>`.
+unsafe fn flush_into<S: BatchSink>(target: *const (), items: &mut dyn Any) {
+    let items = mem::take(items.downcast_mut::<Vec<S::Item>>().unwrap());
+    if !items.is_empty() {
+        (*(target as *const S)).push_batch(items);
+    }
+}
+
+// Buffers are owned by the scope, which lives on one thread; structures
+// are borrowed for `'a`, so none of them can go away before the scope
+// flushes into it.
+pub struct BatchScope<'a> {
+    pending: Vec<Pending>,
+    buffered: usize,
+    max_batch: usize,
+    structures: PhantomData<&'a ()>,
+}
+
+impl<'a> BatchScope<'a> {
+    pub fn new() -> Self {
+        BatchScope {
+            pending: Vec::new(),
+            buffered: 0,
+            max_batch: DEFAULT_MAX_BATCH,
+            structures: PhantomData,
+        }
+    }
+
+    // Number of items buffered per structure before they are handed over.
+    pub fn with_max_batch(mut self, max_batch: usize) -> Self {
+        assert!(max_batch > 0, "max_batch must be non-zero");
+        self.max_batch = max_batch;
+        self
+    }
+
+    // Buffers `item` for `sink`.
+    pub fn push<S: BatchSink>(&mut self, sink: &'a S, item: S::Item) {
+        let target = sink as *const S as *const ();
+        // Keyed by type as well as address: a structure and its first field
+        // share an address.
+        let sink_type = TypeId::of::<S>();
+        let index = match self
+            .pending
+            .iter()
+            .position(|p| p.target == target && p.sink_type == sink_type)
+        {
+            Some(index) => index,
+            None => {
+                self.pending.push(Pending {
+                    target,
+                    sink_type,
+                    items: Box::new(Vec::<S::Item>::new()),
+                    flush: flush_into::<S>,
+                });
+                self.pending.len() - 1
+            }
+        };
+        let items = self.pending[index]
+            .items
+            .downcast_mut::<Vec<S::Item>>()
+            .unwrap();
+        items.push(item);
+        self.buffered += 1;
+        if items.len() >= self.max_batch {
+            self.buffered -= items.len();
+            sink.push_batch(mem::take(items));
+        }
+    }
+
+    // Hands every buffered item to its structure.
+    pub fn flush(&mut self) {
+        for pending in &mut self.pending {
+            // Safety: `target` was created from a `&'a S` with the `S` that
+            // `flush` and `items` were created for.
+            unsafe { (pending.flush)(pending.target, &mut *pending.items) };
+        }
+        self.buffered = 0;
+    }
+
+    // Items buffered and not yet handed over.
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+}
+
+impl Default for BatchScope<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for BatchScope<'_> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
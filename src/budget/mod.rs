@@ -0,0 +1,97 @@
+// Shared capacity budget across several structures.
+//
+// Services that run many caches and queues from this crate register them with
+// one `MemoryBudget`. Usage is measured in whatever unit the structures
+// report (elements for everything in this crate). When `enforce` finds the
+// total above the limit, every registered structure is asked to shed a share
+// of the excess proportional to its own usage, so the largest consumers give
+// up the most.
+use std::sync::{Arc, Weak};
+
+use crate::sync::{Mutex, MutexGuard};
+
+pub trait Shed: Send + Sync {
+    // Units currently held.
+    fn usage(&self) -> usize;
+
+    // Releases roughly `amount` units, preferring the least valuable
+    // elements. Returns the number actually released.
+    fn shed(&self, amount: usize) -> usize;
+}
+
+struct Inner {
+    limit: usize,
+    members: Mutex<Vec<Weak<dyn Shed>>>,
+}
+
+// Cloning yields another handle to the same budget.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Inner>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            inner: Arc::new(Inner {
+                limit,
+                members: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    fn members(&self) -> MutexGuard<'_, Vec<Weak<dyn Shed>>> {
+        // Entries are only pushed and pruned, so a poisoned list is still valid.
+        self.inner.members.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Structures stay registered until their last `Arc` is dropped; the
+    // budget never keeps them alive.
+    pub fn register<T: Shed + 'static>(&self, structure: &Arc<T>) {
+        let weak = Arc::downgrade(structure) as Weak<dyn Shed>;
+        let mut members = self.members();
+        members.retain(|m| m.strong_count() > 0);
+        members.push(weak);
+    }
+
+    fn live(&self) -> Vec<Arc<dyn Shed>> {
+        let mut members = self.members();
+        members.retain(|m| m.strong_count() > 0);
+        members.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    // Total usage of all live registered structures.
+    pub fn usage(&self) -> usize {
+        self.live().iter().map(|m| m.usage()).sum()
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.usage() > self.inner.limit
+    }
+
+    // Brings usage back under the limit if it is exceeded. Returns the number
+    // of units released. Call it periodically or after bulk inserts; no lock
+    // of the budget is held while structures shed.
+    pub fn enforce(&self) -> usize {
+        let live = self.live();
+        let usages: Vec<usize> = live.iter().map(|m| m.usage()).collect();
+        let total: usize = usages.iter().sum();
+        if total <= self.inner.limit {
+            return 0;
+        }
+        let excess = total - self.inner.limit;
+        live.iter()
+            .zip(usages)
+            .filter(|(_, usage)| *usage > 0)
+            .map(|(member, usage)| {
+                // Rounded up so the shares never add up to less than `excess`.
+                let share = (excess as u128 * usage as u128).div_ceil(total as u128) as usize;
+                member.shed(share)
+            })
+            .sum()
+    }
+}
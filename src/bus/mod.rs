@@ -0,0 +1,313 @@
+use std::any::{self, Any, TypeId};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::actor::{Mailbox, OverflowPolicy, SendError};
+use crate::registry::{Diagnostics, StructureId};
+use crate::unwind;
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+struct Topic {
+    message_type: TypeId,
+    type_name: &'static str,
+    capacity: usize,
+    policy: OverflowPolicy,
+    subscribers: Vec<Arc<dyn Subscription>>,
+}
+
+// A subscriber's `Mailbox<T>` seen without its message type.
+trait Subscription: Send + Sync {
+    fn close(&self);
+    fn is_closed(&self) -> bool;
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+}
+
+impl<T: Send + 'static> Subscription for Mailbox<T> {
+    fn close(&self) {
+        Mailbox::close(self)
+    }
+
+    fn is_closed(&self) -> bool {
+        Mailbox::is_closed(self)
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+impl Topic {
+    fn new<T: 'static>(capacity: usize, policy: OverflowPolicy) -> Self {
+        Topic {
+            message_type: TypeId::of::<T>(),
+            type_name: any::type_name::<T>(),
+            capacity,
+            policy,
+            subscribers: Vec::new(),
+        }
+    }
+
+    // The panic message for using the topic with the wrong type. Callers
+    // release the topics lock before panicking, so the mistake does not
+    // poison the bus for every later call.
+    fn check_type<T: 'static>(&self) -> Result<(), String> {
+        if self.message_type == TypeId::of::<T>() {
+            return Ok(());
+        }
+        Err(format!(
+            "topic carries {} messages, not {}",
+            self.type_name,
+            any::type_name::<T>()
+        ))
+    }
+
+    fn close(&self) {
+        self.subscribers.iter().for_each(|s| s.close());
+    }
+}
+
+// In-process publish/subscribe. Each topic carries one message type, fixed by
+// whoever declares, subscribes to or publishes on it first. Every subscriber
+// owns a bounded `Mailbox`, and the topic's `OverflowPolicy` decides what a
+// publisher does when a subscriber falls behind: with `Block` the slowest
+// subscriber paces the publisher, the other policies shed messages for that
+// subscriber only.
+//
+// Publishing clones the message for every subscriber but the last. Using a
+// topic with the wrong message type is a programming error and panics.
+pub struct Bus<K> {
+    topics: RwLock<HashMap<K, Topic>>,
+    default_capacity: usize,
+    default_policy: OverflowPolicy,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq + Clone> Bus<K> {
+    pub fn new() -> Self {
+        Self::with_defaults(DEFAULT_CAPACITY, OverflowPolicy::Block)
+    }
+
+    // Capacity and policy for topics that are not declared explicitly.
+    pub fn with_defaults(capacity: usize, policy: OverflowPolicy) -> Self {
+        assert!(capacity > 0, "capacity must be non-zero");
+        Bus {
+            topics: RwLock::new(HashMap::new()),
+            default_capacity: capacity,
+            default_policy: policy,
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    // Fixes the message type, per-subscriber capacity and overflow policy of
+    // `topic`. Subscribers that already exist keep their mailboxes.
+    pub fn declare<T: Any + Send>(&self, topic: K, capacity: usize, policy: OverflowPolicy) {
+        assert!(capacity > 0, "capacity must be non-zero");
+        let mut topics = self.topics.write().unwrap();
+        let entry = topics
+            .entry(topic)
+            .or_insert_with(|| Topic::new::<T>(capacity, policy));
+        if let Err(msg) = entry.check_type::<T>() {
+            drop(topics);
+            panic!("{msg}");
+        }
+        entry.capacity = capacity;
+        entry.policy = policy;
+    }
+
+    pub fn subscribe<T: Any + Send>(&self, topic: K) -> Subscriber<T> {
+        let mut topics = self.topics.write().unwrap();
+        let entry = topics
+            .entry(topic)
+            .or_insert_with(|| Topic::new::<T>(self.default_capacity, self.default_policy));
+        if let Err(msg) = entry.check_type::<T>() {
+            drop(topics);
+            panic!("{msg}");
+        }
+        let mailbox = Arc::new(Mailbox::new(entry.capacity, entry.policy));
+        entry.subscribers.push(Arc::clone(&mailbox) as Arc<dyn Subscription>);
+        Subscriber { mailbox }
+    }
+
+    // Runs `handler` on a dedicated thread for every message published on
+    // `topic`, until the topic is closed. A panicking handler unsubscribes
+    // and the panic surfaces through the join handle.
+    pub fn subscribe_with<T, F>(&self, topic: K, mut handler: F) -> JoinHandle<()>
+    where
+        T: Any + Send,
+        F: FnMut(T) + Send + 'static,
+    {
+        let subscriber = self.subscribe::<T>(topic);
+        thread::spawn(move || {
+            let result = unwind::catch(|| {
+                while let Some(msg) = subscriber.recv() {
+                    handler(msg);
+                }
+            });
+            if let Err(payload) = result {
+                drop(subscriber);
+                unwind::resume(payload);
+            }
+        })
+    }
+
+    // Delivers `msg` to every current subscriber of `topic` and returns how
+    // many accepted it. Messages published on a topic nobody subscribes to
+    // are dropped.
+    pub fn publish<T: Any + Send + Clone>(&self, topic: &K, msg: T) -> usize {
+        // Send outside the lock: a `Block` topic may wait on slow
+        // subscribers, and that must not stall `subscribe`.
+        let subscribers: Vec<Arc<Mailbox<T>>> = {
+            let topics = self.topics.read().unwrap();
+            let Some(entry) = topics.get(topic) else {
+                return 0;
+            };
+            if let Err(msg) = entry.check_type::<T>() {
+                drop(topics);
+                panic!("{msg}");
+            }
+            entry
+                .subscribers
+                .iter()
+                .filter_map(|s| Arc::clone(s).into_any().downcast::<Mailbox<T>>().ok())
+                .collect()
+        };
+
+        let mut delivered = 0;
+        let mut closed = false;
+        let mut msg = Some(msg);
+        for (i, mailbox) in subscribers.iter().enumerate() {
+            let copy = if i + 1 == subscribers.len() {
+                msg.take().unwrap()
+            } else {
+                msg.clone().unwrap()
+            };
+            match mailbox.send(copy) {
+                Ok(()) => delivered += 1,
+                Err(SendError::Full(_)) => {}
+                Err(SendError::Closed(_)) => closed = true,
+            }
+        }
+        if closed {
+            self.prune(topic);
+        }
+        delivered
+    }
+
+    fn prune(&self, topic: &K) {
+        let mut topics = self.topics.write().unwrap();
+        if let Some(entry) = topics.get_mut(topic) {
+            entry.subscribers.retain(|s| !s.is_closed());
+        }
+    }
+
+    // Number of live subscribers of `topic`.
+    pub fn subscriber_count(&self, topic: &K) -> usize {
+        self.topics
+            .read()
+            .unwrap()
+            .get(topic)
+            .map_or(0, |t| t.subscribers.len())
+    }
+
+    // Removes `topic`. Its subscribers receive the messages already queued,
+    // then `recv` returns `None`.
+    pub fn close_topic(&self, topic: &K) {
+        if let Some(entry) = self.topics.write().unwrap().remove(topic) {
+            entry.close();
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Bus<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> Drop for Bus<K> {
+    fn drop(&mut self) {
+        let topics = self.topics.get_mut().unwrap_or_else(|e| e.into_inner());
+        topics.values().for_each(Topic::close);
+    }
+}
+
+impl<K: Send + Sync> Diagnostics for Bus<K> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "Bus"
+    }
+
+    // Number of topics.
+    fn size(&self) -> Option<usize> {
+        Some(self.topics.read().unwrap().len())
+    }
+}
+
+// Receiving end of one subscription. Dropping it unsubscribes.
+pub struct Subscriber<T> {
+    mailbox: Arc<Mailbox<T>>,
+}
+
+impl<T> Subscriber<T> {
+    // Blocks until a message arrives; `None` once the topic is closed and
+    // every queued message was received.
+    pub fn recv(&self) -> Option<T> {
+        self.mailbox.recv()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<T> {
+        self.mailbox.recv_timeout(timeout)
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        self.mailbox.try_recv()
+    }
+
+    // Messages queued for this subscriber.
+    pub fn len(&self) -> usize {
+        self.mailbox.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mailbox.is_empty()
+    }
+}
+
+impl<T> Drop for Subscriber<T> {
+    fn drop(&mut self) {
+        self.mailbox.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn wrong_type_does_not_poison_the_bus() {
+        let bus = Bus::new();
+        let subscriber = bus.subscribe::<u32>("numbers");
+        let wrong = |f: &dyn Fn()| panic::catch_unwind(AssertUnwindSafe(f)).is_err();
+        assert!(wrong(&|| drop(bus.subscribe::<String>("numbers"))));
+        assert!(wrong(&|| bus.declare::<String>("numbers", 4, OverflowPolicy::Block)));
+        assert!(wrong(&|| {
+            bus.publish(&"numbers", "one".to_string());
+        }));
+
+        assert_eq!(bus.publish(&"numbers", 1u32), 1);
+        assert_eq!(subscriber.try_recv(), Some(1));
+        assert_eq!(bus.subscriber_count(&"numbers"), 1);
+    }
+}
@@ -0,0 +1,537 @@
+// Declarative configuration for the crate's structures.
+//
+// A `PipelineConfig` holds named sections of tuning parameters (shard counts,
+// capacities, policies, timeouts) parsed from JSON or TOML, so deployments
+// can retune them without code changes. The element types are still chosen
+// in code: `config.mailbox::<Job>("ingest")` builds the mailbox configured
+// under `[mailboxes.ingest]`. The config is plain data, so one parsed config
+// can be shared between threads and build structures from any of them.
+//
+// Values are validated when the config is parsed, so building a structure
+// from a parsed config never panics on a bad parameter.
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::actor::{Mailbox, OverflowPolicy};
+use crate::bus::Bus;
+use crate::futcache::FutureCache;
+use crate::queue::{AdaptiveQueue, KeyedOrderedQueue};
+use crate::quota::{QuotaMap, RefillPolicy};
+use crate::rate::KeyedRateCounter;
+use crate::session::SessionStore;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    Parse(String),
+    Invalid {
+        section: &'static str,
+        name: String,
+        reason: &'static str,
+    },
+    Missing {
+        section: &'static str,
+        name: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Parse(message) => write!(f, "invalid config: {}", message),
+            ConfigError::Invalid {
+                section,
+                name,
+                reason,
+            } => write!(f, "{}.{}: {}", section, name, reason),
+            ConfigError::Missing { section, name } => {
+                write!(f, "no {}.{} in config", section, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MailboxConfig {
+    pub capacity: usize,
+    #[serde(default = "default_policy")]
+    pub policy: OverflowPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BusConfig {
+    // Per-subscriber capacity and policy of topics not declared in code.
+    pub capacity: usize,
+    #[serde(default = "default_policy")]
+    pub policy: OverflowPolicy,
+}
+
+fn default_policy() -> OverflowPolicy {
+    OverflowPolicy::Block
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveQueueConfig {
+    pub high_watermark: f64,
+    pub low_watermark: f64,
+    pub window: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyedQueueConfig {
+    pub shards: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    pub shards: Option<usize>,
+    pub max_entries: Option<usize>,
+    pub ttl_ms: Option<u64>,
+    pub early_refresh_beta: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateCounterConfig {
+    pub window_ms: u64,
+    pub buckets: usize,
+    pub shards: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum RefillConfig {
+    FixedWindow { quota: u64, window_ms: u64 },
+    TokenBucket { capacity: u64, refill: u64, every_ms: u64 },
+}
+
+impl RefillConfig {
+    pub fn to_policy(&self) -> RefillPolicy {
+        match *self {
+            RefillConfig::FixedWindow { quota, window_ms } => RefillPolicy::FixedWindow {
+                quota,
+                window: Duration::from_millis(window_ms),
+            },
+            RefillConfig::TokenBucket {
+                capacity,
+                refill,
+                every_ms,
+            } => RefillPolicy::TokenBucket {
+                capacity,
+                refill,
+                every: Duration::from_millis(every_ms),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub refill: RefillConfig,
+    pub shards: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionConfig {
+    pub idle_timeout_ms: u64,
+    pub max_lifetime_ms: u64,
+    pub shards: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub mailboxes: HashMap<String, MailboxConfig>,
+    pub buses: HashMap<String, BusConfig>,
+    pub adaptive_queues: HashMap<String, AdaptiveQueueConfig>,
+    pub keyed_queues: HashMap<String, KeyedQueueConfig>,
+    pub caches: HashMap<String, CacheConfig>,
+    pub rate_counters: HashMap<String, RateCounterConfig>,
+    pub quotas: HashMap<String, QuotaConfig>,
+    pub sessions: HashMap<String, SessionConfig>,
+}
+
+fn lookup<'a, C>(
+    section: &'static str,
+    entries: &'a HashMap<String, C>,
+    name: &str,
+) -> Result<&'a C, ConfigError> {
+    entries.get(name).ok_or_else(|| ConfigError::Missing {
+        section,
+        name: name.to_string(),
+    })
+}
+
+impl PipelineConfig {
+    pub fn from_json(text: &str) -> Result<Self, ConfigError> {
+        let config: Self =
+            serde_json::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Checks every parameter the constructors would reject.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        fn check<C>(
+            section: &'static str,
+            entries: &HashMap<String, C>,
+            rule: impl Fn(&C) -> Option<&'static str>,
+        ) -> Result<(), ConfigError> {
+            for (name, entry) in entries {
+                if let Some(reason) = rule(entry) {
+                    return Err(ConfigError::Invalid {
+                        section,
+                        name: name.clone(),
+                        reason,
+                    });
+                }
+            }
+            Ok(())
+        }
+        let shards = |shards: Option<usize>| (shards == Some(0)).then_some("shards must be non-zero");
+
+        check("mailboxes", &self.mailboxes, |c| {
+            (c.capacity == 0).then_some("capacity must be non-zero")
+        })?;
+        check("buses", &self.buses, |c| {
+            (c.capacity == 0).then_some("capacity must be non-zero")
+        })?;
+        check("adaptive_queues", &self.adaptive_queues, |c| {
+            if c.low_watermark > c.high_watermark {
+                Some("low_watermark must not exceed high_watermark")
+            } else {
+                (c.window == 0).then_some("window must be non-zero")
+            }
+        })?;
+        check("keyed_queues", &self.keyed_queues, |c| shards(c.shards))?;
+        check("caches", &self.caches, |c| {
+            if c.early_refresh_beta.is_some_and(|beta| beta.is_nan() || beta <= 0.0) {
+                Some("early_refresh_beta must be positive")
+            } else {
+                shards(c.shards)
+            }
+        })?;
+        check("rate_counters", &self.rate_counters, |c| {
+            if c.buckets == 0 {
+                Some("buckets must be non-zero")
+            } else {
+                shards(c.shards)
+            }
+        })?;
+        check("quotas", &self.quotas, |c| shards(c.shards))?;
+        check("sessions", &self.sessions, |c| shards(c.shards))?;
+        Ok(())
+    }
+
+    pub fn mailbox<M>(&self, name: &str) -> Result<Mailbox<M>, ConfigError> {
+        let c = lookup("mailboxes", &self.mailboxes, name)?;
+        Ok(Mailbox::new(c.capacity, c.policy))
+    }
+
+    pub fn bus<K: Hash + Eq + Clone>(&self, name: &str) -> Result<Bus<K>, ConfigError> {
+        let c = lookup("buses", &self.buses, name)?;
+        Ok(Bus::with_defaults(c.capacity, c.policy))
+    }
+
+    pub fn adaptive_queue<T>(&self, name: &str) -> Result<AdaptiveQueue<T>, ConfigError> {
+        let c = lookup("adaptive_queues", &self.adaptive_queues, name)?;
+        Ok(AdaptiveQueue::with_thresholds(
+            c.high_watermark,
+            c.low_watermark,
+            c.window,
+        ))
+    }
+
+    pub fn keyed_queue<K: Hash + Eq + Clone, T>(
+        &self,
+        name: &str,
+    ) -> Result<KeyedOrderedQueue<K, T>, ConfigError> {
+        let c = lookup("keyed_queues", &self.keyed_queues, name)?;
+        Ok(match c.shards {
+            Some(shards) => KeyedOrderedQueue::with_shards(shards),
+            None => KeyedOrderedQueue::new(),
+        })
+    }
+
+    pub fn cache<K: Hash + Eq + Clone, V: Clone>(
+        &self,
+        name: &str,
+    ) -> Result<FutureCache<K, V>, ConfigError> {
+        let c = lookup("caches", &self.caches, name)?;
+        let mut cache = match c.shards {
+            Some(shards) => FutureCache::with_shards(shards),
+            None => FutureCache::new(),
+        };
+        if let Some(max_entries) = c.max_entries {
+            cache = cache.with_max_entries(max_entries);
+        }
+        if let Some(ttl_ms) = c.ttl_ms {
+            cache = cache.with_ttl(Duration::from_millis(ttl_ms));
+        }
+        if let Some(beta) = c.early_refresh_beta {
+            cache = cache.with_early_refresh(beta);
+        }
+        Ok(cache)
+    }
+
+    pub fn rate_counter<K: Hash + Eq>(
+        &self,
+        name: &str,
+    ) -> Result<KeyedRateCounter<K>, ConfigError> {
+        let c = lookup("rate_counters", &self.rate_counters, name)?;
+        let window = Duration::from_millis(c.window_ms);
+        Ok(match c.shards {
+            Some(shards) => KeyedRateCounter::with_shards(window, c.buckets, shards),
+            None => KeyedRateCounter::new(window, c.buckets),
+        })
+    }
+
+    pub fn quota_map<K: Hash + Eq>(&self, name: &str) -> Result<QuotaMap<K>, ConfigError> {
+        let c = lookup("quotas", &self.quotas, name)?;
+        let policy = c.refill.to_policy();
+        Ok(match c.shards {
+            Some(shards) => QuotaMap::with_shards(policy, shards),
+            None => QuotaMap::new(policy),
+        })
+    }
+
+    pub fn session_store<K: Hash + Eq, S>(
+        &self,
+        name: &str,
+    ) -> Result<SessionStore<K, S>, ConfigError> {
+        let c = lookup("sessions", &self.sessions, name)?;
+        let idle_timeout = Duration::from_millis(c.idle_timeout_ms);
+        let max_lifetime = Duration::from_millis(c.max_lifetime_ms);
+        Ok(match c.shards {
+            Some(shards) => SessionStore::with_shards(idle_timeout, max_lifetime, shards),
+            None => SessionStore::new(idle_timeout, max_lifetime),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        [mailboxes.ingest]
+        capacity = 128
+        policy = "drop_newest"
+
+        [buses.events]
+        capacity = 16
+
+        [adaptive_queues.work]
+        high_watermark = 0.8
+        low_watermark = 0.2
+        window = 64
+
+        [keyed_queues.orders]
+        shards = 4
+
+        [caches.pages]
+        shards = 8
+        max_entries = 1000
+        ttl_ms = 30000
+        early_refresh_beta = 1.0
+
+        [rate_counters.hosts]
+        window_ms = 1000
+        buckets = 10
+
+        [quotas.api]
+        refill = { kind = "token_bucket", capacity = 100, refill = 10, every_ms = 100 }
+
+        [sessions.users]
+        idle_timeout_ms = 60000
+        max_lifetime_ms = 3600000
+        shards = 2
+    "#;
+
+    fn invalid(section: &'static str, name: &str, reason: &'static str) -> ConfigError {
+        ConfigError::Invalid {
+            section,
+            name: name.to_string(),
+            reason,
+        }
+    }
+
+    #[test]
+    fn parses_toml() {
+        let config = PipelineConfig::from_toml(TOML).unwrap();
+        assert_eq!(
+            config.mailboxes["ingest"],
+            MailboxConfig {
+                capacity: 128,
+                policy: OverflowPolicy::DropNewest,
+            }
+        );
+        assert_eq!(config.buses["events"].policy, OverflowPolicy::Block);
+        assert_eq!(config.caches["pages"].max_entries, Some(1000));
+        assert_eq!(config.rate_counters["hosts"].shards, None);
+        assert_eq!(
+            config.quotas["api"].refill,
+            RefillConfig::TokenBucket {
+                capacity: 100,
+                refill: 10,
+                every_ms: 100,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_json() {
+        let config = PipelineConfig::from_json(
+            r#"{
+                "keyed_queues": { "orders": { "shards": 4 } },
+                "quotas": {
+                    "api": { "refill": { "kind": "fixed_window", "quota": 5, "window_ms": 1000 } }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.keyed_queues["orders"].shards, Some(4));
+        assert_eq!(
+            config.quotas["api"].refill.to_policy(),
+            RefillPolicy::FixedWindow {
+                quota: 5,
+                window: Duration::from_secs(1),
+            }
+        );
+        assert!(config.mailboxes.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_text() {
+        assert!(matches!(
+            PipelineConfig::from_json(r#"{ "mailboxes": { "a": { "capacity": 1, "size": 2 } } }"#),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            PipelineConfig::from_toml("[caches.a]\nshards = -1"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_zero_shards() {
+        assert_eq!(
+            PipelineConfig::from_toml("[keyed_queues.orders]\nshards = 0"),
+            Err(invalid("keyed_queues", "orders", "shards must be non-zero"))
+        );
+        assert_eq!(
+            PipelineConfig::from_json(r#"{ "caches": { "pages": { "shards": 0 } } }"#),
+            Err(invalid("caches", "pages", "shards must be non-zero"))
+        );
+    }
+
+    #[test]
+    fn rejects_inverted_watermarks() {
+        let text = "[adaptive_queues.work]\nhigh_watermark = 0.2\nlow_watermark = 0.8\nwindow = 64";
+        assert_eq!(
+            PipelineConfig::from_toml(text),
+            Err(invalid(
+                "adaptive_queues",
+                "work",
+                "low_watermark must not exceed high_watermark"
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_non_positive_beta() {
+        for beta in ["0.0", "-1.0", "nan"] {
+            let text = format!("[caches.pages]\nearly_refresh_beta = {}", beta);
+            assert_eq!(
+                PipelineConfig::from_toml(&text),
+                Err(invalid("caches", "pages", "early_refresh_beta must be positive")),
+                "beta = {}",
+                beta
+            );
+        }
+    }
+
+    #[test]
+    fn builds_every_section_of_a_parsed_config() {
+        let config = PipelineConfig::from_toml(TOML).unwrap();
+        let mailbox = config.mailbox::<u32>("ingest").unwrap();
+        assert_eq!(mailbox.capacity(), 128);
+        config.bus::<String>("events").unwrap();
+        config.adaptive_queue::<u32>("work").unwrap();
+        config.keyed_queue::<u32, u32>("orders").unwrap();
+        config.cache::<u32, String>("pages").unwrap();
+        config.rate_counter::<String>("hosts").unwrap();
+        config.quota_map::<String>("api").unwrap();
+        config.session_store::<u32, ()>("users").unwrap();
+        assert_eq!(
+            config.cache::<u32, String>("missing").err(),
+            Some(ConfigError::Missing {
+                section: "caches",
+                name: "missing".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn builds_edge_values_without_panicking() {
+        // The smallest values `validate` lets through, each of which the
+        // constructors must accept.
+        let config = PipelineConfig::from_toml(
+            r#"
+            [mailboxes.m]
+            capacity = 1
+            [buses.b]
+            capacity = 1
+            [adaptive_queues.a]
+            high_watermark = 0.5
+            low_watermark = 0.5
+            window = 1
+            [keyed_queues.k]
+            shards = 1
+            [caches.c]
+            shards = 1
+            max_entries = 0
+            ttl_ms = 0
+            early_refresh_beta = 1e-9
+            [rate_counters.r]
+            window_ms = 0
+            buckets = 1
+            shards = 1
+            [quotas.q]
+            refill = { kind = "fixed_window", quota = 0, window_ms = 0 }
+            shards = 1
+            [sessions.s]
+            idle_timeout_ms = 0
+            max_lifetime_ms = 0
+            shards = 1
+            "#,
+        )
+        .unwrap();
+        config.mailbox::<u32>("m").unwrap();
+        config.bus::<u32>("b").unwrap();
+        config.adaptive_queue::<u32>("a").unwrap();
+        config.keyed_queue::<u32, u32>("k").unwrap();
+        config.cache::<u32, u32>("c").unwrap();
+        config.rate_counter::<u32>("r").unwrap();
+        config.quota_map::<u32>("q").unwrap();
+        config.session_store::<u32, ()>("s").unwrap();
+    }
+}
@@ -0,0 +1,2704 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Add, Deref};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock, TryLockError, TryLockResult};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::eviction::EvictionReason;
+use crate::preload::{self, Progress};
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::{self, AtomicUsize, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::unwind::{self, PanicPolicy};
+
+const SLOTS_PER_BUCKET: usize = 4;
+const MIN_BUCKETS: usize = 2;
+// Bounds on the breadth-first search for a displacement path.
+const MAX_PATH_LEN: usize = 5;
+const MAX_SEARCHED_BUCKETS: usize = 512;
+// Buckets per chunk handed to a helper thread by `par_fold_snapshot`.
+const FOLD_CHUNK: usize = 64;
+
+type DropItem<K, V> = Box<dyn Fn(K, V) + Send + Sync>;
+type EvictionListener<K, V> = Box<dyn Fn(&K, &V, EvictionReason) + Send + Sync>;
+
+#[derive(Clone)]
+struct Entry<K, V> {
+    hash: u64,
+    key: K,
+    value: V,
+}
+
+// `overflow` holds entries that found no slot in either of their buckets
+// while the table was too empty to be worth doubling, which only happens
+// when many keys share their buckets. It is guarded by the bucket lock like
+// the slots, and entries in it never move.
+#[derive(Clone)]
+struct Bucket<K, V> {
+    slots: [Option<Entry<K, V>>; SLOTS_PER_BUCKET],
+    overflow: Vec<Entry<K, V>>,
+}
+
+impl<K, V> Bucket<K, V> {
+    fn new() -> Self {
+        Bucket {
+            slots: std::array::from_fn(|_| None),
+            overflow: Vec::new(),
+        }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &Entry<K, V>> {
+        self.slots.iter().flatten().chain(&self.overflow)
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = Entry<K, V>> + '_ {
+        self.slots
+            .iter_mut()
+            .filter_map(Option::take)
+            .chain(self.overflow.drain(..))
+    }
+
+    // Takes out the entries `keep` rejects, letting it update the ones it
+    // keeps, and uncounts them from `len` as it goes.
+    fn take_unless(
+        &mut self,
+        len: &LenCounter,
+        mut keep: impl FnMut(&K, &mut V) -> bool,
+    ) -> Vec<Entry<K, V>> {
+        let mut taken = Vec::new();
+        for slot in &mut self.slots {
+            if slot.as_mut().is_some_and(|e| !keep(&e.key, &mut e.value)) {
+                let entry = slot.take().unwrap();
+                len.sub(entry.hash, 1);
+                taken.push(entry);
+            }
+        }
+        let mut i = 0;
+        while i < self.overflow.len() {
+            let e = &mut self.overflow[i];
+            if keep(&e.key, &mut e.value) {
+                i += 1;
+            } else {
+                let entry = self.overflow.swap_remove(i);
+                len.sub(entry.hash, 1);
+                taken.push(entry);
+            }
+        }
+        taken
+    }
+}
+
+impl<K: Eq, V> Bucket<K, V> {
+    fn find<Q>(&self, key: &Q) -> Option<&Entry<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        self.entries().find(|e| e.key.borrow() == key)
+    }
+
+    fn find_mut<Q>(&mut self, key: &Q) -> Option<&mut Entry<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        self.slots
+            .iter_mut()
+            .flatten()
+            .chain(&mut self.overflow)
+            .find(|e| e.key.borrow() == key)
+    }
+
+    fn take<Q>(&mut self, key: &Q) -> Option<Entry<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let slot = self.slots.iter_mut().find(|s| matches!(s, Some(e) if e.key.borrow() == key));
+        if let Some(slot) = slot {
+            return slot.take();
+        }
+        let index = self.overflow.iter().position(|e| e.key.borrow() == key)?;
+        Some(self.overflow.swap_remove(index))
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.slots.iter().position(Option::is_none)
+    }
+}
+
+struct Table<K, V> {
+    buckets: Box<[RwLock<Bucket<K, V>>]>,
+    mask: usize,
+}
+
+impl<K: Eq, V> Table<K, V> {
+    fn new(num_buckets: usize) -> Self {
+        let num_buckets = num_buckets.max(MIN_BUCKETS).next_power_of_two();
+        Table {
+            buckets: (0..num_buckets).map(|_| RwLock::new(Bucket::new())).collect(),
+            mask: num_buckets - 1,
+        }
+    }
+
+    fn primary(&self, hash: u64) -> usize {
+        hash as usize & self.mask
+    }
+
+    // Symmetric: alternate(alternate(i, h), h) == i, so an entry can always
+    // find its other bucket from the one it is in.
+    fn alternate(&self, index: usize, hash: u64) -> usize {
+        let tag = ((hash >> 32) as usize | 1).wrapping_mul(0x5bd1_e995);
+        (index ^ tag) & self.mask
+    }
+
+    fn read(&self, index: usize) -> RwLockReadGuard<'_, Bucket<K, V>> {
+        self.buckets[index].read().unwrap()
+    }
+
+    // Locks one or two buckets, always in index order so concurrent
+    // writers cannot deadlock.
+    fn write_pair(&self, a: usize, b: usize) -> LockedPair<'_, K, V> {
+        if a == b {
+            return LockedPair {
+                first: self.buckets[a].write().unwrap(),
+                second: None,
+                swapped: false,
+            };
+        }
+        let (lo, hi) = if a < b { (a, b) } else { (b, a) };
+        let first = self.buckets[lo].write().unwrap();
+        let second = self.buckets[hi].write().unwrap();
+        LockedPair {
+            first,
+            second: Some(second),
+            swapped: a > b,
+        }
+    }
+
+    // Single-threaded insert used while rebuilding a table under the map's
+    // exclusive lock. Returns the entry back if no room could be made.
+    fn insert_exclusive(&mut self, mut entry: Entry<K, V>) -> Result<(), Entry<K, V>> {
+        let mut index = self.primary(entry.hash);
+        for kick in 0..MAX_SEARCHED_BUCKETS {
+            let alt = self.alternate(index, entry.hash);
+            for candidate in [index, alt] {
+                let bucket = self.buckets[candidate].get_mut().unwrap();
+                if let Some(slot) = bucket.free_slot() {
+                    bucket.slots[slot] = Some(entry);
+                    return Ok(());
+                }
+            }
+            // Evict a victim from the alternate bucket and carry on with it.
+            let bucket = self.buckets[alt].get_mut().unwrap();
+            let victim = bucket.slots[kick % SLOTS_PER_BUCKET].replace(entry).unwrap();
+            entry = victim;
+            index = alt;
+        }
+        Err(entry)
+    }
+
+    // Inserts or replaces under exclusive access, where `len` entries are
+    // already stored, and returns the previous value. If no room can be
+    // made the table doubles, unless it is sparse: see `is_sparse`.
+    fn put_exclusive(&mut self, entry: Entry<K, V>, len: usize) -> Option<(K, V)> {
+        let a = self.primary(entry.hash);
+        let b = self.alternate(a, entry.hash);
+        for index in [a, b] {
+            if let Some(old) = self.buckets[index].get_mut().unwrap().find_mut(&entry.key) {
+                return Some((entry.key, std::mem::replace(&mut old.value, entry.value)));
+            }
+        }
+        if let Err(entry) = self.insert_exclusive(entry) {
+            if self.is_sparse(len + 1) {
+                self.push_overflow(entry);
+            } else {
+                let mut entries = self.drain();
+                entries.push(entry);
+                *self = Table::build(entries, (self.mask + 1) * 2);
+            }
+        }
+        None
+    }
+
+    // Whether `len` entries would fill at most half the slots. Below that
+    // load, failing to place an entry means its buckets are crowded by keys
+    // sharing them, which doubling the table cannot spread out, so the
+    // entry goes to overflow instead.
+    fn is_sparse(&self, len: usize) -> bool {
+        len * 2 <= self.buckets.len() * SLOTS_PER_BUCKET
+    }
+
+    fn push_overflow(&mut self, entry: Entry<K, V>) {
+        let index = self.primary(entry.hash);
+        self.buckets[index].get_mut().unwrap().overflow.push(entry);
+    }
+
+    fn drain(&mut self) -> Vec<Entry<K, V>> {
+        self.buckets
+            .iter_mut()
+            .flat_map(|b| b.get_mut().unwrap().drain())
+            .collect()
+    }
+
+    // A table of at least `num_buckets` buckets holding `entries`, doubling
+    // until they all fit or the table is sparse, after which entries that
+    // find no slot go to overflow.
+    fn build(mut entries: Vec<Entry<K, V>>, num_buckets: usize) -> Self {
+        let mut table = Table::new(num_buckets);
+        'rebuild: loop {
+            let sparse = table.is_sparse(entries.len());
+            while let Some(entry) = entries.pop() {
+                if let Err(entry) = table.insert_exclusive(entry) {
+                    if sparse {
+                        table.push_overflow(entry);
+                        continue;
+                    }
+                    entries.push(entry);
+                    entries.extend(table.drain());
+                    table = Table::new(table.buckets.len() * 2);
+                    continue 'rebuild;
+                }
+            }
+            return table;
+        }
+    }
+}
+
+// Returned by the `try_` methods when a lock they need is held elsewhere,
+// or when inserting would have to move entries or grow the table. `item`
+// is whatever the call would have stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WouldBlock<T = ()> {
+    pub item: T,
+}
+
+impl<T> fmt::Display for WouldBlock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("operation would block on a lock held elsewhere")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for WouldBlock<T> {}
+
+// Returned by `try_insert` when the key is already present: the entry that
+// was not inserted, and a copy of the value that was already there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OccupiedError<K, V> {
+    pub key: K,
+    pub value: V,
+    pub existing: V,
+}
+
+impl<K, V> fmt::Display for OccupiedError<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("key is already present")
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> std::error::Error for OccupiedError<K, V> {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameError {
+    // The key to rename from is not in the map.
+    NotFound,
+    // The key to rename to is already in the map.
+    Occupied,
+}
+
+impl fmt::Display for RenameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenameError::NotFound => f.write_str("key to rename is not present"),
+            RenameError::Occupied => f.write_str("new key is already present"),
+        }
+    }
+}
+
+impl std::error::Error for RenameError {}
+
+// A key together with its hash under a map's hasher, made by
+// `CuckooHashMap::hash_key`. The `_prehashed` methods use the stored hash
+// instead of hashing the key again, and callers can route on `hash()` to
+// pick a map or shard without hashing twice. Only meaningful for the map
+// that made it, or one sharing its hasher; elsewhere lookups miss.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashedKey<K> {
+    key: K,
+    hash: u64,
+}
+
+impl<K> HashedKey<K> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+}
+
+// A lock held elsewhere becomes `WouldBlock`; a poisoned one panics, as
+// `unwrap` does on the blocking paths.
+fn try_acquire<G>(result: TryLockResult<G>) -> Result<G, WouldBlock> {
+    match result {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::WouldBlock) => Err(WouldBlock { item: () }),
+        Err(TryLockError::Poisoned(e)) => panic!("{e}"),
+    }
+}
+
+// A value read-locked in place; see `CuckooHashMap::get_ref`. `T` is the
+// part of the value the guard derefs to, narrowed with `map`.
+pub struct MapReadGuard<'a, K, V, T: ?Sized = V> {
+    // Declared first so it is released before the table lock it borrows.
+    _bucket: RwLockReadGuard<'a, Bucket<K, V>>,
+    value: *const T,
+    _table: RwLockReadGuard<'a, Table<K, V>>,
+}
+
+impl<'a, K, V, T: ?Sized> MapReadGuard<'a, K, V, T> {
+    // Narrows the guard to a part of the value, such as one field of a
+    // struct, keeping the bucket locked.
+    pub fn map<U: ?Sized>(guard: Self, f: impl FnOnce(&T) -> &U) -> MapReadGuard<'a, K, V, U> {
+        let value: *const U = f(&guard);
+        MapReadGuard {
+            _bucket: guard._bucket,
+            value,
+            _table: guard._table,
+        }
+    }
+}
+
+impl<K, V, T: ?Sized> Deref for MapReadGuard<'_, K, V, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: `value` points into the bucket, which cannot change while
+        // `_bucket` holds it read-locked.
+        unsafe { &*self.value }
+    }
+}
+
+// Retries `attempt` until it takes the lock or `deadline` passes.
+fn acquire_by<G>(deadline: Instant, mut attempt: impl FnMut() -> TryLockResult<G>) -> Result<G, WouldBlock> {
+    loop {
+        match try_acquire(attempt()) {
+            Err(_) if Instant::now() < deadline => thread::yield_now(),
+            result => return result,
+        }
+    }
+}
+
+struct LockedPair<'a, K, V> {
+    first: RwLockWriteGuard<'a, Bucket<K, V>>,
+    second: Option<RwLockWriteGuard<'a, Bucket<K, V>>>,
+    swapped: bool,
+}
+
+impl<K, V> LockedPair<'_, K, V> {
+    // Buckets in the order they were requested; the second is None when
+    // both indices were equal.
+    fn buckets(&mut self) -> (&mut Bucket<K, V>, Option<&mut Bucket<K, V>>) {
+        match (&mut self.second, self.swapped) {
+            (None, _) => (&mut self.first, None),
+            (Some(second), false) => (&mut self.first, Some(second)),
+            (Some(second), true) => (second, Some(&mut self.first)),
+        }
+    }
+}
+
+// The entry count, striped over cache-line-aligned counters so writers on
+// different keys rarely touch the same line. An entry is always counted in
+// the stripe picked by the low bits of its hash, which also pick its primary
+// bucket, so every stripe stays non-negative and a sum taken during
+// concurrent writes is at worst slightly stale.
+//
+// There is one stripe per core, rounded up to a power of two, so the count
+// scales with the machine without a knob to tune per deployment.
+struct LenCounter {
+    stripes: Box<[Stripe]>,
+}
+
+#[repr(align(64))]
+struct Stripe(AtomicUsize);
+
+impl LenCounter {
+    fn new() -> Self {
+        static NUM_STRIPES: OnceLock<usize> = OnceLock::new();
+        let num_stripes = *NUM_STRIPES.get_or_init(|| {
+            thread::available_parallelism()
+                .map_or(1, |n| n.get())
+                .next_power_of_two()
+        });
+        LenCounter {
+            stripes: (0..num_stripes).map(|_| Stripe(AtomicUsize::new(0))).collect(),
+        }
+    }
+
+    fn stripe(&self, hash: u64) -> &AtomicUsize {
+        &self.stripes[hash as usize & (self.stripes.len() - 1)].0
+    }
+
+    fn add(&self, hash: u64, n: usize) {
+        self.stripe(hash).fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn sub(&self, hash: u64, n: usize) {
+        self.stripe(hash).fetch_sub(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> usize {
+        self.stripes.iter().map(|s| s.0.load(Ordering::Relaxed)).sum()
+    }
+
+    fn clear(&self) {
+        for stripe in self.stripes.iter() {
+            stripe.0.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// Concurrent cuckoo hash map in the style of libcuckoo. Every key can live in
+// one of two buckets of four slots each, so a lookup inspects at most eight
+// slots under two bucket read locks. Inserts into full buckets search for a
+// short displacement path and move entries one step at a time, locking only
+// the two buckets involved in each step. The table is only locked as a whole
+// while it doubles in size.
+//
+// Four-way buckets keep the table usable at load factors above 90%, which
+// makes this a denser alternative to per-shard `HashMap`s for read-mostly
+// workloads.
+pub struct CuckooHashMap<K, V, S = RandomState> {
+    table: RwLock<Table<K, V>>,
+    len: LenCounter,
+    hasher: S,
+    on_drop_item: Option<DropItem<K, V>>,
+    eviction_listener: Option<EvictionListener<K, V>>,
+    panic_policy: PanicPolicy,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq, V> CuckooHashMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, RandomState::new())
+    }
+}
+
+// `S` picks both buckets of every key and nothing else hashes keys, so a
+// faster hasher speeds up every operation and a keyed one protects all of
+// them against crafted collisions.
+impl<K: Hash + Eq, V, S: BuildHasher> CuckooHashMap<K, V, S> {
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        CuckooHashMap {
+            table: RwLock::new(Table::new(capacity.div_ceil(SLOTS_PER_BUCKET))),
+            len: LenCounter::new(),
+            hasher,
+            on_drop_item: None,
+            eviction_listener: None,
+            panic_policy: PanicPolicy::default(),
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    // Called with each entry still in the map when it is dropped, instead of
+    // dropping it silently.
+    pub fn with_on_drop_item(mut self, f: impl Fn(K, V) + Send + Sync + 'static) -> Self {
+        self.on_drop_item = Some(Box::new(f));
+        self
+    }
+
+    // Called with every entry the map lets go of while it is in use, after
+    // the bucket locks are released, so the listener may call back into the
+    // map: `Explicit` for `remove`, `remove_if`, `compute` returning None,
+    // `retain`, `clear` and values discarded by the panic policy, and
+    // `Replaced` for values overwritten by `insert`, `insert_batch`,
+    // `merge`, `compute` or `extend`. Values changed in place through
+    // `with_mut` and the methods built on it are not reported, nor are
+    // entries handed to the caller by `drain` or `into_iter`.
+    pub fn with_eviction_listener(
+        mut self,
+        listener: impl Fn(&K, &V, EvictionReason) + Send + Sync + 'static,
+    ) -> Self {
+        self.eviction_listener = Some(Box::new(listener));
+        self
+    }
+
+    fn notify(&self, key: &K, value: &V, reason: EvictionReason) {
+        if let Some(listener) = &self.eviction_listener {
+            listener(key, value, reason);
+        }
+    }
+
+    // What happens to an entry whose `with_mut` closure panics, including
+    // the closures run by `compare_and_swap` and `with_mut_or_default`.
+    // Either way the bucket locks are released unpoisoned before the panic
+    // is resumed.
+    pub fn with_panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hasher
+    }
+
+    // Hashes `key` once for use with the `_prehashed` methods.
+    pub fn hash_key(&self, key: K) -> HashedKey<K> {
+        HashedKey {
+            hash: self.hasher.hash_one(&key),
+            key,
+        }
+    }
+
+    // Like std's `HashMap`, lookups take any borrowed form of the key, such
+    // as `&str` for `String` keys, as long as it hashes the same.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.with(key, V::clone)
+    }
+
+    pub fn get_prehashed(&self, key: &HashedKey<K>) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.with_hashed(key.hash, &key.key, V::clone)
+    }
+
+    // Looks `key` up by reference, like hashbrown's `entry_ref`: the key is
+    // only turned into an owned `K`, e.g. a `String` allocated from a
+    // `&str`, when its entry has to be inserted.
+    pub fn entry_ref<'q, Q>(&self, key: &'q Q) -> EntryRef<'_, 'q, K, Q, V, S>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        EntryRef { map: self, key }
+    }
+
+    // Returns a guard that derefs to the value for `key`, keeping the bucket
+    // holding it read-locked instead of cloning the value. Until the guard
+    // is dropped, writers to that bucket and anything that needs the whole
+    // table (growing, `clear`, `insert_batch`) wait, so the guard should be
+    // short-lived, and the thread holding it must not write to the map.
+    pub fn get_ref<Q>(&self, key: &Q) -> Option<MapReadGuard<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        let table = self.table.read().unwrap();
+        // Safety: the buckets live as long as the table lock is held, and
+        // the guard holds it until after the bucket lock is released.
+        let buckets = unsafe { &*(&*table as *const Table<K, V>) };
+        let a = buckets.primary(hash);
+        let b = buckets.alternate(a, hash);
+        let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+        // Both are held until the key is found, as in `with`; after that,
+        // the entry cannot move while its own bucket stays locked.
+        let first = buckets.read(lo);
+        let second = if lo != hi { Some(buckets.read(hi)) } else { None };
+        let bucket = match first.find(key) {
+            Some(_) => first,
+            None => second.filter(|second| second.find(key).is_some())?,
+        };
+        let value: *const V = &bucket.find(key).unwrap().value;
+        Some(MapReadGuard {
+            _bucket: bucket,
+            value,
+            _table: table,
+        })
+    }
+
+    // Looks up every key in `keys`, read-locking each bucket involved once,
+    // in index order, and holding them all until the last lookup. The
+    // results reflect a single point in time.
+    pub fn multi_get(&self, keys: &[K]) -> Vec<Option<V>>
+    where
+        V: Clone,
+    {
+        let table = self.table.read().unwrap();
+        let candidates: Vec<(usize, usize)> = keys
+            .iter()
+            .map(|key| {
+                let hash = self.hasher.hash_one(key);
+                let a = table.primary(hash);
+                (a, table.alternate(a, hash))
+            })
+            .collect();
+        let mut indices: Vec<usize> = candidates.iter().flat_map(|&(a, b)| [a, b]).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        let buckets: Vec<_> = indices.iter().map(|&i| table.read(i)).collect();
+        keys.iter()
+            .zip(candidates)
+            .map(|(key, (a, b))| {
+                [a, b].into_iter().find_map(|index| {
+                    let bucket = &buckets[indices.binary_search(&index).unwrap()];
+                    bucket.find(key).map(|e| e.value.clone())
+                })
+            })
+            .collect()
+    }
+
+    // Like `get`, but returns `WouldBlock` instead of waiting if the table
+    // or either candidate bucket is locked by a writer.
+    pub fn try_get<Q>(&self, key: &Q) -> Result<Option<V>, WouldBlock>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        self.get_timeout(key, Duration::ZERO)
+    }
+
+    // Like `get`, but gives up with `WouldBlock` if the locks it needs are
+    // not all taken within `timeout`, to keep a request within its latency
+    // budget. std's locks cannot wait with a timeout, so the thread retries
+    // them, yielding in between, until the deadline.
+    pub fn get_timeout<Q>(&self, key: &Q, timeout: Duration) -> Result<Option<V>, WouldBlock>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: Clone,
+    {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            return Ok(self.get(key));
+        };
+        let hash = self.hasher.hash_one(key);
+        let table = acquire_by(deadline, || self.table.try_read())?;
+        let a = table.primary(hash);
+        let b = table.alternate(a, hash);
+        let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+        let first = acquire_by(deadline, || table.buckets[lo].try_read())?;
+        let second = if lo != hi {
+            Some(acquire_by(deadline, || table.buckets[hi].try_read())?)
+        } else {
+            None
+        };
+        for bucket in std::iter::once(&*first).chain(second.as_deref()) {
+            if let Some(entry) = bucket.find(key) {
+                return Ok(Some(entry.value.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with(key, |_| ()).is_some()
+    }
+
+    // Runs `f` on the value for `key` while both candidate buckets are
+    // read-locked.
+    pub fn with<Q, R>(&self, key: &Q, f: impl FnOnce(&V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with_hashed(self.hasher.hash_one(key), key, f)
+    }
+
+    fn with_hashed<Q, R>(&self, hash: u64, key: &Q, f: impl FnOnce(&V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let table = self.table.read().unwrap();
+        let a = table.primary(hash);
+        let b = table.alternate(a, hash);
+        let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+        // Both buckets are held together so an entry moving between them
+        // cannot be missed.
+        let first = table.read(lo);
+        let second = if lo != hi { Some(table.read(hi)) } else { None };
+        for bucket in std::iter::once(&*first).chain(second.as_deref()) {
+            if let Some(entry) = bucket.find(key) {
+                return Some(f(&entry.value));
+            }
+        }
+        None
+    }
+
+    // Runs `f` on the value for `key` in place while both candidate buckets
+    // are write-locked. If `f` panics, the panic policy decides whether the
+    // value keeps whatever changes `f` made or the entry is removed, and the
+    // locks are released unpoisoned before the panic resumes.
+    pub fn with_mut<Q, R>(&self, key: &Q, f: impl FnOnce(&mut V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        let table = self.table.read().unwrap();
+        let a = table.primary(hash);
+        let b = table.alternate(a, hash);
+        let mut pair = table.write_pair(a, b);
+        let (first, second) = pair.buckets();
+        let bucket = std::iter::once(first)
+            .chain(second)
+            .find(|bucket| bucket.find(key).is_some())?;
+        match unwind::catch(|| f(&mut bucket.find_mut(key).unwrap().value)) {
+            Ok(result) => Some(result),
+            Err(payload) => {
+                // Dropped after the locks are released.
+                let discarded = match self.panic_policy {
+                    PanicPolicy::Keep => None,
+                    PanicPolicy::Discard => {
+                        self.len.sub(hash, 1);
+                        bucket.take(key)
+                    }
+                };
+                drop(pair);
+                if let Some(entry) = &discarded {
+                    self.notify(&entry.key, &entry.value, EvictionReason::Explicit);
+                }
+                drop(discarded);
+                unwind::resume(payload)
+            }
+        }
+    }
+
+    // Runs `f` on the value for `key` in place, as `with_mut` does, and
+    // returns whether the key was present. For read-modify-write updates
+    // that must not be lost to a concurrent `get` and `insert`.
+    pub fn update<Q>(&self, key: &Q, f: impl FnOnce(&mut V)) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with_mut(key, f).is_some()
+    }
+
+    // Replaces the value for `key` with `new` if it equals `expected`,
+    // comparing and writing under the same bucket locks. Hands `new` back if
+    // the value differs or the key is absent.
+    pub fn compare_and_swap<Q>(&self, key: &Q, expected: &V, new: V) -> Result<(), V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+        V: PartialEq,
+    {
+        let mut new = Some(new);
+        let swapped = self.with_mut(key, |value| {
+            if *value == *expected {
+                *value = new.take().unwrap();
+            }
+        });
+        match (swapped, new) {
+            (Some(()), None) => Ok(()),
+            (_, new) => Err(new.unwrap()),
+        }
+    }
+
+    // Inserts or replaces the value for `key`, returning the previous value.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.put(self.hash_key(key), value, true)
+    }
+
+    pub fn insert_prehashed(&self, key: HashedKey<K>, value: V) -> Option<V> {
+        self.put(key, value, true)
+    }
+
+    // Inserts or replaces every entry in `items` under a single acquisition
+    // of the table's write lock, instead of two bucket locks per entry.
+    // Lookups wait for the whole batch, so very large batches are better
+    // split. Returns how many keys were new.
+    pub fn insert_batch(&self, items: impl IntoIterator<Item = (K, V)>) -> usize {
+        // Hashed before locking so a panicking iterator or hasher cannot
+        // poison the table.
+        let entries: Vec<Entry<K, V>> = items
+            .into_iter()
+            .map(|(key, value)| Entry {
+                hash: self.hasher.hash_one(&key),
+                key,
+                value,
+            })
+            .collect();
+        let mut added = 0;
+        // Replaced values are dropped after the lock is released.
+        let mut replaced = Vec::new();
+        let mut table = self.table.write().unwrap();
+        let len = self.len();
+        for entry in entries {
+            let hash = entry.hash;
+            match table.put_exclusive(entry, len + added) {
+                Some(old) => replaced.push(old),
+                None => {
+                    self.len.add(hash, 1);
+                    added += 1;
+                }
+            }
+        }
+        drop(table);
+        for (key, value) in &replaced {
+            self.notify(key, value, EvictionReason::Replaced);
+        }
+        added
+    }
+
+    // Like `insert`, but hands the entry back in `WouldBlock` instead of
+    // waiting if the table or either candidate bucket is locked, or if
+    // both buckets are full and entries would have to be moved first.
+    pub fn try_insert_nonblocking(&self, key: K, value: V) -> Result<Option<V>, WouldBlock<(K, V)>> {
+        let hash = self.hasher.hash_one(&key);
+        let blocked = |key, value| WouldBlock { item: (key, value) };
+        let Ok(table) = try_acquire(self.table.try_read()) else {
+            return Err(blocked(key, value));
+        };
+        let a = table.primary(hash);
+        let b = table.alternate(a, hash);
+        let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+        let Ok(mut first) = try_acquire(table.buckets[lo].try_write()) else {
+            return Err(blocked(key, value));
+        };
+        let mut second = None;
+        if lo != hi {
+            let Ok(guard) = try_acquire(table.buckets[hi].try_write()) else {
+                return Err(blocked(key, value));
+            };
+            second = Some(guard);
+        }
+
+        let mut present = None;
+        let mut free = None;
+        let buckets = std::iter::once(&mut *first).chain(second.as_deref_mut());
+        for (i, bucket) in buckets.enumerate() {
+            if bucket.find(&key).is_some() {
+                present = Some(i);
+                break;
+            }
+            if free.is_none() {
+                free = bucket.free_slot().map(|slot| (i, slot));
+            }
+        }
+        if let Some(i) = present {
+            let bucket = if i == 0 { &mut *first } else { second.as_deref_mut().unwrap() };
+            let old = std::mem::replace(&mut bucket.find_mut(&key).unwrap().value, value);
+            drop(first);
+            drop(second);
+            drop(table);
+            self.notify(&key, &old, EvictionReason::Replaced);
+            return Ok(Some(old));
+        }
+        let Some((i, slot)) = free else {
+            return Err(blocked(key, value));
+        };
+        let bucket = if i == 0 { &mut *first } else { second.as_deref_mut().unwrap() };
+        bucket.slots[slot] = Some(Entry { hash, key, value });
+        self.len.add(hash, 1);
+        Ok(None)
+    }
+
+    // Runs `f` on the value for `key` in place, first inserting
+    // `V::default()` if the key is absent. Concurrent callers never both
+    // insert a default: an update made by one is seen by the other.
+    pub fn with_mut_or_default<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> R
+    where
+        K: Clone,
+        V: Default,
+    {
+        let mut f = Some(f);
+        loop {
+            if let Some(result) = self.with_mut(key, |value| f.take().unwrap()(value)) {
+                return result;
+            }
+            // Another thread may insert or remove the key in between; the
+            // default only goes in if the key is still absent.
+            self.put(self.hash_key(key.clone()), V::default(), false);
+        }
+    }
+
+    pub fn get_or_default(&self, key: &K) -> V
+    where
+        K: Clone,
+        V: Default + Clone,
+    {
+        self.with_mut_or_default(key, |value| value.clone())
+    }
+
+    // Replaces the value for `key` with `f(&value)`, starting from
+    // `V::default()` if the key is absent, and returns the previous value.
+    // Read and write happen under the same bucket locks, so concurrent
+    // updates are never lost.
+    pub fn fetch_update(&self, key: &K, f: impl FnOnce(&V) -> V) -> V
+    where
+        K: Clone,
+        V: Default,
+    {
+        self.with_mut_or_default(key, |value| {
+            let new = f(value);
+            std::mem::replace(value, new)
+        })
+    }
+
+    // Adds `delta` to the value for `key`, starting from `V::default()` if
+    // the key is absent, and returns the new value. For per-key counters.
+    pub fn add(&self, key: &K, delta: V) -> V
+    where
+        K: Clone,
+        V: Default + Add<Output = V> + Copy,
+    {
+        self.with_mut_or_default(key, |value| {
+            *value = *value + delta;
+            *value
+        })
+    }
+
+    // Returns a clone of the value for `key`, first inserting `make()` if
+    // the key is absent. `make` runs with both candidate buckets
+    // write-locked, so concurrent callers for the same key wait for its
+    // value instead of running their own `make`; lookups of keys sharing
+    // those buckets wait too. If `make` panics, nothing is inserted and the
+    // locks are released unpoisoned.
+    pub fn get_or_insert_with(&self, key: K, make: impl FnOnce() -> V) -> V
+    where
+        V: Clone,
+    {
+        self.get_or_insert_hashed(self.hash_key(key), make)
+    }
+
+    fn get_or_insert_hashed(&self, key: HashedKey<K>, make: impl FnOnce() -> V) -> V
+    where
+        V: Clone,
+    {
+        let mut made = None;
+        let existing = self.upsert(
+            key,
+            make,
+            |bucket, key, _| bucket.find(&key).unwrap().value.clone(),
+            |make| {
+                let value = make();
+                made = Some(value.clone());
+                Some(value)
+            },
+        );
+        existing.or(made).unwrap()
+    }
+
+    // Stores the entry if `key` is absent. If it is present, replaces and
+    // returns the old value when `replace` is set, and otherwise leaves the
+    // map unchanged and returns `value` back.
+    fn put(&self, key: HashedKey<K>, value: V, replace: bool) -> Option<V> {
+        let (key, old) = self.upsert(
+            key,
+            value,
+            |bucket, key, value| {
+                if !replace {
+                    return (None, value);
+                }
+                let old = std::mem::replace(&mut bucket.find_mut(&key).unwrap().value, value);
+                (Some(key), old)
+            },
+            Some,
+        )?;
+        if let Some(key) = key {
+            self.notify(&key, &old, EvictionReason::Replaced);
+        }
+        Some(old)
+    }
+
+    // Inserts the entry only if `key` is absent. Otherwise leaves the map
+    // unchanged and hands the entry back in `OccupiedError`, with a copy of
+    // the value already stored, for registrations that must not overwrite
+    // one another. `LockFreeHashMap::try_insert` means the same.
+    pub fn try_insert(&self, key: K, value: V) -> Result<(), OccupiedError<K, V>>
+    where
+        V: Clone,
+    {
+        let occupied = self.upsert(
+            self.hash_key(key),
+            value,
+            |bucket, key, value| {
+                let existing = bucket.find(&key).unwrap().value.clone();
+                OccupiedError { key, value, existing }
+            },
+            Some,
+        );
+        occupied.map_or(Ok(()), Err)
+    }
+
+    // Inserts `value` if `key` is absent, and otherwise replaces the stored
+    // value with `f(&stored, value)`, returning the previous value. The
+    // combination happens under the bucket locks, so concurrent writers
+    // merge their values (summing counters, unioning sets) instead of the
+    // last one winning. If `f` panics, the stored value is left unchanged
+    // and the locks are released unpoisoned.
+    pub fn merge(&self, key: K, value: V, f: impl FnOnce(&V, V) -> V) -> Option<V> {
+        self.upsert(
+            self.hash_key(key),
+            value,
+            |bucket, key, value| {
+                let entry = bucket.find_mut(&key).unwrap();
+                let merged = f(&entry.value, value);
+                (key, std::mem::replace(&mut entry.value, merged))
+            },
+            Some,
+        )
+        .map(|(key, old)| {
+            self.notify(&key, &old, EvictionReason::Replaced);
+            old
+        })
+    }
+
+    // Sets the value for `key` to `f(current value)`, removing the entry
+    // when `f` returns None and inserting one when the key was absent and
+    // `f` returns a value. Returns the previous value. `f` runs with both
+    // candidate buckets write-locked, so it is the building block for
+    // updates that may insert or delete; if it panics, the map is left
+    // unchanged and the locks are released unpoisoned.
+    pub fn compute(&self, key: K, f: impl FnOnce(Option<&V>) -> Option<V>) -> Option<V> {
+        self.upsert(
+            self.hash_key(key),
+            f,
+            |bucket, key, f| {
+                let entry = bucket.find_mut(&key).unwrap();
+                match f(Some(&entry.value)) {
+                    Some(new) => {
+                        let old = std::mem::replace(&mut entry.value, new);
+                        (key, old, EvictionReason::Replaced)
+                    }
+                    None => {
+                        let entry = bucket.take(&key).unwrap();
+                        self.len.sub(entry.hash, 1);
+                        (entry.key, entry.value, EvictionReason::Explicit)
+                    }
+                }
+            },
+            |f| f(None),
+        )
+        .map(|(key, old, reason)| {
+            self.notify(&key, &old, reason);
+            old
+        })
+    }
+
+    // Looks `key` up with both candidate buckets write-locked. If it is
+    // present, returns `present(bucket, key, state)` for the bucket holding
+    // it. Otherwise waits for a free slot, making room or growing the table
+    // as needed, stores what `absent(state)` returns there, if anything, and
+    // returns None. Only one of the two runs, under the bucket locks; if it
+    // panics, the locks are released unpoisoned before the panic resumes.
+    fn upsert<T, R>(
+        &self,
+        key: HashedKey<K>,
+        state: T,
+        present: impl FnOnce(&mut Bucket<K, V>, K, T) -> R,
+        absent: impl FnOnce(T) -> Option<V>,
+    ) -> Option<R> {
+        let HashedKey { key, hash } = key;
+        // Set once no room could be made in a sparse table.
+        let mut overflow = false;
+        loop {
+            let table = self.table.read().unwrap();
+            let a = table.primary(hash);
+            let b = table.alternate(a, hash);
+
+            {
+                let mut pair = table.write_pair(a, b);
+                let (first, second) = pair.buckets();
+                let mut buckets = std::iter::once(first).chain(second);
+                let mut free = None;
+                for (i, bucket) in buckets.by_ref().enumerate() {
+                    if bucket.find(&key).is_some() {
+                        match unwind::catch(|| present(bucket, key, state)) {
+                            Ok(result) => return Some(result),
+                            Err(payload) => {
+                                drop(pair);
+                                unwind::resume(payload)
+                            }
+                        }
+                    }
+                    if free.is_none() {
+                        free = bucket.free_slot().map(|slot| (i, slot));
+                    }
+                }
+                drop(buckets);
+                if free.is_some() || overflow {
+                    let value = match unwind::catch(|| absent(state)) {
+                        Ok(Some(value)) => value,
+                        Ok(None) => return None,
+                        Err(payload) => {
+                            drop(pair);
+                            unwind::resume(payload)
+                        }
+                    };
+                    let entry = Entry { hash, key, value };
+                    let (first, second) = pair.buckets();
+                    match free {
+                        Some((0, slot)) => first.slots[slot] = Some(entry),
+                        Some((_, slot)) => second.unwrap().slots[slot] = Some(entry),
+                        None => first.overflow.push(entry),
+                    }
+                    self.len.add(hash, 1);
+                    return None;
+                }
+            }
+
+            if self.make_room(&table, a, b) {
+                continue;
+            }
+            if table.is_sparse(self.len() + 1) {
+                overflow = true;
+                continue;
+            }
+            let mask = table.mask;
+            drop(table);
+            self.grow(mask);
+        }
+    }
+
+    // Moves the value stored under `old` to `new` in one step, e.g. when
+    // migrating identifiers. The candidate buckets of both keys are
+    // write-locked together, in index order, so every reader finds the
+    // value under exactly one of the two keys. Fails without changing
+    // anything if `old` is absent or `new` is present.
+    pub fn rename<Q>(&self, old: &Q, new: K) -> Result<(), RenameError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let old_hash = self.hasher.hash_one(old);
+        let new_hash = self.hasher.hash_one(&new);
+        // Set once no room could be made in a sparse table.
+        let mut overflow = false;
+        loop {
+            let table = self.table.read().unwrap();
+            let from_a = table.primary(old_hash);
+            let from_b = table.alternate(from_a, old_hash);
+            let a = table.primary(new_hash);
+            let b = table.alternate(a, new_hash);
+            let mut indices = vec![from_a, from_b, a, b];
+            indices.sort_unstable();
+            indices.dedup();
+            let mut buckets: Vec<_> = indices.iter().map(|&i| table.buckets[i].write().unwrap()).collect();
+            let at = |index| indices.binary_search(&index).unwrap();
+
+            let from = [from_a, from_b]
+                .into_iter()
+                .find(|&i| buckets[at(i)].find(old).is_some())
+                .ok_or(RenameError::NotFound)?;
+            if [a, b].into_iter().any(|i| buckets[at(i)].find::<K>(&new).is_some()) {
+                return Err(RenameError::Occupied);
+            }
+            // Taken out first, as its slot may be the one the new key needs.
+            let mut entry = buckets[at(from)].take(old).unwrap();
+            let free = [a, b]
+                .into_iter()
+                .find_map(|i| buckets[at(i)].free_slot().map(|slot| (i, slot)));
+            if free.is_some() || overflow {
+                self.len.sub(entry.hash, 1);
+                self.len.add(new_hash, 1);
+                entry.key = new;
+                entry.hash = new_hash;
+                match free {
+                    Some((i, slot)) => buckets[at(i)].slots[slot] = Some(entry),
+                    None => buckets[at(a)].overflow.push(entry),
+                }
+                return Ok(());
+            }
+
+            // No room under the new key: put the entry back and make some.
+            let bucket = &mut buckets[at(from)];
+            match bucket.free_slot() {
+                Some(slot) => bucket.slots[slot] = Some(entry),
+                None => bucket.overflow.push(entry),
+            }
+            drop(buckets);
+            if self.make_room(&table, a, b) {
+                continue;
+            }
+            if table.is_sparse(self.len() + 1) {
+                overflow = true;
+                continue;
+            }
+            let mask = table.mask;
+            drop(table);
+            self.grow(mask);
+        }
+    }
+
+    // Inserts `entries` from helper threads, reporting progress as it goes.
+    // Existing values are replaced, as with `insert`; if `entries` repeats a
+    // key, which of its values ends up stored is unspecified. Returns how
+    // many keys were new.
+    pub fn preload(
+        &self,
+        entries: impl IntoIterator<Item = (K, V)>,
+        progress: impl Fn(Progress) + Sync,
+    ) -> usize
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        S: Sync,
+    {
+        preload::run(
+            entries,
+            |(key, value)| self.insert(key, value).is_none(),
+            progress,
+        )
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.remove_hashed(self.hasher.hash_one(key), key)
+    }
+
+    pub fn remove_prehashed(&self, key: &HashedKey<K>) -> Option<V> {
+        self.remove_hashed(key.hash, &key.key)
+    }
+
+    fn remove_hashed<Q>(&self, hash: u64, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + ?Sized,
+    {
+        let table = self.table.read().unwrap();
+        let a = table.primary(hash);
+        let b = table.alternate(a, hash);
+        let mut pair = table.write_pair(a, b);
+        let (first, second) = pair.buckets();
+        let entry = std::iter::once(first).chain(second).find_map(|bucket| bucket.take(key));
+        drop(pair);
+        let entry = entry?;
+        self.len.sub(hash, 1);
+        self.notify(&entry.key, &entry.value, EvictionReason::Explicit);
+        Some(entry.value)
+    }
+
+    // Removes the entry for `key` only if `predicate` holds for its value,
+    // deciding and removing under the same bucket locks. Returns the removed
+    // value. `predicate` only sees the value, so a panic in it leaves the
+    // entry in place whatever the panic policy.
+    pub fn remove_if<Q>(&self, key: &Q, predicate: impl FnOnce(&V) -> bool) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        let table = self.table.read().unwrap();
+        let a = table.primary(hash);
+        let b = table.alternate(a, hash);
+        let mut pair = table.write_pair(a, b);
+        let (first, second) = pair.buckets();
+        let bucket = std::iter::once(first)
+            .chain(second)
+            .find(|bucket| bucket.find(key).is_some())?;
+        match unwind::catch(|| predicate(&bucket.find(key).unwrap().value)) {
+            Ok(true) => {
+                self.len.sub(hash, 1);
+                let entry = bucket.take(key).unwrap();
+                drop(pair);
+                self.notify(&entry.key, &entry.value, EvictionReason::Explicit);
+                Some(entry.value)
+            }
+            Ok(false) => None,
+            Err(payload) => {
+                drop(pair);
+                unwind::resume(payload)
+            }
+        }
+    }
+
+    // Sums the striped counters without taking any lock, so it stays cheap
+    // under heavy writes.
+    pub fn len(&self) -> usize {
+        self.len.get()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Number of slots in the current table.
+    pub fn capacity(&self) -> usize {
+        self.table.read().unwrap().buckets.len() * SLOTS_PER_BUCKET
+    }
+
+    // Grows the table once, if needed, so that `additional` more entries
+    // fit in its slots, instead of doubling it step by step during a bulk
+    // load. The whole table is locked while it is rebuilt.
+    pub fn reserve(&self, additional: usize) {
+        let mut table = self.table.write().unwrap();
+        let needed = self.len().saturating_add(additional);
+        if table.buckets.len() * SLOTS_PER_BUCKET >= needed {
+            return;
+        }
+        let entries = table.drain();
+        *table = Table::build(entries, needed.div_ceil(SLOTS_PER_BUCKET));
+    }
+
+    // Rebuilds the table at the smallest size that holds the current
+    // entries and at least `min_capacity` slots, e.g. after heavy churn.
+    // Never grows the table. The whole table is locked while it is rebuilt.
+    pub fn shrink_to(&self, min_capacity: usize) {
+        let mut table = self.table.write().unwrap();
+        let num_buckets = self.len().max(min_capacity).div_ceil(SLOTS_PER_BUCKET);
+        if num_buckets.max(MIN_BUCKETS).next_power_of_two() >= table.buckets.len() {
+            return;
+        }
+        let entries = table.drain();
+        *table = Table::build(entries, num_buckets);
+    }
+
+    pub fn shrink_to_fit(&self) {
+        self.shrink_to(0);
+    }
+
+    // Removes every entry, keeping the table's capacity. The whole table is
+    // locked, so the map empties at a single point in time: inserts that
+    // finished before are removed, ones that start after are kept. Values
+    // are dropped after the lock is released.
+    pub fn clear(&self) {
+        let mut old = {
+            let mut table = self.table.write().unwrap();
+            self.len.clear();
+            let num_buckets = table.buckets.len();
+            std::mem::replace(&mut *table, Table::new(num_buckets))
+        };
+        if self.eviction_listener.is_some() {
+            for entry in old.drain() {
+                self.notify(&entry.key, &entry.value, EvictionReason::Explicit);
+            }
+        }
+        drop(old);
+    }
+
+    // Keeps only the entries for which `f` returns true, letting `f` update
+    // the values it keeps. Buckets are write-locked one at a time, so the
+    // rest of the map stays usable during the sweep, but it is not a single
+    // point in time: an entry inserted meanwhile may or may not be visited,
+    // and one moved between buckets by a concurrent insert may be visited
+    // twice or not at all. `f` must not call back into the map. If it
+    // panics, its entry is kept and the lock is released unpoisoned.
+    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let table = self.table.read().unwrap();
+        for bucket in table.buckets.iter() {
+            let mut bucket = bucket.write().unwrap();
+            match unwind::catch(|| bucket.take_unless(&self.len, &mut f)) {
+                Ok(removed) => {
+                    drop(bucket);
+                    for entry in &removed {
+                        self.notify(&entry.key, &entry.value, EvictionReason::Explicit);
+                    }
+                    drop(removed);
+                }
+                Err(payload) => {
+                    drop(bucket);
+                    unwind::resume(payload)
+                }
+            }
+        }
+    }
+
+    // Yields a copy of every entry, read-locking one bucket at a time while
+    // copying it. Weakly consistent: entries inserted or removed meanwhile
+    // may or may not be seen, and one moved between buckets by a concurrent
+    // insert or by the table growing may be seen twice or not at all. For a
+    // single point in time, use `snapshot`.
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_
+    where
+        K: Clone,
+        V: Clone,
+    {
+        Entries::new(self, |key, value| (key.clone(), value.clone()))
+    }
+
+    // Like `iter`, copying out only the keys, e.g. for a periodic export.
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_
+    where
+        K: Clone,
+    {
+        Entries::new(self, |key, _| key.clone())
+    }
+
+    // Like `iter`, copying out only the values.
+    pub fn values(&self) -> impl Iterator<Item = V> + '_
+    where
+        V: Clone,
+    {
+        Entries::new(self, |_, value| value.clone())
+    }
+
+    // Removes and yields every entry, write-locking one bucket at a time to
+    // empty it into the iterator. Each entry is either still in the map or
+    // owned by the iterator; dropping the iterator early puts back the
+    // taken entries it has not yielded, unless their key was inserted again
+    // meanwhile, and leaves the buckets it has not reached alone. Weakly
+    // consistent like `iter`: entries inserted meanwhile, or moved by a
+    // concurrent insert into a bucket already emptied, stay in the map.
+    pub fn drain(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        Drain {
+            map: self,
+            next_bucket: 0,
+            buffer: Vec::new().into_iter(),
+        }
+    }
+
+    // Visits every entry without copying it, as weakly consistent as
+    // `iter`. `f` runs with the entry's bucket read-locked and must not
+    // write to the map.
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+        let table = self.table.read().unwrap();
+        for index in 0..table.buckets.len() {
+            for entry in table.read(index).entries() {
+                f(&entry.key, &entry.value);
+            }
+        }
+    }
+
+    // Folds `f` over every entry, for totals and metrics over maps too large
+    // to copy. Only one bucket is locked at a time, as in `iter`, with the
+    // same weak consistency; `f` runs with the entry's bucket read-locked
+    // and must not write to the map.
+    pub fn fold_snapshot<A>(&self, init: A, mut f: impl FnMut(A, &K, &V) -> A) -> A {
+        let mut acc = init;
+        for index in 0.. {
+            let table = self.table.read().unwrap();
+            if index >= table.buckets.len() {
+                break;
+            }
+            for entry in table.read(index).entries() {
+                acc = f(acc, &entry.key, &entry.value);
+            }
+        }
+        acc
+    }
+
+    // Like `fold_snapshot`, spread over one helper thread per core. Each
+    // thread folds the chunks of buckets it takes into its own `init()`,
+    // and the results are then merged with `combine` in no particular
+    // order.
+    pub fn par_fold_snapshot<A: Send>(
+        &self,
+        init: impl Fn() -> A + Sync,
+        fold: impl Fn(A, &K, &V) -> A + Sync,
+        combine: impl Fn(A, A) -> A,
+    ) -> A
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        S: Sync,
+    {
+        let next_chunk = AtomicUsize::new(0);
+        let work = || {
+            let mut acc = init();
+            loop {
+                let start = next_chunk.fetch_add(FOLD_CHUNK, Ordering::Relaxed);
+                let table = self.table.read().unwrap();
+                let end = (start + FOLD_CHUNK).min(table.buckets.len());
+                if start >= end {
+                    return acc;
+                }
+                for index in start..end {
+                    for entry in table.read(index).entries() {
+                        acc = fold(acc, &entry.key, &entry.value);
+                    }
+                }
+            }
+        };
+
+        let helpers = thread::available_parallelism().map_or(1, |n| n.get());
+        if helpers <= 1 || sync::SINGLE_THREADED {
+            return work();
+        }
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..helpers).map(|_| s.spawn(work)).collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                .reduce(&combine)
+                .unwrap()
+        })
+    }
+
+    // Copies every entry out at a single point in time, e.g. for an audit
+    // export. Bucket locks are taken as for `clone`, so lookups carry on
+    // and writers wait only for the copy.
+    pub fn snapshot(&self) -> HashMap<K, V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.with_all_buckets(|_, buckets| {
+            buckets
+                .iter()
+                .flat_map(|b| b.entries())
+                .map(|e| (e.key.clone(), e.value.clone()))
+                .collect()
+        })
+    }
+
+    // Copies every entry out in key order, e.g. for a deterministic dump.
+    // Bucket locks are taken as for `snapshot`, so the result is a single
+    // point in time while lookups carry on; sorting happens after the locks
+    // are released.
+    pub fn to_sorted_vec(&self) -> Vec<(K, V)>
+    where
+        K: Ord + Clone,
+        V: Clone,
+    {
+        let mut entries: Vec<(K, V)> = self.with_all_buckets(|_, buckets| {
+            buckets
+                .iter()
+                .flat_map(|b| b.entries())
+                .map(|e| (e.key.clone(), e.value.clone()))
+                .collect()
+        });
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    // Removes every entry and returns them in key order. The table keeps its
+    // capacity.
+    pub fn drain_sorted(&self) -> Vec<(K, V)>
+    where
+        K: Ord,
+    {
+        let mut entries: Vec<(K, V)> = {
+            let mut table = self.table.write().unwrap();
+            // Inserts and removals hold a read lock on the table, so the
+            // count cannot change underneath the write lock.
+            self.len.clear();
+            table.drain().into_iter().map(|e| (e.key, e.value)).collect()
+        };
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    // Runs `f` on the table while every bucket is read-locked, taken in
+    // index order as `write_pair` does, so `f` sees a single point in time.
+    // Lookups carry on meanwhile; writers wait until `f` returns.
+    fn with_all_buckets<R>(
+        &self,
+        f: impl FnOnce(&Table<K, V>, &[RwLockReadGuard<'_, Bucket<K, V>>]) -> R,
+    ) -> R {
+        let table = self.table.read().unwrap();
+        let buckets: Vec<_> = (0..table.buckets.len()).map(|i| table.read(i)).collect();
+        f(&table, &buckets)
+    }
+
+    // Searches breadth-first for a chain of displacements that frees a slot
+    // in bucket `a` or `b`, then performs the moves from the far end back
+    // towards the start. Returns false if no path exists within the search
+    // bounds, meaning the table should grow. A move that finds its buckets
+    // changed by another thread stops early and returns true so the caller
+    // simply retries.
+    fn make_room(&self, table: &Table<K, V>, a: usize, b: usize) -> bool {
+        // Each node is (bucket, parent node, slot in the parent moved here).
+        let mut nodes: Vec<(usize, usize, usize)> = Vec::new();
+        let mut frontier = VecDeque::new();
+        for start in [a, b] {
+            nodes.push((start, usize::MAX, 0));
+            frontier.push_back((nodes.len() - 1, 0));
+        }
+
+        let mut target = None;
+        while let Some((node, depth)) = frontier.pop_front() {
+            let index = nodes[node].0;
+            let bucket = table.read(index);
+            if let Some(free) = bucket.free_slot() {
+                target = Some((node, free));
+                break;
+            }
+            if depth >= MAX_PATH_LEN || nodes.len() >= MAX_SEARCHED_BUCKETS {
+                continue;
+            }
+            for (slot, entry) in bucket.slots.iter().enumerate() {
+                let Some(entry) = entry else { continue };
+                let alt = table.alternate(index, entry.hash);
+                if alt != index {
+                    nodes.push((alt, node, slot));
+                    frontier.push_back((nodes.len() - 1, depth + 1));
+                }
+            }
+        }
+
+        let Some((mut node, mut free_slot)) = target else {
+            return false;
+        };
+        while nodes[node].1 != usize::MAX {
+            let (dst, parent, src_slot) = nodes[node];
+            let src = nodes[parent].0;
+            let mut pair = table.write_pair(src, dst);
+            let (src_bucket, dst_bucket) = pair.buckets();
+            let Some(dst_bucket) = dst_bucket else {
+                return true;
+            };
+            let movable = dst_bucket.slots[free_slot].is_none()
+                && matches!(&src_bucket.slots[src_slot], Some(e) if table.alternate(src, e.hash) == dst);
+            if !movable {
+                return true;
+            }
+            dst_bucket.slots[free_slot] = src_bucket.slots[src_slot].take();
+            node = parent;
+            free_slot = src_slot;
+        }
+        true
+    }
+
+    fn grow(&self, observed_mask: usize) {
+        let mut table = self.table.write().unwrap();
+        if table.mask != observed_mask {
+            // Another thread already grew the table.
+            return;
+        }
+        let entries = table.drain();
+        *table = Table::build(entries, (table.mask + 1) * 2);
+    }
+}
+
+// A borrowed key handed out by `CuckooHashMap::entry_ref`. Each method
+// looks the key up with read locks first and only allocates the owned key
+// on a miss, inserting under the bucket write locks as
+// `get_or_insert_with` does. The hash is computed once for both steps.
+pub struct EntryRef<'a, 'q, K, Q: ?Sized, V, S> {
+    map: &'a CuckooHashMap<K, V, S>,
+    key: &'q Q,
+}
+
+impl<'q, K, Q, V, S> EntryRef<'_, 'q, K, Q, V, S>
+where
+    K: Hash + Eq + Borrow<Q>,
+    Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &'q Q {
+        self.key
+    }
+
+    // Runs `f` on the value in place if the key is present.
+    pub fn and_modify(self, f: impl FnOnce(&mut V)) -> Self {
+        self.map.with_mut(self.key, f);
+        self
+    }
+
+    pub fn or_insert(self, value: V) -> V
+    where
+        V: Clone,
+    {
+        self.or_insert_with(|| value)
+    }
+
+    pub fn or_insert_with(self, make: impl FnOnce() -> V) -> V
+    where
+        V: Clone,
+    {
+        let hash = self.map.hasher.hash_one(self.key);
+        if let Some(value) = self.map.with_hashed(hash, self.key, V::clone) {
+            return value;
+        }
+        let key = HashedKey {
+            key: self.key.to_owned(),
+            hash,
+        };
+        self.map.get_or_insert_hashed(key, make)
+    }
+
+    pub fn or_default(self) -> V
+    where
+        V: Default + Clone,
+    {
+        self.or_insert_with(V::default)
+    }
+}
+
+// Shared-value mode: values are stored as `Arc<V>`, so a read hands out a
+// reference count instead of cloning `V`, and no lock is held while the
+// caller looks at it. `V` need not be `Clone`.
+pub type SharedCuckooHashMap<K, V, S = RandomState> = CuckooHashMap<K, Arc<V>, S>;
+
+impl<K: Hash + Eq, V, S: BuildHasher> CuckooHashMap<K, Arc<V>, S> {
+    pub fn get_shared<Q>(&self, key: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.with(key, Arc::clone)
+    }
+
+    pub fn insert_shared(&self, key: K, value: V) -> Option<Arc<V>> {
+        self.insert(key, Arc::new(value))
+    }
+
+    // Replaces the value for `key` with `f(&value)`, under the bucket locks
+    // as `update` does. Readers that already hold the old `Arc` keep seeing
+    // the old value.
+    pub fn update_shared<Q>(&self, key: &Q, f: impl FnOnce(&V) -> V) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.update(key, |value| *value = Arc::new(f(value)))
+    }
+}
+
+// Walks the table one bucket at a time, copying out `project` of each entry
+// in the bucket before moving on, so no lock is held between calls to
+// `next`.
+struct Entries<'a, K, V, S, T> {
+    map: &'a CuckooHashMap<K, V, S>,
+    next_bucket: usize,
+    buffer: std::vec::IntoIter<T>,
+    project: fn(&K, &V) -> T,
+}
+
+impl<'a, K, V, S, T> Entries<'a, K, V, S, T> {
+    fn new(map: &'a CuckooHashMap<K, V, S>, project: fn(&K, &V) -> T) -> Self {
+        Entries {
+            map,
+            next_bucket: 0,
+            buffer: Vec::new().into_iter(),
+            project,
+        }
+    }
+}
+
+impl<K: Eq, V, S, T> Iterator for Entries<'_, K, V, S, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some(item) = self.buffer.next() {
+                return Some(item);
+            }
+            let table = self.map.table.read().unwrap();
+            if self.next_bucket >= table.buckets.len() {
+                return None;
+            }
+            let bucket = table.read(self.next_bucket);
+            let items: Vec<T> = bucket.entries().map(|e| (self.project)(&e.key, &e.value)).collect();
+            self.buffer = items.into_iter();
+            self.next_bucket += 1;
+        }
+    }
+}
+
+struct Drain<'a, K: Hash + Eq, V, S: BuildHasher> {
+    map: &'a CuckooHashMap<K, V, S>,
+    next_bucket: usize,
+    buffer: std::vec::IntoIter<Entry<K, V>>,
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Iterator for Drain<'_, K, V, S> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        loop {
+            if let Some(entry) = self.buffer.next() {
+                return Some((entry.key, entry.value));
+            }
+            let table = self.map.table.read().unwrap();
+            if self.next_bucket >= table.buckets.len() {
+                return None;
+            }
+            let mut bucket = table.buckets[self.next_bucket].write().unwrap();
+            let entries: Vec<Entry<K, V>> = bucket.drain().collect();
+            for entry in &entries {
+                self.map.len.sub(entry.hash, 1);
+            }
+            self.buffer = entries.into_iter();
+            self.next_bucket += 1;
+        }
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> Drop for Drain<'_, K, V, S> {
+    fn drop(&mut self) {
+        // Already hashed when they were taken out.
+        for entry in self.buffer.by_ref() {
+            let key = HashedKey {
+                key: entry.key,
+                hash: entry.hash,
+            };
+            self.map.put(key, entry.value, false);
+        }
+    }
+}
+
+// Builds the table directly from the entries, without taking any lock or
+// going through `insert`, sized for the map up front.
+impl<K: Hash + Eq, V, H> From<HashMap<K, V, H>> for CuckooHashMap<K, V> {
+    fn from(map: HashMap<K, V, H>) -> Self {
+        let hasher = RandomState::new();
+        let num_buckets = map.len().div_ceil(SLOTS_PER_BUCKET);
+        let len = LenCounter::new();
+        let entries: Vec<Entry<K, V>> = map
+            .into_iter()
+            .map(|(key, value)| {
+                let hash = hasher.hash_one(&key);
+                len.add(hash, 1);
+                Entry { hash, key, value }
+            })
+            .collect();
+        CuckooHashMap {
+            table: RwLock::new(Table::build(entries, num_buckets)),
+            len,
+            hasher,
+            on_drop_item: None,
+            eviction_listener: None,
+            panic_policy: PanicPolicy::default(),
+            id: StructureId::next(),
+        }
+    }
+}
+
+// A deep copy of the map at a single point in time, with the same layout
+// and hasher. The drop callback is not copied.
+impl<K, V, S> Clone for CuckooHashMap<K, V, S>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+    S: BuildHasher + Clone,
+{
+    fn clone(&self) -> Self {
+        let (table, len) = self.with_all_buckets(|table, buckets| {
+            let copy: Box<[RwLock<Bucket<K, V>>]> = buckets
+                .iter()
+                .map(|bucket| RwLock::new(Bucket::clone(bucket)))
+                .collect();
+            let len = LenCounter::new();
+            for entry in buckets.iter().flat_map(|b| b.entries()) {
+                len.add(entry.hash, 1);
+            }
+            let table = Table {
+                buckets: copy,
+                mask: table.mask,
+            };
+            (table, len)
+        });
+        CuckooHashMap {
+            table: RwLock::new(table),
+            len,
+            hasher: self.hasher.clone(),
+            on_drop_item: None,
+            eviction_listener: None,
+            panic_policy: self.panic_policy,
+            id: StructureId::next(),
+        }
+    }
+}
+
+// Compares contents, regardless of capacity or hasher. Both maps are
+// read-locked as a whole for the comparison, the one at the lower address
+// first, so two threads comparing the same maps cannot deadlock.
+impl<K, V, S> PartialEq for CuckooHashMap<K, V, S>
+where
+    K: Hash + Eq,
+    V: PartialEq,
+    S: BuildHasher,
+{
+    fn eq(&self, other: &Self) -> bool {
+        if std::ptr::eq(self, other) {
+            return true;
+        }
+        let compare = |ours: &[RwLockReadGuard<'_, Bucket<K, V>>],
+                       table: &Table<K, V>,
+                       theirs: &[RwLockReadGuard<'_, Bucket<K, V>>]| {
+            let count = |buckets: &[RwLockReadGuard<'_, Bucket<K, V>>]| {
+                buckets.iter().flat_map(|b| b.entries()).count()
+            };
+            count(ours) == count(theirs)
+                && ours.iter().flat_map(|b| b.entries()).all(|entry| {
+                    let hash = other.hasher.hash_one(&entry.key);
+                    let a = table.primary(hash);
+                    let b = table.alternate(a, hash);
+                    [a, b].into_iter().any(|index| {
+                        theirs[index]
+                            .find(&entry.key)
+                            .is_some_and(|e| e.value == entry.value)
+                    })
+                })
+        };
+        if (self as *const Self) < (other as *const Self) {
+            self.with_all_buckets(|_, ours| {
+                other.with_all_buckets(|table, theirs| compare(ours, table, theirs))
+            })
+        } else {
+            other.with_all_buckets(|table, theirs| {
+                self.with_all_buckets(|_, ours| compare(ours, table, theirs))
+            })
+        }
+    }
+}
+
+impl<K: Hash + Eq, V: Eq, S: BuildHasher> Eq for CuckooHashMap<K, V, S> {}
+
+// Later entries for a key replace earlier ones, as with `insert`.
+impl<K: Hash + Eq, V, S: BuildHasher + Default> FromIterator<(K, V)> for CuckooHashMap<K, V, S> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let iter = iter.into_iter();
+        let mut map = Self::with_capacity_and_hasher(iter.size_hint().0, S::default());
+        map.extend(iter);
+        map
+    }
+}
+
+// Exclusive access, so entries go straight into the table without taking
+// any bucket lock. Existing values are replaced, as with `insert`.
+impl<K: Hash + Eq, V, S: BuildHasher> Extend<(K, V)> for CuckooHashMap<K, V, S> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let table = self.table.get_mut().unwrap();
+        for (key, value) in iter {
+            let hash = self.hasher.hash_one(&key);
+            match table.put_exclusive(Entry { hash, key, value }, self.len.get()) {
+                Some((key, old)) => {
+                    if let Some(listener) = &self.eviction_listener {
+                        listener(&key, &old, EvictionReason::Replaced);
+                    }
+                }
+                None => self.len.add(hash, 1),
+            }
+        }
+    }
+}
+
+// Hands every entry to the caller; `on_drop_item` is not called for them.
+impl<K: Eq, V, S> IntoIterator for CuckooHashMap<K, V, S> {
+    type Item = (K, V);
+    type IntoIter = std::vec::IntoIter<(K, V)>;
+
+    fn into_iter(mut self) -> Self::IntoIter {
+        let entries = self.table.get_mut().unwrap().drain();
+        self.len.clear();
+        let pairs: Vec<(K, V)> = entries.into_iter().map(|e| (e.key, e.value)).collect();
+        pairs.into_iter()
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher + Default> Default for CuckooHashMap<K, V, S> {
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, V, S> Drop for CuckooHashMap<K, V, S> {
+    fn drop(&mut self) {
+        let Some(on_drop_item) = &self.on_drop_item else {
+            return;
+        };
+        let table = self.table.get_mut().unwrap_or_else(|e| e.into_inner());
+        for bucket in table.buckets.iter_mut() {
+            let bucket = bucket.get_mut().unwrap_or_else(|e| e.into_inner());
+            for entry in bucket.drain() {
+                on_drop_item(entry.key, entry.value);
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Send + Sync, V: Send + Sync, S: BuildHasher + Send + Sync> Diagnostics for CuckooHashMap<K, V, S> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "CuckooHashMap"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::Hasher;
+    use std::panic::{self, AssertUnwindSafe};
+
+    // Distinct keys that hash identically under every seed.
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    struct Colliding(u32);
+
+    impl Hash for Colliding {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            0u32.hash(state);
+        }
+    }
+
+    fn panic_in(map: &CuckooHashMap<u32, u32>, key: u32) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            map.with_mut(&key, |value| {
+                *value += 1;
+                panic!("closure failed");
+            })
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn keep_leaves_changes_after_panic() {
+        let map = CuckooHashMap::new();
+        map.insert(1, 10);
+        panic_in(&map, 1);
+        // A poisoned bucket lock would make these panic.
+        assert_eq!(map.get(&1), Some(11));
+        assert_eq!(map.insert(1, 20), Some(11));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn discard_removes_entry_after_panic() {
+        let map = CuckooHashMap::new().with_panic_policy(PanicPolicy::Discard);
+        map.insert(1, 10);
+        map.insert(2, 20);
+        panic_in(&map, 1);
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.get(&2), Some(20));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.insert(1, 30), None);
+    }
+
+    #[test]
+    fn collect_and_extend_replace_duplicates() {
+        let mut map: CuckooHashMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+        assert_eq!(map.len(), 1000);
+        map.extend((500..1500).map(|i| (i, i + 1)));
+        assert_eq!(map.len(), 1500);
+        assert_eq!(map.get(&499), Some(499));
+        assert_eq!(map.get(&500), Some(501));
+        assert_eq!(map.get(&1499), Some(1500));
+    }
+
+    #[test]
+    fn clone_copies_every_entry() {
+        let map: CuckooHashMap<u32, String> = (0..1000).map(|i| (i, i.to_string())).collect();
+        let copy = map.clone();
+        map.insert(0, "changed".to_string());
+        map.remove(&1);
+        assert_eq!(copy.len(), 1000);
+        assert!((0..1000).all(|i| copy.get(&i) == Some(i.to_string())));
+    }
+
+    #[test]
+    fn eq_compares_contents() {
+        let map: CuckooHashMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+        let other = CuckooHashMap::with_capacity(10_000);
+        for i in (0..1000).rev() {
+            other.insert(i, i);
+        }
+        assert!(map == other);
+        assert!(map == map);
+        other.insert(0, 1);
+        assert!(map != other);
+        other.insert(0, 0);
+        other.insert(1000, 1000);
+        assert!(map != other);
+    }
+
+    #[test]
+    fn concurrent_adds_are_not_lost() {
+        let map = CuckooHashMap::new();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..1000u64 {
+                        map.add(&(i % 10), 1);
+                    }
+                });
+            }
+        });
+        assert!((0..10).all(|key| map.get(&key) == Some(400)));
+        assert_eq!(map.fetch_update(&0, |v| v * 2), 400);
+        assert_eq!(map.fetch_update(&10, |v| v + 5), 0);
+        assert_eq!(map.get(&0), Some(800));
+        assert_eq!(map.get(&10), Some(5));
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let map = CuckooHashMap::new();
+        map.insert("hits", 0);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        assert!(map.update(&"hits", |hits| *hits += 1));
+                    }
+                });
+            }
+        });
+        assert_eq!(map.get(&"hits"), Some(4000));
+        assert!(!map.update(&"misses", |misses| *misses += 1));
+        assert_eq!(map.get(&"misses"), None);
+    }
+
+    #[test]
+    fn multi_get_returns_results_in_key_order() {
+        let map: CuckooHashMap<u32, u32> = (0..100).map(|i| (i, i * 2)).collect();
+        let keys = [5, 200, 5, 99, 0];
+        assert_eq!(
+            map.multi_get(&keys),
+            [Some(10), None, Some(10), Some(198), Some(0)]
+        );
+        assert!(map.multi_get(&[]).is_empty());
+    }
+
+    #[test]
+    fn insert_batch_counts_new_keys() {
+        let map = CuckooHashMap::new();
+        map.insert(0, 0);
+        assert_eq!(map.insert_batch((0..1000).map(|i| (i, i + 1))), 999);
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.get(&0), Some(1));
+        assert_eq!(map.get(&999), Some(1000));
+    }
+
+    #[test]
+    fn snapshot_copies_every_entry() {
+        let map: CuckooHashMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+        let snapshot = map.snapshot();
+        map.insert(0, 1);
+        assert_eq!(snapshot, (0..1000).map(|i| (i, i)).collect());
+    }
+
+    #[test]
+    fn to_sorted_vec_shares_the_table_with_readers() {
+        let map: CuckooHashMap<u32, u32> = (0..1000).rev().map(|i| (i, i)).collect();
+        let held = std::sync::Barrier::new(2);
+        let done = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            // Holds a bucket and the table read-locked throughout the copy.
+            s.spawn(|| {
+                let _guard = map.get_ref(&7).unwrap();
+                held.wait();
+                done.wait();
+            });
+            held.wait();
+            let sorted = map.to_sorted_vec();
+            done.wait();
+            assert_eq!(sorted, (0..1000).map(|i| (i, i)).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn try_methods_return_would_block_under_a_writer() {
+        let map = CuckooHashMap::new();
+        assert_eq!(map.try_insert_nonblocking(1, 10), Ok(None));
+        assert_eq!(map.try_insert_nonblocking(1, 11), Ok(Some(10)));
+        assert_eq!(map.try_get(&1), Ok(Some(11)));
+        assert_eq!(map.try_get(&2), Ok(None));
+
+        let table = map.table.write().unwrap();
+        assert_eq!(map.try_get(&1), Err(WouldBlock { item: () }));
+        assert_eq!(map.try_insert_nonblocking(2, 20), Err(WouldBlock { item: (2, 20) }));
+        drop(table);
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn get_timeout_waits_for_a_short_writer() {
+        let map = CuckooHashMap::new();
+        map.insert(1, 10);
+        let locked = std::sync::Barrier::new(2);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let _table = map.table.write().unwrap();
+                locked.wait();
+                std::thread::sleep(Duration::from_millis(50));
+            });
+            locked.wait();
+            let timeout = Duration::from_millis(10);
+            let started = Instant::now();
+            assert_eq!(map.get_timeout(&1, timeout), Err(WouldBlock { item: () }));
+            assert!(started.elapsed() >= timeout);
+            assert_eq!(map.get_timeout(&1, Duration::from_secs(10)), Ok(Some(10)));
+        });
+        assert_eq!(map.get_timeout(&1, Duration::MAX), Ok(Some(10)));
+    }
+
+    #[test]
+    fn try_insert_keeps_the_first_value() {
+        let map = CuckooHashMap::new();
+        assert_eq!(map.try_insert("worker", 1), Ok(()));
+        assert_eq!(
+            map.try_insert("worker", 2),
+            Err(OccupiedError {
+                key: "worker",
+                value: 2,
+                existing: 1
+            })
+        );
+        assert_eq!(map.get(&"worker"), Some(1));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn remove_if_keeps_entry_after_panic() {
+        let map = CuckooHashMap::new().with_panic_policy(PanicPolicy::Discard);
+        map.insert(1, 10);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            map.remove_if(&1, |_| panic!("predicate failed"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(map.remove(&1), Some(10));
+    }
+
+    #[test]
+    fn colliding_keys_go_to_overflow() {
+        let map = CuckooHashMap::new();
+        for i in 0..100 {
+            assert_eq!(map.insert(Colliding(i), i), None);
+        }
+        assert_eq!(map.len(), 100);
+        assert!(map.capacity() <= 1024);
+        assert!((0..100).all(|i| map.get(&Colliding(i)) == Some(i)));
+        assert_eq!(map.insert(Colliding(50), 0), Some(50));
+        assert_eq!(map.remove(&Colliding(99)), Some(99));
+        assert_eq!(map.get(&Colliding(99)), None);
+        assert_eq!(map.len(), 99);
+
+        let copy = map.clone();
+        assert!(copy == map);
+        assert_eq!(copy.snapshot().len(), 99);
+
+        let batch = CuckooHashMap::new();
+        assert_eq!(batch.insert_batch((0..100).map(|i| (Colliding(i), i))), 100);
+        let mut extended = CuckooHashMap::new();
+        extended.extend((0..100).map(|i| (Colliding(i), i)));
+        let converted = CuckooHashMap::from(
+            (0..100).map(|i| (Colliding(i), i)).collect::<HashMap<_, _>>(),
+        );
+        for map in [batch, extended, converted] {
+            assert_eq!(map.len(), 100);
+            assert!((0..100).all(|i| map.get(&Colliding(i)) == Some(i)));
+        }
+    }
+
+    #[test]
+    fn get_or_insert_with_runs_make_once() {
+        let map = CuckooHashMap::new();
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    let value = map.get_or_insert_with(1, || {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        10
+                    });
+                    assert_eq!(value, 10);
+                });
+            }
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            map.get_or_insert_with(2, || panic!("make failed"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.get_or_insert_with(2, || 20), 20);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn len_tracks_concurrent_inserts_and_removes() {
+        let map = CuckooHashMap::new();
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let writers: Vec<_> = (0..4u32)
+                .map(|t| {
+                    let map = &map;
+                    s.spawn(move || {
+                        for i in 0..1000 {
+                            map.insert(t * 1000 + i, i);
+                        }
+                        for i in (0..1000).step_by(2) {
+                            map.remove(&(t * 1000 + i));
+                        }
+                    })
+                })
+                .collect();
+            s.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    assert!(map.len() <= 4000);
+                }
+            });
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(map.len(), 2000);
+        assert!(map.contains_key(&1) && !map.contains_key(&0));
+        map.drain_sorted();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn retain_and_clear() {
+        let map: CuckooHashMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+        map.retain(|&key, value| {
+            *value *= 2;
+            key % 2 == 0
+        });
+        assert_eq!(map.len(), 500);
+        assert_eq!(map.get(&2), Some(4));
+        assert_eq!(map.get(&3), None);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            map.retain(|&key, _| key != 500 || panic!("f failed"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(map.get(&500), Some(1000));
+        assert_eq!(map.len(), map.snapshot().len());
+
+        let capacity = map.capacity();
+        map.clear();
+        assert!(map.is_empty());
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.capacity(), capacity);
+        map.insert(1, 1);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn iter_and_for_each_visit_every_entry() {
+        let map: CuckooHashMap<u32, u32> = (0..1000).map(|i| (i, i * 2)).collect();
+        let mut seen: Vec<(u32, u32)> = map.iter().collect();
+        seen.sort_unstable();
+        assert_eq!(seen, (0..1000).map(|i| (i, i * 2)).collect::<Vec<_>>());
+
+        let mut sum = 0;
+        map.for_each(|_, value| sum += value);
+        assert_eq!(sum, (0..1000).map(|i| i * 2).sum::<u32>());
+        assert_eq!(CuckooHashMap::<u32, u32>::new().iter().count(), 0);
+
+        let mut keys: Vec<u32> = map.keys().collect();
+        keys.sort_unstable();
+        assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+        assert_eq!(map.values().sum::<u32>(), sum);
+    }
+
+    #[test]
+    fn iter_holds_no_lock_between_items() {
+        let map: CuckooHashMap<u32, u32> = (0..100).map(|i| (i, i)).collect();
+        let mut iter = map.iter();
+        iter.next();
+        // Would deadlock if the iterator kept a bucket or the table locked.
+        map.insert_batch((100..1000).map(|i| (i, i)));
+        assert!(iter.count() >= 99);
+    }
+
+    #[test]
+    fn drain_hands_over_every_entry_once() {
+        let map: CuckooHashMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+        let mut drained: Vec<(u32, u32)> = map.drain().take(10).collect();
+        // The entries taken but not yielded went back.
+        assert_eq!(map.len(), 990);
+        drained.extend(map.drain());
+        assert!(map.is_empty());
+        drained.sort_unstable();
+        assert_eq!(drained, (0..1000).map(|i| (i, i)).collect::<Vec<_>>());
+
+        let map: CuckooHashMap<u32, u32> = (0..100).map(|i| (i, i)).collect();
+        let mut owned: Vec<(u32, u32)> = map.into_iter().collect();
+        owned.sort_unstable();
+        assert_eq!(owned, (0..100).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn compute_inserts_updates_and_removes() {
+        let map = CuckooHashMap::new();
+        assert_eq!(map.compute(1, |value| value.map(|v| v + 1)), None);
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.compute(1, |value| Some(value.map_or(1, |v| v + 1))), None);
+        assert_eq!(map.compute(1, |value| Some(value.map_or(1, |v| v + 1))), Some(1));
+        assert_eq!(map.get(&1), Some(2));
+        assert_eq!(map.compute(1, |_| None), Some(2));
+        assert_eq!(map.get(&1), None);
+        assert!(map.is_empty());
+
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        map.compute(2, |value| Some(value.map_or(1, |v| v + 1)));
+                    }
+                });
+            }
+        });
+        assert_eq!(map.get(&2), Some(4000));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn concurrent_merges_combine_values() {
+        let map: CuckooHashMap<u32, Vec<u32>> = CuckooHashMap::new();
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        map.merge(i % 10, vec![t * 100 + i], |old, new| {
+                            old.iter().copied().chain(new).collect()
+                        });
+                    }
+                });
+            }
+        });
+        assert_eq!(map.len(), 10);
+        assert!((0..10).all(|key| map.get(&key).unwrap().len() == 40));
+        assert_eq!(map.merge(0, vec![], |old, _| old.clone()).map(|v| v.len()), Some(40));
+    }
+
+    #[test]
+    fn lookups_take_borrowed_keys() {
+        let map: CuckooHashMap<String, u32> = CuckooHashMap::new();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        assert_eq!(map.get("a"), Some(1));
+        assert!(map.contains_key("b") && !map.contains_key("c"));
+        assert!(map.update("a", |value| *value += 10));
+        assert_eq!(map.with("a", |value| *value), Some(11));
+        assert_eq!(map.compare_and_swap("b", &2, 3), Ok(()));
+        assert_eq!(map.remove_if("b", |&value| value == 2), None);
+        assert_eq!(map.remove("b"), Some(3));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn custom_hasher_is_used_throughout() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::BuildHasherDefault;
+
+        type Fixed = BuildHasherDefault<DefaultHasher>;
+        let map: CuckooHashMap<u32, u32, Fixed> = (0..1000).map(|i| (i, i)).collect();
+        let other = CuckooHashMap::with_hasher(Fixed::default());
+        other.insert_batch((0..1000).map(|i| (i, i)));
+        assert!(map == other);
+        assert_eq!(map.get(&999), Some(999));
+        let empty: CuckooHashMap<u32, u32, Fixed> = CuckooHashMap::default();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn prehashed_keys_are_hashed_once() {
+        use std::hash::DefaultHasher;
+
+        #[derive(Default)]
+        struct Counting(Arc<AtomicUsize>);
+        impl BuildHasher for Counting {
+            type Hasher = DefaultHasher;
+            fn build_hasher(&self) -> DefaultHasher {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                DefaultHasher::new()
+            }
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let map = CuckooHashMap::with_hasher(Counting(calls.clone()));
+        let key = map.hash_key("a".to_string());
+        assert_eq!(key.hash(), map.hasher().hash_one("a"));
+        calls.store(0, Ordering::Relaxed);
+
+        assert_eq!(map.insert_prehashed(key.clone(), 1), None);
+        assert_eq!(map.get_prehashed(&key), Some(1));
+        assert_eq!(map.remove_prehashed(&key), Some(1));
+        assert_eq!(calls.load(Ordering::Relaxed), 0);
+        // Plain lookups find what was inserted prehashed.
+        map.insert_prehashed(key.clone(), 2);
+        assert_eq!(map.get("a"), Some(2));
+        assert_eq!(key.into_key(), "a");
+    }
+
+    #[test]
+    fn shared_values_are_not_cloned() {
+        // Deliberately not `Clone`.
+        struct Blob(Vec<u8>);
+
+        let map = SharedCuckooHashMap::new();
+        map.insert_shared("a", Blob(vec![1; 1024]));
+        let first = map.get_shared("a").unwrap();
+        let second = map.get_shared("a").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        assert!(map.update_shared("a", |blob| Blob(blob.0[..2].to_vec())));
+        assert!(!map.update_shared("b", |blob| Blob(blob.0.clone())));
+        assert_eq!(first.0.len(), 1024);
+        assert_eq!(map.get_shared("a").unwrap().0, [1, 1]);
+        assert_eq!(Arc::strong_count(&first), 2);
+    }
+
+    #[test]
+    fn entry_ref_allocates_only_on_insert() {
+        // Counts how often the map makes an owned key from a borrowed one.
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+        #[derive(PartialEq, Eq, Hash, Debug)]
+        struct Key(u32);
+        impl Clone for Key {
+            fn clone(&self) -> Self {
+                CLONES.fetch_add(1, Ordering::Relaxed);
+                Key(self.0)
+            }
+        }
+
+        let map = CuckooHashMap::new();
+        assert_eq!(map.entry_ref(&Key(1)).or_insert(10), 10);
+        assert_eq!(CLONES.load(Ordering::Relaxed), 1);
+        assert_eq!(map.entry_ref(&Key(1)).or_insert(20), 10);
+        assert_eq!(map.entry_ref(&Key(1)).and_modify(|v| *v += 1).or_default(), 11);
+        assert_eq!(CLONES.load(Ordering::Relaxed), 1);
+        assert_eq!(map.entry_ref(&Key(2)).and_modify(|v| *v += 1).or_default(), 0);
+        assert_eq!(CLONES.load(Ordering::Relaxed), 2);
+
+        let names: CuckooHashMap<String, u32> = CuckooHashMap::new();
+        assert_eq!(names.entry_ref("a").or_insert_with(|| 1), 1);
+        assert_eq!(names.get("a"), Some(1));
+    }
+
+    #[test]
+    fn eviction_listener_sees_removals_and_replacements() {
+        use std::sync::{Arc, Mutex};
+        use EvictionReason::{Explicit, Replaced};
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let mut map = CuckooHashMap::new()
+            .with_eviction_listener(move |&k: &u32, &v: &u32, reason| log.lock().unwrap().push((k, v, reason)));
+        let take = || std::mem::take(&mut *seen.lock().unwrap());
+
+        map.insert(1, 10);
+        map.insert(1, 11);
+        map.merge(1, 1, |a, b| a + b);
+        map.insert_batch([(1, 13), (2, 20)]);
+        map.extend([(2, 21)]);
+        assert_eq!(take(), [(1, 10, Replaced), (1, 11, Replaced), (1, 12, Replaced), (2, 20, Replaced)]);
+
+        map.remove(&1);
+        assert_eq!(map.remove_if(&2, |&v| v > 100), None);
+        map.compute(2, |_| None);
+        assert_eq!(take(), [(1, 13, Explicit), (2, 21, Explicit)]);
+
+        map.insert_batch((0..10).map(|i| (i, i)));
+        map.retain(|&k, _| k >= 5);
+        map.update(&5, |v| *v += 1);
+        let mut removed = take();
+        removed.sort_by_key(|&(k, _, _)| k);
+        assert_eq!(removed, (0..5).map(|i| (i, i, Explicit)).collect::<Vec<_>>());
+        map.clear();
+        let mut cleared = take();
+        cleared.sort_by_key(|&(k, _, _)| k);
+        assert_eq!(cleared[0], (5, 6, Explicit));
+        assert_eq!(cleared.len(), 5);
+    }
+
+    #[test]
+    fn get_ref_reads_in_place() {
+        let map: CuckooHashMap<u32, Vec<u32>> = (0..100).map(|i| (i, vec![i; 1000])).collect();
+        {
+            let value = map.get_ref(&7).unwrap();
+            assert_eq!(value.len(), 1000);
+            // Lookups of other keys carry on while the guard is held.
+            assert_eq!(map.get(&8).map(|v| v[0]), Some(8));
+        }
+        assert!(map.get_ref(&100).is_none());
+        map.insert(7, vec![]);
+        assert!(map.get_ref(&7).unwrap().is_empty());
+
+        let first = MapReadGuard::map(map.get_ref(&8).unwrap(), |v| &v[0]);
+        assert_eq!(*first, 8);
+        let tail = MapReadGuard::map(map.get_ref(&9).unwrap(), |v| &v[998..]);
+        assert_eq!(*tail, [9, 9]);
+    }
+
+    #[test]
+    fn fold_snapshot_aggregates_every_entry() {
+        let map: CuckooHashMap<u64, u64> = (0..10_000).map(|i| (i, i)).collect();
+        let expected = (0..10_000).sum::<u64>();
+        assert_eq!(map.fold_snapshot(0, |acc, _, &v| acc + v), expected);
+        let (count, sum) = map.par_fold_snapshot(
+            || (0, 0),
+            |(count, sum), _, &v| (count + 1, sum + v),
+            |a, b| (a.0 + b.0, a.1 + b.1),
+        );
+        assert_eq!((count, sum), (10_000, expected));
+    }
+
+    #[test]
+    fn reserve_grows_once_and_keeps_entries() {
+        let map: CuckooHashMap<u32, u32> = (0..100).map(|i| (i, i)).collect();
+        map.reserve(10_000);
+        let capacity = map.capacity();
+        assert!(capacity >= 10_100);
+        assert!((0..100).all(|i| map.get(&i) == Some(i)));
+        map.reserve(10);
+        assert_eq!(map.capacity(), capacity);
+        let with_capacity: CuckooHashMap<u32, u32> = CuckooHashMap::with_capacity(1000);
+        assert!(with_capacity.capacity() >= 1000);
+    }
+
+    #[test]
+    fn shrink_releases_capacity_after_churn() {
+        let map: CuckooHashMap<u32, u32> = (0..10_000).map(|i| (i, i)).collect();
+        let full = map.capacity();
+        map.retain(|&key, _| key < 100);
+        map.shrink_to(1000);
+        let shrunk = map.capacity();
+        assert!(shrunk >= 1000 && shrunk < full);
+        map.shrink_to_fit();
+        assert!(map.capacity() < shrunk);
+        assert!((0..100).all(|i| map.get(&i) == Some(i)));
+        assert_eq!(map.len(), 100);
+        map.shrink_to(full * 2);
+        assert!(map.capacity() < shrunk);
+    }
+
+    #[test]
+    fn rename_is_never_observed_halfway() {
+        let map: CuckooHashMap<u32, u32> = (0..1000).map(|i| (i, i)).collect();
+        assert_eq!(map.rename(&5000, 5001), Err(RenameError::NotFound));
+        assert_eq!(map.rename(&1, 2), Err(RenameError::Occupied));
+        assert_eq!(map.rename(&1, 1001), Ok(()));
+        assert_eq!((map.get(&1), map.get(&1001)), (None, Some(1)));
+        assert_eq!(map.len(), 1000);
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    map.rename(&1001, 1).unwrap();
+                    map.rename(&1, 1001).unwrap();
+                }
+                done.store(true, Ordering::Relaxed);
+            });
+            while !done.load(Ordering::Relaxed) {
+                let found = map.multi_get(&[1, 1001]);
+                assert_eq!(found.iter().flatten().count(), 1);
+            }
+        });
+        assert_eq!(map.get(&1001), Some(1));
+    }
+}
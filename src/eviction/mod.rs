@@ -0,0 +1,262 @@
+// Why a structure let go of an element, and which element a bounded one
+// lets go of next.
+//
+// Structures that drop elements on their own (bounded caches, mailboxes that
+// discard messages when full) accept an eviction listener through
+// `with_eviction_listener`. The listener receives each element together with
+// an `EvictionReason`, so bookkeeping kept outside the structure (reference
+// counts, metrics, pending acknowledgements) can follow every removal.
+// Listeners are called after the structure's locks have been released.
+//
+// Bounded caches pick their victims through an `EvictionPolicy`, set with
+// `with_eviction_policy`: `Lru` (the default), `Fifo` or `Random`, or any
+// other implementation. Sharded structures keep one policy per shard.
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EvictionReason {
+    // Made room for newer elements: a size bound was reached or the
+    // structure was asked to shed load.
+    Capacity,
+    // Outlived its time-to-live.
+    Expired,
+    // Removed by an explicit call such as `invalidate`.
+    Explicit,
+    // Overwritten by a newer value for the same key.
+    Replaced,
+}
+
+// Tracks the keys a bounded structure holds and chooses which one to evict.
+// The structure calls it under the lock guarding those keys, so
+// implementations need no synchronization of their own, and must not block.
+pub trait EvictionPolicy<K> {
+    // `key` was stored; it is not tracked yet.
+    fn on_insert(&mut self, key: &K);
+
+    // A stored `key` was read.
+    fn on_access(&mut self, key: &K);
+
+    // `key` was removed for any reason other than `evict` choosing it.
+    fn on_remove(&mut self, key: &K);
+
+    // Chooses the next key to evict and stops tracking it. None if no key
+    // is tracked.
+    fn evict(&mut self) -> Option<K>;
+}
+
+// Keys in the order of a per-key tick, oldest first.
+#[derive(Debug, Clone)]
+struct Ordered<K> {
+    order: BTreeMap<u64, K>,
+    ticks: HashMap<K, u64>,
+    next_tick: u64,
+}
+
+impl<K: Hash + Eq + Clone> Ordered<K> {
+    fn new() -> Self {
+        Ordered {
+            order: BTreeMap::new(),
+            ticks: HashMap::new(),
+            next_tick: 0,
+        }
+    }
+
+    fn push(&mut self, key: &K) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        if let Some(old) = self.ticks.insert(key.clone(), tick) {
+            self.order.remove(&old);
+        }
+        self.order.insert(tick, key.clone());
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<K> {
+        let (_, key) = self.order.pop_first()?;
+        self.ticks.remove(&key);
+        Some(key)
+    }
+}
+
+// Evicts the least recently stored or read key.
+#[derive(Debug, Clone)]
+pub struct Lru<K>(Ordered<K>);
+
+impl<K: Hash + Eq + Clone> Lru<K> {
+    pub fn new() -> Self {
+        Lru(Ordered::new())
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Lru<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone> EvictionPolicy<K> for Lru<K> {
+    fn on_insert(&mut self, key: &K) {
+        self.0.push(key);
+    }
+
+    fn on_access(&mut self, key: &K) {
+        if self.0.ticks.contains_key(key) {
+            self.0.push(key);
+        }
+    }
+
+    fn on_remove(&mut self, key: &K) {
+        self.0.remove(key);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.0.pop_oldest()
+    }
+}
+
+// Evicts the key stored longest ago, however often it is read.
+#[derive(Debug, Clone)]
+pub struct Fifo<K>(Ordered<K>);
+
+impl<K: Hash + Eq + Clone> Fifo<K> {
+    pub fn new() -> Self {
+        Fifo(Ordered::new())
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Fifo<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone> EvictionPolicy<K> for Fifo<K> {
+    fn on_insert(&mut self, key: &K) {
+        self.0.push(key);
+    }
+
+    fn on_access(&mut self, _key: &K) {}
+
+    fn on_remove(&mut self, key: &K) {
+        self.0.remove(key);
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        self.0.pop_oldest()
+    }
+}
+
+// Evicts a uniformly random key. Cheapest to maintain, as reads change
+// nothing, and immune to scans flushing the whole cache at once.
+#[derive(Debug, Clone)]
+pub struct Random<K> {
+    keys: Vec<K>,
+    index: HashMap<K, usize>,
+    // xorshift state, seeded from `RandomState`.
+    state: u64,
+}
+
+impl<K: Hash + Eq + Clone> Random<K> {
+    pub fn new() -> Self {
+        Random {
+            keys: Vec::new(),
+            index: HashMap::new(),
+            state: RandomState::new().hash_one(0u8) | 1,
+        }
+    }
+
+    fn next_index(&mut self) -> usize {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        (x % self.keys.len() as u64) as usize
+    }
+}
+
+impl<K: Hash + Eq + Clone> Default for Random<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone> EvictionPolicy<K> for Random<K> {
+    fn on_insert(&mut self, key: &K) {
+        if !self.index.contains_key(key) {
+            self.index.insert(key.clone(), self.keys.len());
+            self.keys.push(key.clone());
+        }
+    }
+
+    fn on_access(&mut self, _key: &K) {}
+
+    fn on_remove(&mut self, key: &K) {
+        let Some(i) = self.index.remove(key) else {
+            return;
+        };
+        self.keys.swap_remove(i);
+        if let Some(moved) = self.keys.get(i) {
+            self.index.insert(moved.clone(), i);
+        }
+    }
+
+    fn evict(&mut self) -> Option<K> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let i = self.next_index();
+        let key = self.keys[i].clone();
+        self.on_remove(&key);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(policy: &mut impl EvictionPolicy<u32>) -> Vec<u32> {
+        std::iter::from_fn(|| policy.evict()).collect()
+    }
+
+    #[test]
+    fn lru_and_fifo_differ_on_reads() {
+        let mut lru = Lru::new();
+        let mut fifo = Fifo::new();
+        for key in 0..4 {
+            lru.on_insert(&key);
+            fifo.on_insert(&key);
+        }
+        lru.on_access(&0);
+        fifo.on_access(&0);
+        lru.on_remove(&2);
+        fifo.on_remove(&2);
+        assert_eq!(drain(&mut lru), [1, 3, 0]);
+        assert_eq!(drain(&mut fifo), [0, 1, 3]);
+        // Reading an untracked key does not start tracking it.
+        lru.on_access(&7);
+        assert_eq!(lru.evict(), None);
+    }
+
+    #[test]
+    fn random_evicts_every_key_once() {
+        let mut random = Random::new();
+        for key in 0..100 {
+            random.on_insert(&key);
+        }
+        random.on_insert(&5);
+        random.on_remove(&50);
+        let mut evicted = drain(&mut random);
+        evicted.sort_unstable();
+        let expected: Vec<u32> = (0..100).filter(|&k| k != 50).collect();
+        assert_eq!(evicted, expected);
+    }
+}
@@ -0,0 +1,690 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::budget::Shed;
+use crate::eviction::{EvictionPolicy, EvictionReason, Lru};
+use crate::preload::{self, Progress};
+use crate::registry::{Diagnostics, StructureId};
+use crate::shard;
+use crate::sync::Mutex;
+use crate::unwind;
+
+const DEFAULT_SHARDS: usize = 16;
+
+type DropItem<K, V> = Box<dyn Fn(K, V) + Send + Sync>;
+type EvictionListener<K, V> = Box<dyn Fn(K, V, EvictionReason) + Send + Sync>;
+
+enum SlotState<V> {
+    Pending(Vec<Waker>),
+    Ready(V),
+    // The computing caller was dropped before finishing.
+    Abandoned,
+}
+
+// Result of one in-flight computation, shared by every caller waiting on it.
+struct Slot<V> {
+    state: Mutex<SlotState<V>>,
+}
+
+impl<V> Slot<V> {
+    fn new() -> Self {
+        Slot {
+            state: Mutex::new(SlotState::Pending(Vec::new())),
+        }
+    }
+
+    fn finish(&self, state: SlotState<V>) {
+        let previous = std::mem::replace(&mut *self.state.lock().unwrap(), state);
+        if let SlotState::Pending(wakers) = previous {
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+}
+
+struct WaitSlot<V> {
+    slot: Arc<Slot<V>>,
+}
+
+impl<V: Clone> Future for WaitSlot<V> {
+    type Output = Option<V>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<V>> {
+        let mut state = self.slot.state.lock().unwrap();
+        match &mut *state {
+            SlotState::Ready(v) => Poll::Ready(Some(v.clone())),
+            SlotState::Abandoned => Poll::Ready(None),
+            SlotState::Pending(wakers) => {
+                if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                    wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+enum Entry<V> {
+    Pending(Arc<Slot<V>>),
+    Ready {
+        value: V,
+        stored: Instant,
+        // How long the value took to compute.
+        cost: Duration,
+        // Some caller is recomputing the value ahead of its expiry.
+        refreshing: bool,
+    },
+}
+
+enum Claim<K, V> {
+    Wait(Arc<Slot<V>>),
+    // Carries the expired value the computation replaces, if any.
+    Compute(Arc<Slot<V>>, Option<(K, V)>),
+    Refresh,
+}
+
+// Uniform in (0, 1]. A per-thread xorshift seeded from `RandomState` is all
+// the randomness early refresh needs.
+fn unit_random() -> f64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().hash_one(0u8) | 1);
+    }
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        ((x >> 11) + 1) as f64 / (1u64 << 53) as f64
+    })
+}
+
+struct Shard<K, V, P> {
+    entries: HashMap<K, Entry<V>>,
+    // Tracks the ready entries, and picks which one to evict.
+    policy: P,
+    ready: usize,
+}
+
+impl<K: Hash + Eq + Clone, V, P: EvictionPolicy<K>> Shard<K, V, P> {
+    fn new(policy: P) -> Self {
+        Shard {
+            entries: HashMap::new(),
+            policy,
+            ready: 0,
+        }
+    }
+
+    // Removes the entry for `key` and returns its completed value, if it
+    // had one.
+    fn remove_entry(&mut self, key: &K) -> Option<(K, V)> {
+        match self.entries.remove_entry(key)? {
+            (key, Entry::Ready { value, .. }) => {
+                self.policy.on_remove(&key);
+                self.ready -= 1;
+                Some((key, value))
+            }
+            (_, Entry::Pending(_)) => None,
+        }
+    }
+
+    // Stores a completed value, then evicts the values the policy picks into
+    // `evicted` until at most `max` remain.
+    fn store(
+        &mut self,
+        key: K,
+        value: V,
+        cost: Duration,
+        max: Option<usize>,
+        evicted: &mut Vec<(K, V)>,
+    ) {
+        self.policy.on_insert(&key);
+        self.ready += 1;
+        self.entries.insert(
+            key,
+            Entry::Ready {
+                value,
+                stored: Instant::now(),
+                cost,
+                refreshing: false,
+            },
+        );
+        if let Some(max) = max {
+            while self.ready > max {
+                let Some(entry) = self.evict() else {
+                    break;
+                };
+                evicted.push(entry);
+            }
+        }
+    }
+
+    // Removes the completed value the policy picks.
+    fn evict(&mut self) -> Option<(K, V)> {
+        let key = self.policy.evict()?;
+        match self.entries.remove_entry(&key) {
+            Some((key, Entry::Ready { value, .. })) => {
+                self.ready -= 1;
+                Some((key, value))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Memoizes async computations. Concurrent callers of `get_or_compute` for the
+// same key share a single computation: the first caller drives the future it
+// was given, the others wait for its result. Completed values are kept until
+// they expire (`with_ttl`) or, once there are more than
+// `with_max_entries`, are evicted as the eviction policy `P` picks: least
+// recently used first unless built `with_policy`.
+//
+// If the driving caller is dropped before the computation finishes, or its
+// future panics, the waiters wake up and one of them starts over with its
+// own future. No shard lock is held while the future runs, so a panic never
+// poisons the cache.
+pub struct FutureCache<K, V, P = Lru<K>> {
+    shards: Vec<Mutex<Shard<K, V, P>>>,
+    hasher: RandomState,
+    max_entries_per_shard: Option<usize>,
+    ttl: Option<Duration>,
+    early_refresh: Option<f64>,
+    on_drop_item: Option<DropItem<K, V>>,
+    eviction_listener: Option<EvictionListener<K, V>>,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> FutureCache<K, V> {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(num_shards: usize) -> Self {
+        Self::with_shards_and_policy(num_shards, Lru::new())
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> FutureCache<K, V, P> {
+    // Evicts the values `policy` picks, whether over `with_max_entries` or
+    // shed by a memory budget, e.g. `eviction::Fifo` or `eviction::Random`.
+    pub fn with_policy(policy: P) -> Self
+    where
+        P: Clone,
+    {
+        Self::with_shards_and_policy(DEFAULT_SHARDS, policy)
+    }
+
+    // Each shard gets its own copy of `policy`.
+    pub fn with_shards_and_policy(num_shards: usize, policy: P) -> Self
+    where
+        P: Clone,
+    {
+        FutureCache {
+            shards: (0..shard::shard_count(num_shards))
+                .map(|_| Mutex::new(Shard::new(policy.clone())))
+                .collect(),
+            hasher: RandomState::new(),
+            max_entries_per_shard: None,
+            ttl: None,
+            early_refresh: None,
+            on_drop_item: None,
+            eviction_listener: None,
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    // Bounds the number of completed values. The bound is split evenly
+    // across shards, so eviction never needs more than one shard lock.
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries_per_shard = Some(max_entries.div_ceil(self.shards.len()).max(1));
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    // Probabilistic early refresh (XFetch) for caches with a TTL. As a value
+    // nears expiry, `get_or_compute` recomputes it early with a probability
+    // that grows with how long the value took to compute, scaled by `beta`
+    // (1.0 is the usual choice; larger refreshes earlier). One caller
+    // refreshes while the others keep getting the current value, so expiry
+    // does not send every caller to the backend at once.
+    pub fn with_early_refresh(mut self, beta: f64) -> Self {
+        assert!(beta > 0.0, "beta must be positive");
+        self.early_refresh = Some(beta);
+        self
+    }
+
+    // Called with each completed value still cached when the cache is
+    // dropped, instead of dropping it silently.
+    pub fn with_on_drop_item(mut self, f: impl Fn(K, V) + Send + Sync + 'static) -> Self {
+        self.on_drop_item = Some(Box::new(f));
+        self
+    }
+
+    // Called with each completed value the cache lets go of while in use:
+    // evicted for capacity (`with_max_entries`, or shed by a memory budget),
+    // found expired, removed by `invalidate`, or replaced by an early
+    // refresh. Values still cached when the cache is dropped go to
+    // `with_on_drop_item` instead.
+    pub fn with_eviction_listener(
+        mut self,
+        listener: impl Fn(K, V, EvictionReason) + Send + Sync + 'static,
+    ) -> Self {
+        self.eviction_listener = Some(Box::new(listener));
+        self
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<Shard<K, V, P>> {
+        let index = shard::shard_index(self.hasher.hash_one(key), self.shards.len());
+        &self.shards[index]
+    }
+
+    // Listeners run without any shard lock held. A panicking listener does
+    // not stop the remaining notifications; the first panic is resumed once
+    // they have all been delivered.
+    fn notify(&self, evicted: impl IntoIterator<Item = (K, V)>, reason: EvictionReason) {
+        let Some(listener) = &self.eviction_listener else {
+            return;
+        };
+        let mut panicked = None;
+        for (key, value) in evicted {
+            if let Err(payload) = unwind::catch(|| listener(key, value, reason)) {
+                panicked.get_or_insert(payload);
+            }
+        }
+        if let Some(payload) = panicked {
+            unwind::resume(payload);
+        }
+    }
+
+    fn is_expired(&self, stored: Instant) -> bool {
+        self.ttl.is_some_and(|ttl| stored.elapsed() >= ttl)
+    }
+
+    fn should_refresh_early(&self, stored: Instant, cost: Duration) -> bool {
+        let (Some(ttl), Some(beta)) = (self.ttl, self.early_refresh) else {
+            return false;
+        };
+        let head_start = cost.as_secs_f64() * beta * -unit_random().ln();
+        stored.elapsed().as_secs_f64() + head_start >= ttl.as_secs_f64()
+    }
+
+    // Returns the completed value for `key` without waiting on an in-flight
+    // computation.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let value = match shard.entries.get(key)? {
+            Entry::Ready { value, stored, .. } if !self.is_expired(*stored) => value.clone(),
+            Entry::Ready { .. } => {
+                let expired = shard.remove_entry(key);
+                drop(shard);
+                self.notify(expired, EvictionReason::Expired);
+                return None;
+            }
+            Entry::Pending(_) => return None,
+        };
+        shard.policy.on_access(key);
+        Some(value)
+    }
+
+    pub async fn get_or_compute<F, Fut>(&self, key: K, compute: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let mut compute = Some(compute);
+        loop {
+            let claim = {
+                let mut shard = self.shard(&key).lock().unwrap();
+                match shard.entries.get_mut(&key) {
+                    Some(Entry::Ready {
+                        value,
+                        stored,
+                        cost,
+                        refreshing,
+                        ..
+                    }) if !self.is_expired(*stored) => {
+                        let value = value.clone();
+                        let refresh = !*refreshing && self.should_refresh_early(*stored, *cost);
+                        *refreshing |= refresh;
+                        shard.policy.on_access(&key);
+                        if !refresh {
+                            return value;
+                        }
+                        Claim::Refresh
+                    }
+                    Some(Entry::Pending(slot)) => Claim::Wait(Arc::clone(slot)),
+                    _ => {
+                        let expired = shard.remove_entry(&key);
+                        let slot = Arc::new(Slot::new());
+                        shard
+                            .entries
+                            .insert(key.clone(), Entry::Pending(Arc::clone(&slot)));
+                        Claim::Compute(slot, expired)
+                    }
+                }
+            };
+
+            match claim {
+                Claim::Wait(slot) => {
+                    if let Some(value) = (WaitSlot { slot }).await {
+                        return value;
+                    }
+                }
+                Claim::Refresh => {
+                    let mut refresher = Refresher {
+                        cache: self,
+                        key: &key,
+                        started: Instant::now(),
+                        finished: false,
+                    };
+                    let compute = compute.take().expect("computation started twice");
+                    let value = compute().await;
+                    refresher.complete(value.clone());
+                    return value;
+                }
+                Claim::Compute(slot, expired) => {
+                    let mut driver = Driver {
+                        cache: self,
+                        key: &key,
+                        slot,
+                        started: Instant::now(),
+                        finished: false,
+                    };
+                    // Reported once the driver owns the entry, so a panicking
+                    // listener abandons the computation cleanly.
+                    self.notify(expired, EvictionReason::Expired);
+                    let compute = compute.take().expect("computation started twice");
+                    let value = compute().await;
+                    driver.complete(value.clone());
+                    return value;
+                }
+            }
+        }
+    }
+
+    // Loads `keys` with `loader` from helper threads before the cache takes
+    // traffic, reporting progress as it goes. Keys already cached or being
+    // computed are skipped. Returns how many values were stored.
+    pub fn warm(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+        loader: impl Fn(&K) -> V + Sync,
+        progress: impl Fn(Progress) + Sync,
+    ) -> usize
+    where
+        K: Send,
+        V: Send,
+        P: Send,
+    {
+        preload::run(
+            keys,
+            |key| {
+                if self.shard(&key).lock().unwrap().entries.contains_key(&key) {
+                    return false;
+                }
+                let started = Instant::now();
+                let value = loader(&key);
+                let mut evicted = Vec::new();
+                let mut shard = self.shard(&key).lock().unwrap();
+                if shard.entries.contains_key(&key) {
+                    return false;
+                }
+                let max = self.max_entries_per_shard;
+                shard.store(key, value, started.elapsed(), max, &mut evicted);
+                drop(shard);
+                self.notify(evicted, EvictionReason::Capacity);
+                true
+            },
+            progress,
+        )
+    }
+
+    // Drops a completed value. In-flight computations are not affected.
+    pub fn invalidate(&self, key: &K) -> Option<V> {
+        let mut shard = self.shard(key).lock().unwrap();
+        if let Entry::Pending(_) = shard.entries.get(key)? {
+            return None;
+        }
+        let (key, value) = shard.remove_entry(key)?;
+        drop(shard);
+        if self.eviction_listener.is_some() {
+            self.notify([(key, value.clone())], EvictionReason::Explicit);
+        }
+        Some(value)
+    }
+
+    // Number of completed values plus in-flight computations.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K> + Clone + Default> Default for FutureCache<K, V, P> {
+    fn default() -> Self {
+        Self::with_policy(P::default())
+    }
+}
+
+impl<K, V, P> Shed for FutureCache<K, V, P>
+where
+    K: Hash + Eq + Clone + Send,
+    V: Clone + Send,
+    P: EvictionPolicy<K> + Send,
+{
+    // Completed values; in-flight computations cannot be shed.
+    fn usage(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().ready)
+            .sum()
+    }
+
+    // Evicts the values the policy picks, spreading the evictions evenly
+    // over the shards and taking one shard lock at a time.
+    fn shed(&self, amount: usize) -> usize {
+        let mut released = 0;
+        let mut evicted = Vec::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let share = (amount - released).div_ceil(self.shards.len() - i);
+            let mut shard = shard.lock().unwrap();
+            for _ in 0..share {
+                let Some(entry) = shard.evict() else {
+                    break;
+                };
+                if self.eviction_listener.is_some() {
+                    evicted.push(entry);
+                }
+                released += 1;
+            }
+        }
+        self.notify(evicted, EvictionReason::Capacity);
+        released
+    }
+}
+
+impl<K, V, P> Drop for FutureCache<K, V, P> {
+    fn drop(&mut self) {
+        let Some(on_drop_item) = &self.on_drop_item else {
+            return;
+        };
+        for shard in &mut self.shards {
+            let shard = shard.get_mut().unwrap_or_else(|e| e.into_inner());
+            for (key, entry) in shard.entries.drain() {
+                if let Entry::Ready { value, .. } = entry {
+                    on_drop_item(key, value);
+                }
+            }
+        }
+    }
+}
+
+impl<K, V, P> Diagnostics for FutureCache<K, V, P>
+where
+    K: Hash + Eq + Clone + Send,
+    V: Clone + Send,
+    P: EvictionPolicy<K> + Send,
+{
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "FutureCache"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+// Owned by the caller that runs the computation. Publishes the result, or
+// marks the slot abandoned if that caller is dropped mid-computation.
+struct Driver<'a, K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> {
+    cache: &'a FutureCache<K, V, P>,
+    key: &'a K,
+    slot: Arc<Slot<V>>,
+    started: Instant,
+    finished: bool,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> Driver<'_, K, V, P> {
+    fn owns_entry(&self, shard: &Shard<K, V, P>) -> bool {
+        matches!(shard.entries.get(self.key), Some(Entry::Pending(s)) if Arc::ptr_eq(s, &self.slot))
+    }
+
+    fn complete(&mut self, value: V) {
+        self.finished = true;
+        let mut evicted = Vec::new();
+        {
+            let mut shard = self.cache.shard(self.key).lock().unwrap();
+            // The entry may have been invalidated while we were computing;
+            // only store the value if the slot is still ours.
+            if self.owns_entry(&shard) {
+                shard.store(
+                    self.key.clone(),
+                    value.clone(),
+                    self.started.elapsed(),
+                    self.cache.max_entries_per_shard,
+                    &mut evicted,
+                );
+            }
+        }
+        self.slot.finish(SlotState::Ready(value));
+        self.cache.notify(evicted, EvictionReason::Capacity);
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> Drop for Driver<'_, K, V, P> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Ok(mut shard) = self.cache.shard(self.key).lock() {
+            if self.owns_entry(&shard) {
+                shard.entries.remove(self.key);
+            }
+        }
+        self.slot.finish(SlotState::Abandoned);
+    }
+}
+
+// Owned by the caller that refreshes a value ahead of its expiry. Replaces
+// the value it set out to refresh, or clears the refreshing mark if that
+// caller is dropped mid-computation.
+struct Refresher<'a, K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> {
+    cache: &'a FutureCache<K, V, P>,
+    key: &'a K,
+    started: Instant,
+    finished: bool,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> Refresher<'_, K, V, P> {
+    fn complete(&mut self, new_value: V) {
+        self.finished = true;
+        let mut shard = self.cache.shard(self.key).lock().unwrap();
+        // An invalidated or expired value is not brought back.
+        if let Some(Entry::Ready {
+            value,
+            stored,
+            cost,
+            refreshing: refreshing @ true,
+            ..
+        }) = shard.entries.get_mut(self.key)
+        {
+            let old = std::mem::replace(value, new_value);
+            *stored = Instant::now();
+            *cost = self.started.elapsed();
+            *refreshing = false;
+            drop(shard);
+            self.cache
+                .notify([(self.key.clone(), old)], EvictionReason::Replaced);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, P: EvictionPolicy<K>> Drop for Refresher<'_, K, V, P> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Ok(mut shard) = self.cache.shard(self.key).lock() {
+            if let Some(Entry::Ready { refreshing, .. }) = shard.entries.get_mut(self.key) {
+                *refreshing = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::Fifo;
+
+    // Loads 1, 2, reads 1, then loads 3 into a one-shard cache holding two
+    // values, and returns the key evicted to make room.
+    fn evicted_after_read<P: EvictionPolicy<u32> + Clone + Send>(policy: P) -> Vec<u32> {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let log = evicted.clone();
+        let cache = FutureCache::with_shards_and_policy(1, policy)
+            .with_max_entries(2)
+            .with_eviction_listener(move |key, _, reason| {
+                assert_eq!(reason, EvictionReason::Capacity);
+                log.lock().unwrap().push(key);
+            });
+        for key in [1, 2] {
+            cache.warm([key], |&k| k * 10, |_| {});
+        }
+        assert_eq!(cache.get(&1), Some(10));
+        cache.warm([3], |&k| k * 10, |_| {});
+        assert_eq!(cache.len(), 2);
+        let evicted = evicted.lock().unwrap().clone();
+        evicted
+    }
+
+    #[test]
+    fn eviction_follows_the_policy() {
+        assert_eq!(evicted_after_read(Lru::new()), [2]);
+        assert_eq!(evicted_after_read(Fifo::new()), [1]);
+    }
+}
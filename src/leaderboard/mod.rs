@@ -0,0 +1,158 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::RwLock;
+
+struct Inner<K, S> {
+    scores: HashMap<K, S>,
+    // Highest score first; equal scores are ordered by key.
+    order: BTreeSet<(Reverse<S>, K)>,
+}
+
+// Keyed scores with rank queries. The key → score map and the score-ordered
+// index are updated together under one lock, so readers never see a key in
+// one and not the other.
+//
+// The crate has no concurrent ordered index (no skip list), and pairing a
+// concurrent map with a separately locked index would let a reader see a
+// re-scored key at its old rank and its new score. One `RwLock` over a
+// `HashMap` and a `BTreeSet` keeps both views consistent: reads run in
+// parallel, writes are serialized, which suits read-mostly boards.
+//
+// `rank` walks the index up to the key, so it costs O(rank); `top_n` costs
+// O(n). Both only take the read lock.
+pub struct Leaderboard<K, S> {
+    inner: RwLock<Inner<K, S>>,
+    id: StructureId,
+}
+
+impl<K: Hash + Ord + Clone, S: Ord + Clone> Leaderboard<K, S> {
+    pub fn new() -> Self {
+        Leaderboard {
+            inner: RwLock::new(Inner {
+                scores: HashMap::new(),
+                order: BTreeSet::new(),
+            }),
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    // Sets the score of `key`, returning its previous score.
+    pub fn set_score(&self, key: K, score: S) -> Option<S> {
+        let mut inner = self.inner.write().unwrap();
+        let previous = inner.scores.insert(key.clone(), score.clone());
+        if let Some(previous) = &previous {
+            inner.order.remove(&(Reverse(previous.clone()), key.clone()));
+        }
+        inner.order.insert((Reverse(score), key));
+        previous
+    }
+
+    pub fn remove(&self, key: &K) -> Option<S> {
+        let mut inner = self.inner.write().unwrap();
+        let score = inner.scores.remove(key)?;
+        inner.order.remove(&(Reverse(score.clone()), key.clone()));
+        Some(score)
+    }
+
+    pub fn score(&self, key: &K) -> Option<S> {
+        self.inner.read().unwrap().scores.get(key).cloned()
+    }
+
+    // Zero-based position of `key`, counting from the highest score.
+    pub fn rank(&self, key: &K) -> Option<usize> {
+        let inner = self.inner.read().unwrap();
+        let score = inner.scores.get(key)?;
+        Some(inner.order.range(..(Reverse(score.clone()), key.clone())).count())
+    }
+
+    // The `n` highest-scoring keys, best first.
+    pub fn top_n(&self, n: usize) -> Vec<(K, S)> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .order
+            .iter()
+            .take(n)
+            .map(|(Reverse(score), key)| (key.clone(), score.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Hash + Ord + Clone, S: Ord + Clone> Default for Leaderboard<K, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, S> Diagnostics for Leaderboard<K, S>
+where
+    K: Hash + Ord + Clone + Send + Sync,
+    S: Ord + Clone + Send + Sync,
+{
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "Leaderboard"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ties_are_ranked_by_key() {
+        let board = Leaderboard::new();
+        board.set_score("carol", 20);
+        board.set_score("bob", 10);
+        board.set_score("alice", 20);
+        board.set_score("dave", 10);
+
+        assert_eq!(board.top_n(3), [("alice", 20), ("carol", 20), ("bob", 10)]);
+        assert_eq!(board.rank(&"alice"), Some(0));
+        assert_eq!(board.rank(&"carol"), Some(1));
+        assert_eq!(board.rank(&"bob"), Some(2));
+        assert_eq!(board.rank(&"dave"), Some(3));
+        assert_eq!(board.rank(&"erin"), None);
+        assert_eq!(board.top_n(10).len(), 4);
+    }
+
+    #[test]
+    fn rescoring_moves_the_key_without_leaving_a_copy() {
+        let board = Leaderboard::new();
+        for (key, score) in [("a", 1), ("b", 2), ("c", 3)] {
+            board.set_score(key, score);
+        }
+        assert_eq!(board.set_score("a", 5), Some(1));
+        assert_eq!(board.rank(&"a"), Some(0));
+        assert_eq!(board.score(&"a"), Some(5));
+        assert_eq!(board.top_n(10), [("a", 5), ("c", 3), ("b", 2)]);
+        assert_eq!(board.len(), 3);
+
+        assert_eq!(board.set_score("a", 0), Some(5));
+        assert_eq!(board.rank(&"a"), Some(2));
+        assert_eq!(board.remove(&"a"), Some(0));
+        assert_eq!(board.top_n(10), [("c", 3), ("b", 2)]);
+        assert_eq!(board.rank(&"a"), None);
+    }
+}
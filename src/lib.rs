@@ -1 +1,33 @@
-pub mod queue;
\ No newline at end of file
+pub mod ordering;
+pub mod unwind;
+pub mod eviction;
+mod sync;
+mod shard;
+pub mod registry;
+pub mod budget;
+pub mod batch;
+pub mod preload;
+pub mod queue;
+pub mod routing;
+pub mod rate;
+pub mod quota;
+pub mod logbuf;
+pub mod sortbuf;
+pub mod tdigest;
+pub mod actor;
+pub mod bus;
+#[cfg(feature = "serde")]
+pub mod config;
+pub mod session;
+pub mod futcache;
+#[cfg(feature = "async")]
+pub mod async_map;
+pub mod leaderboard;
+pub mod lockfree_map;
+pub mod cuckoo_map;
+pub mod weak_map;
+pub mod recent;
+pub mod replay;
+pub mod transfer;
+pub mod workload;
+pub mod workers;
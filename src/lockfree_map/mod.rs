@@ -0,0 +1,421 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::Ordering;
+
+use crossbeam_epoch::{self as epoch, Atomic, Guard, Owned, Shared};
+
+use crate::preload::{self, Progress};
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::AtomicUsize;
+
+// Segment k (k >= 1) holds buckets [2^(k-1), 2^k); segment 0 holds bucket 0.
+const MAX_SEGMENTS: usize = 32;
+const MAX_BUCKETS: usize = 1 << (MAX_SEGMENTS - 1);
+const INITIAL_BUCKETS: usize = 2;
+// Average number of entries per bucket before the bucket count doubles.
+const MAX_LOAD: usize = 2;
+
+// List node. Bucket sentinels have no key/value and are never removed.
+// A set tag bit on `next` marks the node itself as logically deleted.
+struct Node<K, V> {
+    so_key: u64,
+    kv: Option<(K, V)>,
+    next: Atomic<Node<K, V>>,
+}
+
+// Link to update, node found there, and whether that node is the target.
+type Position<'g, K, V> = (&'g Atomic<Node<K, V>>, Shared<'g, Node<K, V>>, bool);
+
+type DropItem<K, V> = Box<dyn Fn(K, V) + Send + Sync>;
+
+struct Segment<K, V> {
+    buckets: Box<[Atomic<Node<K, V>>]>,
+}
+
+fn regular_key(hash: u64) -> u64 {
+    (hash | 1 << 63).reverse_bits()
+}
+
+fn sentinel_key(bucket: usize) -> u64 {
+    (bucket as u64).reverse_bits()
+}
+
+fn segment_of(bucket: usize) -> (usize, usize) {
+    if bucket == 0 {
+        return (0, 0);
+    }
+    let segment = (usize::BITS - bucket.leading_zeros()) as usize;
+    (segment, bucket - (1 << (segment - 1)))
+}
+
+// Lock-free hash map based on split-ordered lists (Shalev & Shavit, 2006).
+//
+// All entries live in one lock-free sorted list (Harris/Michael), ordered by
+// the bit-reversed hash. Buckets are shortcuts into that list: each bucket
+// points at a sentinel node, and doubling the bucket count never moves an
+// entry, it only adds sentinels that split existing buckets lazily on first
+// use. Nodes are reclaimed through crossbeam-epoch.
+//
+// Entries are immutable once inserted: `insert` only adds absent keys, and a
+// value is replaced by removing and re-inserting the key.
+pub struct LockFreeHashMap<K, V, S = RandomState> {
+    segments: [Atomic<Segment<K, V>>; MAX_SEGMENTS],
+    size: AtomicUsize,
+    count: AtomicUsize,
+    hasher: S,
+    on_drop_item: Option<DropItem<K, V>>,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq + Send + 'static, V: Send + 'static> LockFreeHashMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_hasher(RandomState::new())
+    }
+}
+
+// Unlinked nodes are destroyed by the epoch collector, possibly on another
+// thread and after the operation that unlinked them has returned, hence the
+// `Send + 'static` bounds.
+impl<K: Hash + Eq + Send + 'static, V: Send + 'static, S: BuildHasher> LockFreeHashMap<K, V, S> {
+    pub fn with_hasher(hasher: S) -> Self {
+        let map = LockFreeHashMap {
+            segments: std::array::from_fn(|_| Atomic::null()),
+            size: AtomicUsize::new(INITIAL_BUCKETS),
+            count: AtomicUsize::new(0),
+            hasher,
+            on_drop_item: None,
+            id: StructureId::next(),
+        };
+        let guard = epoch::pin();
+        let sentinel = Owned::new(Node {
+            so_key: sentinel_key(0),
+            kv: None,
+            next: Atomic::null(),
+        });
+        map.bucket_slot(0, &guard)
+            .store(sentinel, Ordering::Release);
+        map
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    // Called with each entry still in the map when it is dropped, instead of
+    // dropping it silently.
+    pub fn with_on_drop_item(mut self, f: impl Fn(K, V) + Send + Sync + 'static) -> Self {
+        self.on_drop_item = Some(Box::new(f));
+        self
+    }
+
+    fn bucket_slot<'g>(&'g self, bucket: usize, guard: &'g Guard) -> &'g Atomic<Node<K, V>> {
+        let (segment, index) = segment_of(bucket);
+        let slot = &self.segments[segment];
+        let mut current = slot.load(Ordering::Acquire, guard);
+        if current.is_null() {
+            let len = if segment == 0 { 1 } else { 1 << (segment - 1) };
+            let fresh = Owned::new(Segment {
+                buckets: (0..len).map(|_| Atomic::null()).collect(),
+            });
+            current = match slot.compare_exchange(
+                Shared::null(),
+                fresh,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+                guard,
+            ) {
+                Ok(installed) => installed,
+                Err(e) => e.current,
+            };
+        }
+        // Segments are only freed when the map is dropped.
+        unsafe { &current.deref().buckets[index] }
+    }
+
+    fn bucket_head<'g>(&'g self, bucket: usize, guard: &'g Guard) -> &'g Node<K, V> {
+        let slot = self.bucket_slot(bucket, guard);
+        let sentinel = slot.load(Ordering::Acquire, guard);
+        if !sentinel.is_null() {
+            return unsafe { sentinel.deref() };
+        }
+
+        // Splice a new sentinel into the list after the parent bucket's
+        // sentinel, which precedes it in split order.
+        let parent = bucket & !(1 << (usize::BITS - 1 - bucket.leading_zeros()));
+        let parent_head = self.bucket_head(parent, guard);
+        let so_key = sentinel_key(bucket);
+        let mut node = Owned::new(Node {
+            so_key,
+            kv: None,
+            next: Atomic::null(),
+        });
+        let sentinel = loop {
+            let (prev, curr, found) = Self::find(&parent_head.next, so_key, None, guard);
+            if found {
+                break curr;
+            }
+            node.next.store(curr, Ordering::Relaxed);
+            match prev.compare_exchange(curr, node, Ordering::AcqRel, Ordering::Acquire, guard) {
+                Ok(inserted) => break inserted,
+                Err(e) => node = e.new,
+            }
+        };
+        slot.store(sentinel, Ordering::Release);
+        unsafe { sentinel.deref() }
+    }
+
+    // Walks the list from `start` looking for the node with `so_key` (and
+    // `key`, for regular nodes), unlinking logically deleted nodes on the
+    // way. Returns the link that points at the first node not ordered before
+    // the target, that node, and whether it is the target itself.
+    fn find<'g>(
+        start: &'g Atomic<Node<K, V>>,
+        so_key: u64,
+        key: Option<&K>,
+        guard: &'g Guard,
+    ) -> Position<'g, K, V> {
+        'retry: loop {
+            let mut prev = start;
+            let mut curr = prev.load(Ordering::Acquire, guard);
+            loop {
+                let Some(node) = (unsafe { curr.as_ref() }) else {
+                    return (prev, curr, false);
+                };
+                let next = node.next.load(Ordering::Acquire, guard);
+                if next.tag() == 1 {
+                    match prev.compare_exchange(
+                        curr,
+                        next.with_tag(0),
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                        guard,
+                    ) {
+                        Ok(_) => {
+                            unsafe { guard.defer_destroy(curr) };
+                            curr = next.with_tag(0);
+                            continue;
+                        }
+                        // `prev` changed or was itself deleted.
+                        Err(_) => continue 'retry,
+                    }
+                }
+                if node.so_key > so_key {
+                    return (prev, curr, false);
+                }
+                if node.so_key == so_key {
+                    let matches = match (key, &node.kv) {
+                        (None, None) => true,
+                        (Some(k), Some((nk, _))) => nk == k,
+                        _ => false,
+                    };
+                    if matches {
+                        return (prev, curr, true);
+                    }
+                }
+                prev = &node.next;
+                curr = next;
+            }
+        }
+    }
+
+    fn locate<'g>(&'g self, key: &K, guard: &'g Guard) -> (&'g Node<K, V>, u64) {
+        let hash = self.hasher.hash_one(key);
+        let bucket = hash as usize & (self.size.load(Ordering::Acquire) - 1);
+        (self.bucket_head(bucket, guard), regular_key(hash))
+    }
+
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        let guard = epoch::pin();
+        let (head, so_key) = self.locate(key, &guard);
+        let (_, curr, found) = Self::find(&head.next, so_key, Some(key), &guard);
+        if !found {
+            return None;
+        }
+        unsafe { curr.deref() }.kv.as_ref().map(|(_, v)| v.clone())
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        let guard = epoch::pin();
+        let (head, so_key) = self.locate(key, &guard);
+        Self::find(&head.next, so_key, Some(key), &guard).2
+    }
+
+    // Inserts the entry if `key` is absent. Returns false, dropping `value`,
+    // if the key is already present.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        self.try_insert(key, value).is_ok()
+    }
+
+    // Inserts `entries` from helper threads, reporting progress as it goes.
+    // Entries whose key is already present are dropped, as with `insert`;
+    // if `entries` repeats a key, which of its values is kept is unspecified.
+    // Returns how many were inserted.
+    pub fn preload(
+        &self,
+        entries: impl IntoIterator<Item = (K, V)>,
+        progress: impl Fn(Progress) + Sync,
+    ) -> usize
+    where
+        K: Send + Sync,
+        V: Send + Sync,
+        S: Sync,
+    {
+        preload::run(entries, |(key, value)| self.insert(key, value), progress)
+    }
+
+    // Like `insert`, but hands the entry back if the key is already present.
+    pub fn try_insert(&self, key: K, value: V) -> Result<(), (K, V)> {
+        let guard = epoch::pin();
+        let (head, so_key) = self.locate(&key, &guard);
+        let mut node = Owned::new(Node {
+            so_key,
+            kv: Some((key, value)),
+            next: Atomic::null(),
+        });
+        loop {
+            let key = node.kv.as_ref().map(|(k, _)| k);
+            let (prev, curr, found) = Self::find(&head.next, so_key, key, &guard);
+            if found {
+                return Err(node.into_box().kv.take().unwrap());
+            }
+            node.next.store(curr, Ordering::Relaxed);
+            match prev.compare_exchange(curr, node, Ordering::AcqRel, Ordering::Acquire, &guard) {
+                Ok(_) => break,
+                Err(e) => node = e.new,
+            }
+        }
+
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        let size = self.size.load(Ordering::Relaxed);
+        if count > size * MAX_LOAD && size < MAX_BUCKETS {
+            // Losing this race just means another thread already grew it.
+            let _ = self.size.compare_exchange(size, size * 2, Ordering::AcqRel, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.remove_with(key, |v| v.clone())
+    }
+
+    // Removes the entry for `key`. Returns whether it was present.
+    pub fn delete(&self, key: &K) -> bool {
+        self.remove_with(key, |_| ()).is_some()
+    }
+
+    fn remove_with<R>(&self, key: &K, read: impl FnOnce(&V) -> R) -> Option<R> {
+        let guard = epoch::pin();
+        let (head, so_key) = self.locate(key, &guard);
+        loop {
+            let (prev, curr, found) = Self::find(&head.next, so_key, Some(key), &guard);
+            if !found {
+                return None;
+            }
+            let node = unsafe { curr.deref() };
+            let next = node.next.load(Ordering::Acquire, &guard);
+            if next.tag() == 1 {
+                continue;
+            }
+            // Marking the node is the linearization point; whoever wins the
+            // mark owns the removal.
+            if node
+                .next
+                .compare_exchange(next, next.with_tag(1), Ordering::AcqRel, Ordering::Acquire, &guard)
+                .is_err()
+            {
+                continue;
+            }
+            let result = node.kv.as_ref().map(|(_, v)| read(v));
+            if prev
+                .compare_exchange(curr, next, Ordering::AcqRel, Ordering::Acquire, &guard)
+                .is_ok()
+            {
+                unsafe { guard.defer_destroy(curr) };
+            } else {
+                // Let a traversal unlink it.
+                Self::find(&head.next, so_key, Some(key), &guard);
+            }
+            self.count.fetch_sub(1, Ordering::Relaxed);
+            return result;
+        }
+    }
+
+    // Approximate while other threads are inserting or removing.
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Visits every entry. Weakly consistent: entries inserted or removed
+    // during the walk may or may not be seen.
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V)) {
+        let guard = epoch::pin();
+        let head = self.bucket_head(0, &guard);
+        let mut curr = head.next.load(Ordering::Acquire, &guard);
+        while let Some(node) = unsafe { curr.with_tag(0).as_ref() } {
+            let next = node.next.load(Ordering::Acquire, &guard);
+            if next.tag() == 0 {
+                if let Some((k, v)) = &node.kv {
+                    f(k, v);
+                }
+            }
+            curr = next;
+        }
+    }
+}
+
+impl<K: Hash + Eq + Send + 'static, V: Send + 'static> Default for LockFreeHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Send + Sync + 'static, V: Send + Sync + 'static, S: BuildHasher + Send + Sync> Diagnostics for LockFreeHashMap<K, V, S> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "LockFreeHashMap"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+impl<K, V, S> Drop for LockFreeHashMap<K, V, S> {
+    fn drop(&mut self) {
+        // With `&mut self` no other thread can reach the map. Every node
+        // still linked is reachable from bucket 0's sentinel; unlinked nodes
+        // were already handed to the epoch collector.
+        unsafe {
+            let guard = epoch::unprotected();
+            let segment = self.segments[0].load(Ordering::Relaxed, guard);
+            let mut curr = segment.deref().buckets[0].load(Ordering::Relaxed, guard);
+            while !curr.is_null() {
+                let next = curr.deref().next.load(Ordering::Relaxed, guard);
+                let node = curr.into_owned().into_box();
+                if let (Some(on_drop_item), Some((k, v))) = (&self.on_drop_item, node.kv) {
+                    on_drop_item(k, v);
+                }
+                curr = next.with_tag(0);
+            }
+            for segment in &self.segments {
+                let segment = segment.load(Ordering::Relaxed, guard);
+                if !segment.is_null() {
+                    drop(segment.into_owned());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::AtomicPtr;
+use crate::unwind;
+
+struct Chunk<T> {
+    items: Vec<T>,
+    next: *mut Chunk<T>,
+}
+
+// Log buffer for high-rate producers. Each thread appends into its own chunk
+// through a `LogWriter`; a chunk is pushed onto a lock-free stack once it is
+// full (or the writer is flushed/dropped), and a single flusher takes the
+// whole stack with one atomic swap.
+//
+// The flusher never pops individual chunks, so the stack is not exposed to
+// the ABA problem and nodes can be freed as soon as they are taken.
+pub struct ChunkedLogBuffer<T> {
+    published: AtomicPtr<Chunk<T>>,
+    chunk_size: usize,
+    _marker: PhantomData<*mut T>,
+    id: StructureId,
+}
+
+unsafe impl<T: Send> Send for ChunkedLogBuffer<T> {}
+unsafe impl<T: Send> Sync for ChunkedLogBuffer<T> {}
+
+impl<T> ChunkedLogBuffer<T> {
+    pub fn new(chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk_size must be non-zero");
+        ChunkedLogBuffer {
+            published: AtomicPtr::new(ptr::null_mut()),
+            chunk_size,
+            _marker: PhantomData,
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    // Returns a writer owning a private chunk. Writers are not shared between
+    // threads; each producing thread should hold its own.
+    pub fn writer(&self) -> LogWriter<'_, T> {
+        LogWriter {
+            buffer: self,
+            chunk: Vec::with_capacity(self.chunk_size),
+        }
+    }
+
+    fn publish(&self, items: Vec<T>) {
+        let node = Box::into_raw(Box::new(Chunk {
+            items,
+            next: ptr::null_mut(),
+        }));
+
+        let mut head = self.published.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*node).next = head };
+            match self.published.compare_exchange_weak(
+                head,
+                node,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.published.load(Ordering::Acquire).is_null()
+    }
+
+    // Takes every published chunk, oldest first. Chunks from different
+    // writers are ordered by the time they were published; items within a
+    // chunk keep their append order.
+    pub fn drain(&self) -> Vec<Vec<T>> {
+        let mut chunks = Vec::new();
+        self.drain_with(|chunk| chunks.push(chunk));
+        chunks
+    }
+
+    pub fn drain_with(&self, mut f: impl FnMut(Vec<T>)) {
+        let mut node = self.published.swap(ptr::null_mut(), Ordering::Acquire);
+
+        // The stack is newest-first; reverse it to hand chunks out in
+        // publication order.
+        let mut reversed: *mut Chunk<T> = ptr::null_mut();
+        while !node.is_null() {
+            let next = unsafe { (*node).next };
+            unsafe { (*node).next = reversed };
+            reversed = node;
+            node = next;
+        }
+
+        while !reversed.is_null() {
+            let Chunk { items, next } = *unsafe { Box::from_raw(reversed) };
+            reversed = next;
+            if let Err(payload) = unwind::catch(|| f(items)) {
+                // Hand the chunks not yet delivered back to the stack so a
+                // later drain picks them up, then let the panic continue.
+                while !reversed.is_null() {
+                    let Chunk { items, next } = *unsafe { Box::from_raw(reversed) };
+                    reversed = next;
+                    self.publish(items);
+                }
+                unwind::resume(payload);
+            }
+        }
+    }
+}
+
+impl<T> Drop for ChunkedLogBuffer<T> {
+    fn drop(&mut self) {
+        self.drain_with(drop);
+    }
+}
+
+impl<T: Send> Diagnostics for ChunkedLogBuffer<T> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "ChunkedLogBuffer"
+    }
+
+    fn size(&self) -> Option<usize> {
+        None
+    }
+}
+
+pub struct LogWriter<'a, T> {
+    buffer: &'a ChunkedLogBuffer<T>,
+    chunk: Vec<T>,
+}
+
+impl<T> LogWriter<'_, T> {
+    pub fn append(&mut self, item: T) {
+        self.chunk.push(item);
+        if self.chunk.len() >= self.buffer.chunk_size {
+            self.flush();
+        }
+    }
+
+    // Publishes the current chunk even if it is not full yet.
+    pub fn flush(&mut self) {
+        if self.chunk.is_empty() {
+            return;
+        }
+        let full = mem::replace(&mut self.chunk, Vec::with_capacity(self.buffer.chunk_size));
+        self.buffer.publish(full);
+    }
+
+    pub fn pending(&self) -> usize {
+        self.chunk.len()
+    }
+}
+
+impl<T> Drop for LogWriter<'_, T> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
@@ -0,0 +1,59 @@
+// Named memory orderings for the lock-free queue.
+//
+// Every atomic access in `queue::Queue` and `queue::HazardQueue` goes
+// through one of these constants so the reasoning for each ordering lives in
+// one place and can be audited without reading the algorithm line by line.
+use std::sync::atomic::Ordering;
+
+// Reading `head` before dereferencing it. Pairs with CAS_HEAD_SUCCESS so the
+// node we see as head is fully initialised.
+pub const LOAD_HEAD: Ordering = Ordering::Acquire;
+
+// Reading `tail` before dereferencing it. Pairs with CAS_TAIL_SUCCESS.
+pub const LOAD_TAIL: Ordering = Ordering::Acquire;
+
+// Reading a node's `next` pointer. Pairs with CAS_NEXT_SUCCESS so the value
+// stored in the linked node is visible before we read it.
+pub const LOAD_NEXT: Ordering = Ordering::Acquire;
+
+// Re-reading `head`/`tail` only to detect that a snapshot went stale. The
+// value is compared, never dereferenced, so no synchronisation is needed.
+pub const RECHECK_HEAD: Ordering = Ordering::Relaxed;
+pub const RECHECK_TAIL: Ordering = Ordering::Relaxed;
+
+// Linking a new node after the last node publishes the node and its value.
+pub const CAS_NEXT_SUCCESS: Ordering = Ordering::Release;
+pub const CAS_NEXT_FAILURE: Ordering = Ordering::Relaxed;
+
+// Swinging `tail` forward publishes the new tail to later LOAD_TAILs.
+pub const CAS_TAIL_SUCCESS: Ordering = Ordering::Release;
+pub const CAS_TAIL_FAILURE: Ordering = Ordering::Relaxed;
+
+// Swinging `head` forward publishes the new dummy node to later LOAD_HEADs.
+pub const CAS_HEAD_SUCCESS: Ordering = Ordering::Release;
+pub const CAS_HEAD_FAILURE: Ordering = Ordering::Relaxed;
+
+// The element count. It is a statistic and orders nothing; the nodes and
+// values it counts are published by the accesses above.
+pub const COUNT: Ordering = Ordering::Relaxed;
+
+// Accesses made while holding `&mut self` (teardown, invariant checks). No
+// other thread can observe the queue, so nothing needs ordering.
+pub const EXCLUSIVE: Ordering = Ordering::Relaxed;
+
+// `HazardQueue` accesses to `head`, `tail` and the hazard slots. A thread
+// publishes a hazard and then re-reads the pointer it protects; a reclaimer
+// unlinks a node and then reads every hazard. One total order over all of
+// these guarantees that either the reclaimer sees the hazard or the
+// protecting thread sees the unlink and retries.
+pub const HAZARD: Ordering = Ordering::SeqCst;
+
+// Taking a hazard record hands over its retired list from the previous
+// holder; releasing it publishes the list to the next one.
+pub const ACQUIRE_RECORD: Ordering = Ordering::Acquire;
+pub const RELEASE_RECORD: Ordering = Ordering::Release;
+
+// Checking whether a record looks free, reading the record count, and failed
+// record CASes. These are hints for which record to try or when to scan;
+// nothing is dereferenced on the strength of them.
+pub const RECORD_HINT: Ordering = Ordering::Relaxed;
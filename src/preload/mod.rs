@@ -0,0 +1,90 @@
+// Bulk loading before a structure takes traffic.
+//
+// `LockFreeHashMap::preload`, `CuckooHashMap::preload` and
+// `FutureCache::warm` spread the work over one helper thread per core, so a
+// service can reach its steady-state contents right after deploy instead of
+// filling them miss by miss. The work is handed out in chunks; after each
+// chunk the progress callback is told how far the load has got. Calls to the
+// callback are serialized and `loaded` never decreases, so it can drive a
+// log line or a readiness probe directly.
+use std::thread;
+
+use crate::sync::{self, Mutex};
+
+// Items per chunk handed to a helper thread.
+const CHUNK: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    // Items processed so far, whether or not they ended up stored.
+    pub loaded: usize,
+    pub total: usize,
+}
+
+impl Progress {
+    pub fn is_done(&self) -> bool {
+        self.loaded == self.total
+    }
+}
+
+// Runs `load` on every item, in parallel unless there is too little work to
+// be worth a thread, and returns how many calls returned `true`.
+pub(crate) fn run<T: Send>(
+    items: impl IntoIterator<Item = T>,
+    load: impl Fn(T) -> bool + Sync,
+    progress: impl Fn(Progress) + Sync,
+) -> usize {
+    let mut items = items.into_iter();
+    let mut chunks = Vec::new();
+    loop {
+        let chunk: Vec<T> = items.by_ref().take(CHUNK).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        chunks.push(chunk);
+    }
+    let total = chunks.iter().map(Vec::len).sum();
+    let num_chunks = chunks.len();
+    if num_chunks == 0 {
+        progress(Progress {
+            loaded: 0,
+            total: 0,
+        });
+        return 0;
+    }
+    // Popped from the back; reversed so chunks load in iteration order.
+    chunks.reverse();
+
+    let queue = Mutex::new(chunks);
+    // (loaded, stored), updated and reported under the lock.
+    let counts = Mutex::new((0, 0));
+    let work = || loop {
+        let Some(chunk) = queue.lock().unwrap().pop() else {
+            break;
+        };
+        let len = chunk.len();
+        let stored = chunk.into_iter().map(&load).filter(|&stored| stored).count();
+        let mut counts = counts.lock().unwrap();
+        counts.0 += len;
+        counts.1 += stored;
+        progress(Progress {
+            loaded: counts.0,
+            total,
+        });
+    };
+
+    let helpers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(num_chunks);
+    if helpers > 1 && !sync::SINGLE_THREADED {
+        thread::scope(|s| {
+            for _ in 0..helpers {
+                s.spawn(work);
+            }
+        });
+    } else {
+        work();
+    }
+    let (_, stored) = counts.into_inner().unwrap();
+    stored
+}
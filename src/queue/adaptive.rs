@@ -0,0 +1,236 @@
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::sync::TryLockError;
+
+use super::{Queue, SingleVecLockQueue};
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::{AtomicBool, AtomicU64, AtomicUsize, RwLock};
+
+const DEFAULT_WINDOW: u64 = 1024;
+const DEFAULT_HIGH_WATERMARK: f64 = 0.25;
+const DEFAULT_LOW_WATERMARK: f64 = 0.05;
+
+// Values of `AdaptiveQueue::pending`.
+const NO_MIGRATION: usize = 0;
+const TO_LOCK_FREE: usize = 1;
+const TO_LOCKED: usize = 2;
+
+enum Backend<T> {
+    Locked(SingleVecLockQueue<T>),
+    LockFree(Queue<T>),
+}
+
+impl<T> Backend<T> {
+    fn enqueue(&self, value: T) {
+        match self {
+            Backend::Locked(q) => q.enqueue(value),
+            Backend::LockFree(q) => q.enqueue(value),
+        }
+    }
+
+    fn dequeue(&self) -> Option<T> {
+        match self {
+            Backend::Locked(q) => q.dequeue(),
+            Backend::LockFree(q) => q.dequeue(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Backend::Locked(q) => q.len(),
+            Backend::LockFree(q) => q.len(),
+        }
+    }
+}
+
+// Queue that picks its backend at runtime. It starts on the single mutex
+// queue, which is cheapest when uncontended, and moves to the lock-free queue
+// once the share of operations that overlap with another operation exceeds
+// `high_watermark` within a sampling window. It moves back when that share
+// falls below `low_watermark`.
+//
+// Operations hold a shared lock on the backend; a migration takes the
+// exclusive lock and moves every element across in FIFO order, so switching
+// backends never reorders or loses items. A migration never waits for the
+// lock: while it is busy the migration stays pending, and every following
+// operation tries it again after releasing its own shared lock.
+pub struct AdaptiveQueue<T> {
+    backend: RwLock<Backend<T>>,
+    lock_free: AtomicBool,
+    in_flight: AtomicUsize,
+    ops: AtomicU64,
+    contended: AtomicU64,
+    migrations: AtomicU64,
+    // Migration decided by the last window but not yet performed.
+    pending: AtomicUsize,
+    window: u64,
+    high_watermark: f64,
+    low_watermark: f64,
+    id: StructureId,
+}
+
+impl<T> AdaptiveQueue<T> {
+    pub fn new() -> Self {
+        Self::with_thresholds(DEFAULT_HIGH_WATERMARK, DEFAULT_LOW_WATERMARK, DEFAULT_WINDOW)
+    }
+
+    pub fn with_thresholds(high_watermark: f64, low_watermark: f64, window: u64) -> Self {
+        assert!(
+            low_watermark <= high_watermark,
+            "low_watermark must not exceed high_watermark"
+        );
+        assert!(window > 0, "window must be non-zero");
+        AdaptiveQueue {
+            backend: RwLock::new(Backend::Locked(SingleVecLockQueue::new())),
+            lock_free: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+            ops: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            migrations: AtomicU64::new(0),
+            pending: AtomicUsize::new(NO_MIGRATION),
+            window,
+            high_watermark,
+            low_watermark,
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    pub fn enqueue(&self, value: T) {
+        self.track(|backend| backend.enqueue(value))
+    }
+
+    pub fn dequeue(&self) -> Option<T> {
+        self.track(|backend| backend.dequeue())
+    }
+
+    // Waits for a migration in progress to finish.
+    pub fn len(&self) -> usize {
+        self.backend.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_lock_free(&self) -> bool {
+        self.lock_free.load(Ordering::Relaxed)
+    }
+
+    // Number of backend switches performed so far.
+    pub fn migrations(&self) -> u64 {
+        self.migrations.load(Ordering::Relaxed)
+    }
+
+    fn track<R>(&self, op: impl FnOnce(&Backend<T>) -> R) -> R {
+        if self.in_flight.fetch_add(1, Ordering::Relaxed) > 0 {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+        }
+        let result = op(&self.backend.read().unwrap());
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        // Exactly one operation brings the count to `window` and closes it.
+        if self.ops.fetch_add(1, Ordering::Relaxed) + 1 == self.window {
+            self.end_window();
+        }
+        match self.pending.load(Ordering::Relaxed) {
+            NO_MIGRATION => {}
+            target => self.migrate(target),
+        }
+        result
+    }
+
+    fn end_window(&self) {
+        // Operations counted after the swaps belong to the next window.
+        let ops = self.ops.swap(0, Ordering::Relaxed);
+        let contended = self.contended.swap(0, Ordering::Relaxed);
+        let ratio = contended as f64 / ops as f64;
+
+        let target = if !self.is_lock_free() && ratio > self.high_watermark {
+            TO_LOCK_FREE
+        } else if self.is_lock_free() && ratio < self.low_watermark {
+            TO_LOCKED
+        } else {
+            NO_MIGRATION
+        };
+        self.pending.store(target, Ordering::Relaxed);
+    }
+
+    fn migrate(&self, target: usize) {
+        // A busy backend is left alone; the migration stays pending and the
+        // next operation tries again.
+        let Ok(mut backend) = self.backend.try_write() else {
+            return;
+        };
+        // A later window may have changed or cancelled the migration while
+        // this one waited for the lock.
+        if self
+            .pending
+            .compare_exchange(target, NO_MIGRATION, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return;
+        }
+        let to_lock_free = target == TO_LOCK_FREE;
+        if matches!(*backend, Backend::LockFree(_)) == to_lock_free {
+            return;
+        }
+
+        let next = if to_lock_free {
+            Backend::LockFree(Queue::new())
+        } else {
+            Backend::Locked(SingleVecLockQueue::new())
+        };
+        while let Some(value) = backend.dequeue() {
+            next.enqueue(value);
+        }
+        *backend = next;
+
+        self.lock_free.store(to_lock_free, Ordering::Relaxed);
+        self.migrations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<T> Default for AdaptiveQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Never waits for the backend lock; prints `<locked>` during a migration.
+impl<T: fmt::Debug> fmt::Debug for AdaptiveQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("AdaptiveQueue");
+        d.field("id", &self.id)
+            .field("lock_free", &self.is_lock_free())
+            .field("migrations", &self.migrations());
+        let backend = match self.backend.try_read() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        };
+        match backend.as_deref() {
+            Some(Backend::Locked(q)) => d.field("backend", q),
+            Some(Backend::LockFree(q)) => d.field("backend", q),
+            None => d.field("backend", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+impl<T: Send> Diagnostics for AdaptiveQueue<T> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "AdaptiveQueue"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
@@ -0,0 +1,434 @@
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::ptr;
+
+use super::{DropItem, Node};
+use crate::ordering;
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::{AtomicBool, AtomicPtr, AtomicUsize};
+
+// Hazard slots per record: a dequeue protects the head and its successor.
+const HAZARDS: usize = 2;
+// A record scans once its retired list holds this many nodes per record in
+// the queue, so every scan frees at least half of the list.
+const SCAN_PER_RECORD: usize = 2 * HAZARDS;
+const MIN_SCAN: usize = 16;
+
+// Per-operation reclamation state. An operation takes a free record for its
+// duration, publishes the nodes it is about to dereference in `hazards`, and
+// appends the nodes it unlinks to `retired`. Records are never freed before
+// the queue, so the list of records only grows, up to the largest number of
+// operations that were ever in flight at once.
+struct Record<T> {
+    active: AtomicBool,
+    hazards: [AtomicPtr<Node<T>>; HAZARDS],
+    // Only touched by the operation holding the record.
+    retired: UnsafeCell<Vec<*mut Node<T>>>,
+    // Set before the record is published and never changed afterwards.
+    next: *mut Record<T>,
+}
+
+// Michael-Scott queue like `Queue`, with nodes reclaimed through hazard
+// pointers instead of epochs. A node is freed by the scan that first finds
+// it unprotected, and a scan runs whenever a record's retired list reaches
+// its threshold, so the number of unlinked but unfreed nodes is bounded by
+// the number of records squared, regardless of how long any thread is
+// descheduled. Epoch reclamation has no such bound: one stalled pinned
+// thread holds back every free.
+//
+// Every operation pays for it with sequentially consistent accesses to
+// `head` and `tail` and a walk of the record list, so `Queue` stays the
+// faster choice when bounded memory is not a requirement.
+pub struct HazardQueue<T> {
+    head: AtomicPtr<Node<T>>,
+    tail: AtomicPtr<Node<T>>,
+    records: AtomicPtr<Record<T>>,
+    num_records: AtomicUsize,
+    // Counted as in `Queue`: raised before linking, lowered after unlinking.
+    len: AtomicUsize,
+    on_drop_item: Option<DropItem<T>>,
+    id: StructureId,
+}
+
+// Values are moved in by `enqueue` and out by `dequeue`, possibly on other
+// threads; nodes and records are only reached under the protocol above.
+unsafe impl<T: Send> Send for HazardQueue<T> {}
+unsafe impl<T: Send> Sync for HazardQueue<T> {}
+
+// A record held for one operation. Dropping it clears its hazards and
+// hands the record back.
+struct Holder<'a, T> {
+    queue: &'a HazardQueue<T>,
+    record: &'a Record<T>,
+}
+
+impl<T> Holder<'_, T> {
+    // Loads `src` and publishes it in hazard slot `slot`, retrying until the
+    // published pointer is still current. From then on the node it points to
+    // cannot be freed until the slot changes.
+    fn protect(&self, slot: usize, src: &AtomicPtr<Node<T>>) -> *mut Node<T> {
+        let mut node = src.load(ordering::HAZARD);
+        loop {
+            self.record.hazards[slot].store(node, ordering::HAZARD);
+            let current = src.load(ordering::HAZARD);
+            if current == node {
+                return node;
+            }
+            node = current;
+        }
+    }
+
+    // Publishes `node` without validating it; the caller validates.
+    fn set(&self, slot: usize, node: *mut Node<T>) {
+        self.record.hazards[slot].store(node, ordering::HAZARD);
+    }
+
+    // Queues an unlinked node for freeing, and scans if enough have piled up.
+    fn retire(&self, node: *mut Node<T>) {
+        // Safety: the record is held by this operation alone.
+        let retired = unsafe { &mut *self.record.retired.get() };
+        retired.push(node);
+        let threshold =
+            (SCAN_PER_RECORD * self.queue.num_records.load(ordering::RECORD_HINT)).max(MIN_SCAN);
+        if retired.len() >= threshold {
+            self.queue.scan(retired);
+        }
+    }
+}
+
+impl<T> Drop for Holder<'_, T> {
+    fn drop(&mut self) {
+        for hazard in &self.record.hazards {
+            hazard.store(ptr::null_mut(), ordering::HAZARD);
+        }
+        self.record.active.store(false, ordering::RELEASE_RECORD);
+    }
+}
+
+impl<T> HazardQueue<T> {
+    pub fn new() -> Self {
+        let dummy = Box::into_raw(Box::new(Node::dummy()));
+        HazardQueue {
+            head: AtomicPtr::new(dummy),
+            tail: AtomicPtr::new(dummy),
+            records: AtomicPtr::new(ptr::null_mut()),
+            num_records: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            on_drop_item: None,
+            id: StructureId::next(),
+        }
+    }
+
+    // Called with each element still queued when the queue is dropped, in
+    // FIFO order, instead of dropping it silently.
+    pub fn with_on_drop_item(mut self, f: impl Fn(T) + Send + Sync + 'static) -> Self {
+        self.on_drop_item = Some(Box::new(f));
+        self
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    pub fn enqueue(&self, value: T) {
+        let node = Box::into_raw(Box::new(Node::new(value)));
+        self.len.fetch_add(1, ordering::COUNT);
+        let holder = self.hold();
+        loop {
+            let tail = holder.protect(0, &self.tail);
+            let next = unsafe { (*tail).next.load(ordering::LOAD_NEXT) };
+            if !next.is_null() {
+                // Help a lagging tail along before retrying.
+                self.tail
+                    .compare_exchange(tail, next, ordering::HAZARD, ordering::CAS_TAIL_FAILURE)
+                    .ok();
+                continue;
+            }
+            let linked = unsafe {
+                (*tail).next.compare_exchange(
+                    ptr::null_mut(),
+                    node,
+                    ordering::CAS_NEXT_SUCCESS,
+                    ordering::CAS_NEXT_FAILURE,
+                )
+            };
+            if linked.is_ok() {
+                self.tail
+                    .compare_exchange(tail, node, ordering::HAZARD, ordering::CAS_TAIL_FAILURE)
+                    .ok();
+                return;
+            }
+        }
+    }
+
+    pub fn dequeue(&self) -> Option<T> {
+        let holder = self.hold();
+        loop {
+            let head = holder.protect(0, &self.head);
+            let tail = self.tail.load(ordering::HAZARD);
+            let next = unsafe { (*head).next.load(ordering::LOAD_NEXT) };
+            holder.set(1, next);
+            // While `head` is still the head, `next` has not been unlinked,
+            // so the hazard just published covers it.
+            if self.head.load(ordering::HAZARD) != head {
+                continue;
+            }
+            if next.is_null() {
+                return None;
+            }
+            if head == tail {
+                self.tail
+                    .compare_exchange(tail, next, ordering::HAZARD, ordering::CAS_TAIL_FAILURE)
+                    .ok();
+                continue;
+            }
+            if self
+                .head
+                .compare_exchange(head, next, ordering::HAZARD, ordering::CAS_HEAD_FAILURE)
+                .is_ok()
+            {
+                // `next` is the new dummy and its value is ours. It stays
+                // protected by slot 1 even if another dequeue unlinks it.
+                let value = unsafe { (*next).value.assume_init_read() };
+                holder.retire(head);
+                self.len.fetch_sub(1, ordering::COUNT);
+                return Some(value);
+            }
+        }
+    }
+
+    // Approximate while other threads are operating, as for `Queue::len`.
+    pub fn len(&self) -> usize {
+        self.len.load(ordering::COUNT)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Takes a free record, or adds a new one if every record is in use.
+    fn hold(&self) -> Holder<'_, T> {
+        let mut record = self.records.load(ordering::ACQUIRE_RECORD);
+        while let Some(r) = unsafe { record.as_ref() } {
+            if !r.active.load(ordering::RECORD_HINT)
+                && r.active
+                    .compare_exchange(false, true, ordering::ACQUIRE_RECORD, ordering::RECORD_HINT)
+                    .is_ok()
+            {
+                return Holder {
+                    queue: self,
+                    record: r,
+                };
+            }
+            record = r.next;
+        }
+
+        let record = Box::into_raw(Box::new(Record {
+            active: AtomicBool::new(true),
+            hazards: [
+                AtomicPtr::new(ptr::null_mut()),
+                AtomicPtr::new(ptr::null_mut()),
+            ],
+            retired: UnsafeCell::new(Vec::new()),
+            next: ptr::null_mut(),
+        }));
+        let mut first = self.records.load(ordering::RECORD_HINT);
+        loop {
+            // Not yet reachable by other threads.
+            unsafe { (*record).next = first };
+            match self.records.compare_exchange(
+                first,
+                record,
+                ordering::RELEASE_RECORD,
+                ordering::RECORD_HINT,
+            ) {
+                Ok(_) => break,
+                Err(current) => first = current,
+            }
+        }
+        self.num_records.fetch_add(1, ordering::RECORD_HINT);
+        Holder {
+            queue: self,
+            record: unsafe { &*record },
+        }
+    }
+
+    // Frees every node in `retired` that no record currently protects.
+    fn scan(&self, retired: &mut Vec<*mut Node<T>>) {
+        let mut protected = Vec::new();
+        let mut record = self.records.load(ordering::ACQUIRE_RECORD);
+        while let Some(r) = unsafe { record.as_ref() } {
+            for hazard in &r.hazards {
+                let node = hazard.load(ordering::HAZARD);
+                if !node.is_null() {
+                    protected.push(node);
+                }
+            }
+            record = r.next;
+        }
+        protected.sort_unstable();
+        retired.retain(|&node| {
+            if protected.binary_search(&node).is_ok() {
+                return true;
+            }
+            // Unlinked, so no new hazard can be published for it, and no
+            // existing one covers it. Its value was moved out when it
+            // became the dummy.
+            unsafe { drop(Box::from_raw(node)) };
+            false
+        });
+    }
+}
+
+impl<T> Default for HazardQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for HazardQueue<T> {
+    fn drop(&mut self) {
+        // The head is the dummy; every node after it still holds a value.
+        let mut node = *self.head.get_mut();
+        let mut is_dummy = true;
+        while !node.is_null() {
+            let mut boxed = unsafe { Box::from_raw(node) };
+            if !is_dummy {
+                match &self.on_drop_item {
+                    Some(on_drop_item) => on_drop_item(unsafe { boxed.value.assume_init_read() }),
+                    None => unsafe { boxed.value.assume_init_drop() },
+                }
+            }
+            is_dummy = false;
+            node = *boxed.next.get_mut();
+        }
+
+        let mut record = *self.records.get_mut();
+        while !record.is_null() {
+            let boxed = unsafe { Box::from_raw(record) };
+            for node in boxed.retired.into_inner() {
+                unsafe { drop(Box::from_raw(node)) };
+            }
+            record = boxed.next;
+        }
+    }
+}
+
+// Walking the list would race with concurrent dequeuers, so only the
+// identity is shown.
+impl<T> fmt::Debug for HazardQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HazardQueue")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Send> Diagnostics for HazardQueue<T> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "HazardQueue"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize as StdAtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    const THREADS: usize = 4;
+    const PER_THREAD: usize = 10_000;
+
+    // Counts its own drops, so a value dropped twice or never shows up.
+    struct Counted(Arc<StdAtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Lengths of the retired lists, and the scan threshold each must stay
+    // below between operations.
+    fn retired<T>(queue: &mut HazardQueue<T>) -> (Vec<usize>, usize) {
+        let threshold = (SCAN_PER_RECORD * *queue.num_records.get_mut()).max(MIN_SCAN);
+        let mut lengths = Vec::new();
+        let mut record = *queue.records.get_mut();
+        while let Some(r) = unsafe { record.as_mut() } {
+            lengths.push(r.retired.get_mut().len());
+            record = r.next;
+        }
+        (lengths, threshold)
+    }
+
+    #[test]
+    fn concurrent_operations_drop_every_value_once() {
+        let drops = Arc::new(StdAtomicUsize::new(0));
+        let mut queue = HazardQueue::new();
+        let seen = StdAtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                let (queue, drops) = (&queue, &drops);
+                s.spawn(move || {
+                    for _ in 0..PER_THREAD {
+                        queue.enqueue(Counted(Arc::clone(drops)));
+                    }
+                });
+            }
+            for _ in 0..THREADS {
+                let (queue, seen) = (&queue, &seen);
+                s.spawn(move || {
+                    for _ in 0..PER_THREAD / 2 {
+                        while queue.dequeue().is_none() {
+                            thread::yield_now();
+                        }
+                        seen.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+
+        let remaining = THREADS * PER_THREAD - seen.load(Ordering::Relaxed);
+        assert_eq!(queue.len(), remaining);
+        assert_eq!(drops.load(Ordering::Relaxed), THREADS * PER_THREAD / 2);
+        let (lengths, threshold) = retired(&mut queue);
+        assert!(lengths.iter().all(|&len| len < threshold));
+
+        drop(queue);
+        assert_eq!(drops.load(Ordering::Relaxed), THREADS * PER_THREAD);
+    }
+
+    #[test]
+    fn stalled_reader_does_not_hold_back_reclamation() {
+        let mut queue = HazardQueue::new();
+        queue.enqueue(0);
+        {
+            // A thread descheduled mid-dequeue, protecting the current head.
+            let stalled = queue.hold();
+            stalled.protect(0, &queue.head);
+            thread::scope(|s| {
+                for _ in 0..THREADS {
+                    s.spawn(|| {
+                        for i in 0..PER_THREAD {
+                            queue.enqueue(i);
+                            queue.dequeue();
+                        }
+                    });
+                }
+            });
+        }
+        // Epoch reclamation would have kept every one of these nodes.
+        let (lengths, threshold) = retired(&mut queue);
+        assert!(lengths.iter().all(|&len| len < threshold));
+        assert!(lengths.iter().sum::<usize>() < THREADS * PER_THREAD / 10);
+    }
+}
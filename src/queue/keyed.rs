@@ -0,0 +1,354 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::shard;
+
+const DEFAULT_SHARDS: usize = 16;
+
+// Items of one key, plus whether a consumer currently holds that key.
+struct Lane<T> {
+    items: VecDeque<T>,
+    leased: bool,
+}
+
+struct Shard<K, T> {
+    lanes: HashMap<K, Lane<T>>,
+    // Keys with queued items that no consumer holds, in the order they
+    // became ready.
+    ready: VecDeque<K>,
+}
+
+// FIFO per key, parallel across keys. Popping an item leases its key: no
+// other consumer gets an item of that key until the `Lease` is dropped, so
+// items of one key are processed one at a time and in order, while items of
+// different keys are handed to consumers concurrently.
+//
+// Keys live in shards chosen by hash. A key's sub-queue is created by its
+// first push and removed once it is empty and not leased, so the set of keys
+// can grow and shrink freely.
+pub struct KeyedOrderedQueue<K, T, S = RandomState> {
+    shards: Vec<Mutex<Shard<K, T>>>,
+    hasher: S,
+    // Where the next pop starts looking, so ready keys in every shard get
+    // served.
+    next_shard: AtomicUsize,
+    ready_keys: AtomicUsize,
+    len: AtomicUsize,
+    closed: AtomicBool,
+    consumers_waiting: AtomicUsize,
+    signal: Mutex<()>,
+    became_ready: Condvar,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq + Clone, T> KeyedOrderedQueue<K, T> {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(num_shards: usize) -> Self {
+        Self::with_shards_and_hasher(num_shards, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq + Clone, T, S: BuildHasher> KeyedOrderedQueue<K, T, S> {
+    pub fn with_shards_and_hasher(num_shards: usize, hasher: S) -> Self {
+        KeyedOrderedQueue {
+            shards: (0..shard::shard_count(num_shards))
+                .map(|_| {
+                    Mutex::new(Shard {
+                        lanes: HashMap::new(),
+                        ready: VecDeque::new(),
+                    })
+                })
+                .collect(),
+            hasher,
+            next_shard: AtomicUsize::new(0),
+            ready_keys: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            consumers_waiting: AtomicUsize::new(0),
+            signal: Mutex::new(()),
+            became_ready: Condvar::new(),
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn shard(&self, key: &K) -> MutexGuard<'_, Shard<K, T>> {
+        let index = shard::shard_index(self.hasher.hash_one(key), self.shards.len());
+        self.shards[index].lock().unwrap()
+    }
+
+    fn wake_consumer(&self) {
+        if self.consumers_waiting.load(Ordering::SeqCst) > 0 {
+            let _guard = self.signal.lock().unwrap();
+            self.became_ready.notify_one();
+        }
+    }
+
+    fn wake_all(&self) {
+        let _guard = self.signal.lock().unwrap();
+        self.became_ready.notify_all();
+    }
+
+    // Queues `item` behind every earlier item of `key`. Returns the item if
+    // the queue is closed.
+    pub fn push(&self, key: K, item: T) -> Result<(), T> {
+        if self.is_closed() {
+            return Err(item);
+        }
+        let mut shard = self.shard(&key);
+        let lane = shard.lanes.entry(key.clone()).or_insert_with(|| Lane {
+            items: VecDeque::new(),
+            leased: false,
+        });
+        lane.items.push_back(item);
+        let became_ready = !lane.leased && lane.items.len() == 1;
+        if became_ready {
+            shard.ready.push_back(key);
+            // Counted before the shard is unlocked, as a consumer may pop
+            // the key and decrement the count as soon as it is.
+            self.ready_keys.fetch_add(1, Ordering::SeqCst);
+        }
+        self.len.fetch_add(1, Ordering::SeqCst);
+        drop(shard);
+        if became_ready {
+            self.wake_consumer();
+        }
+        Ok(())
+    }
+
+    // Takes the oldest item of some ready key without waiting.
+    pub fn try_pop(&self) -> Option<Lease<'_, K, T, S>> {
+        if self.ready_keys.load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let start = self.next_shard.fetch_add(1, Ordering::Relaxed);
+        for i in 0..self.shards.len() {
+            let mut shard = self.shards[(start + i) % self.shards.len()].lock().unwrap();
+            let Some(key) = shard.ready.pop_front() else {
+                continue;
+            };
+            let lane = shard.lanes.get_mut(&key).expect("ready key without a lane");
+            let item = lane.items.pop_front().expect("ready key without items");
+            lane.leased = true;
+            drop(shard);
+            self.ready_keys.fetch_sub(1, Ordering::SeqCst);
+            if self.len.fetch_sub(1, Ordering::SeqCst) == 1 && self.is_closed() {
+                self.wake_all();
+            }
+            return Some(Lease {
+                queue: self,
+                key: Some(key),
+                item: Some(item),
+            });
+        }
+        None
+    }
+
+    // Waits for an item of some ready key. Returns `None` once the queue is
+    // closed and every item has been handed out.
+    pub fn pop(&self) -> Option<Lease<'_, K, T, S>> {
+        loop {
+            if let Some(lease) = self.try_pop() {
+                return Some(lease);
+            }
+            let guard = self.signal.lock().unwrap();
+            self.consumers_waiting.fetch_add(1, Ordering::SeqCst);
+            if self.ready_keys.load(Ordering::SeqCst) == 0 {
+                if self.is_closed() && self.is_empty() {
+                    self.consumers_waiting.fetch_sub(1, Ordering::SeqCst);
+                    return None;
+                }
+                drop(self.became_ready.wait(guard).unwrap());
+            }
+            self.consumers_waiting.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn release(&self, key: K) {
+        let mut shard = self.shard(&key);
+        let lane = shard.lanes.get_mut(&key).expect("leased key without a lane");
+        lane.leased = false;
+        if lane.items.is_empty() {
+            shard.lanes.remove(&key);
+            return;
+        }
+        shard.ready.push_back(key);
+        // Counted under the shard lock, as in `push`.
+        self.ready_keys.fetch_add(1, Ordering::SeqCst);
+        drop(shard);
+        self.wake_consumer();
+    }
+
+    // Stops accepting items. Items already queued are still handed out.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.wake_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    // Items queued and not yet handed out.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Keys with queued items or an outstanding lease.
+    pub fn active_keys(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().lanes.len())
+            .sum()
+    }
+}
+
+impl<K: Hash + Eq + Clone, T> Default for KeyedOrderedQueue<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, T, S> Diagnostics for KeyedOrderedQueue<K, T, S>
+where
+    K: Hash + Eq + Clone + Send,
+    T: Send,
+    S: BuildHasher + Send + Sync,
+{
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "KeyedOrderedQueue"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+// An item together with exclusive hold of its key. Dropping the lease (or
+// calling `into_item`) lets the key's next item be popped.
+pub struct Lease<'a, K: Hash + Eq + Clone, T, S: BuildHasher> {
+    queue: &'a KeyedOrderedQueue<K, T, S>,
+    key: Option<K>,
+    item: Option<T>,
+}
+
+impl<K: Hash + Eq + Clone, T, S: BuildHasher> Lease<'_, K, T, S> {
+    pub fn key(&self) -> &K {
+        self.key.as_ref().unwrap()
+    }
+
+    pub fn item(&self) -> &T {
+        self.item.as_ref().unwrap()
+    }
+
+    pub fn item_mut(&mut self) -> &mut T {
+        self.item.as_mut().unwrap()
+    }
+
+    // Releases the key and returns the item. Only use this once the item
+    // has been processed; after it the key's next item may be popped.
+    pub fn into_item(mut self) -> T {
+        self.item.take().unwrap()
+    }
+}
+
+impl<K: Hash + Eq + Clone, T, S: BuildHasher> Drop for Lease<'_, K, T, S> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.queue.release(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn items_of_a_key_stay_in_order() {
+        let queue = KeyedOrderedQueue::with_shards(4);
+        for i in 0..100 {
+            queue.push(i % 5, i).unwrap();
+        }
+        queue.close();
+        let mut seen: HashMap<u32, Vec<u32>> = HashMap::new();
+        while let Some(lease) = queue.pop() {
+            seen.entry(*lease.key()).or_default().push(lease.into_item());
+        }
+        assert_eq!(seen.len(), 5);
+        for (key, items) in seen {
+            let expected: Vec<u32> = (0..100).filter(|i| i % 5 == key).collect();
+            assert_eq!(items, expected);
+        }
+    }
+
+    #[test]
+    fn a_leased_key_is_held_until_released() {
+        // One shard, so keys become ready in push order.
+        let queue = KeyedOrderedQueue::with_shards(1);
+        queue.push("a", 1).unwrap();
+        queue.push("a", 2).unwrap();
+        queue.push("b", 3).unwrap();
+
+        let first = queue.try_pop().unwrap();
+        let other = queue.try_pop().unwrap();
+        assert_eq!((*first.key(), *other.key()), ("a", "b"));
+        // The second item of "a" waits for the lease on the first.
+        assert!(queue.try_pop().is_none());
+        assert_eq!(queue.len(), 1);
+        drop(other);
+        assert_eq!(queue.active_keys(), 1);
+        drop(first);
+        let second = queue.try_pop().unwrap();
+        assert_eq!(second.into_item(), 2);
+        assert!(queue.is_empty());
+        assert_eq!(queue.active_keys(), 0);
+    }
+
+    #[test]
+    fn close_drains_then_ends_every_consumer() {
+        let queue = Arc::new(KeyedOrderedQueue::new());
+        let consumers: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    let mut count = 0;
+                    while let Some(lease) = queue.pop() {
+                        drop(lease);
+                        count += 1;
+                    }
+                    count
+                })
+            })
+            .collect();
+        for i in 0..1000 {
+            queue.push(i % 7, i).unwrap();
+        }
+        queue.close();
+        assert_eq!(queue.push(0, 0), Err(0));
+        let total: usize = consumers.into_iter().map(|c| c.join().unwrap()).sum();
+        assert_eq!(total, 1000);
+        assert!(queue.is_empty());
+        assert_eq!(queue.ready_keys.load(Ordering::SeqCst), 0);
+    }
+}
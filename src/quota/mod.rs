@@ -0,0 +1,233 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::time::{Duration, Instant};
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::shard;
+use crate::sync::Mutex;
+
+const DEFAULT_SHARDS: usize = 16;
+// Shards below this many keys are never swept on write.
+const MIN_SWEEP: usize = 8;
+
+// How consumed quota comes back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefillPolicy {
+    // Every key gets `quota` units per `window`; unused units do not carry
+    // over into the next window.
+    FixedWindow { quota: u64, window: Duration },
+    // Every key holds at most `capacity` units and gets `refill` of them back
+    // every `every`.
+    TokenBucket {
+        capacity: u64,
+        refill: u64,
+        every: Duration,
+    },
+}
+
+impl RefillPolicy {
+    fn period(&self) -> Duration {
+        match *self {
+            RefillPolicy::FixedWindow { window, .. } => window,
+            RefillPolicy::TokenBucket { every, .. } => every,
+        }
+    }
+
+    fn full(&self) -> u64 {
+        match *self {
+            RefillPolicy::FixedWindow { quota, .. } => quota,
+            RefillPolicy::TokenBucket { capacity, .. } => capacity,
+        }
+    }
+}
+
+struct Quota {
+    remaining: u64,
+    // Period in which `remaining` was last brought up to date.
+    tick: u64,
+}
+
+impl Quota {
+    fn refill(&mut self, policy: &RefillPolicy, tick: u64) {
+        if tick <= self.tick {
+            return;
+        }
+        self.remaining = match *policy {
+            RefillPolicy::FixedWindow { quota, .. } => quota,
+            RefillPolicy::TokenBucket {
+                capacity, refill, ..
+            } => (tick - self.tick)
+                .saturating_mul(refill)
+                .saturating_add(self.remaining)
+                .min(capacity),
+        };
+        self.tick = tick;
+    }
+}
+
+struct Shard<K> {
+    quotas: HashMap<K, Quota>,
+    last_sweep: u64,
+    // Key count at which the next write sweeps out refilled keys.
+    sweep_at: usize,
+}
+
+// Remaining quota per key, e.g. for multi-tenant request or byte budgets.
+// Keys start with a full quota and are refilled lazily when touched, so no
+// timer thread is needed. A key whose quota is full again is
+// indistinguishable from one never seen, and is dropped lazily: a shard
+// sweeps itself when a write finds it twice as large as after its previous
+// sweep, so the cost of sweeping stays constant per write.
+pub struct QuotaMap<K, S = RandomState> {
+    shards: Vec<Mutex<Shard<K>>>,
+    hasher: S,
+    policy: RefillPolicy,
+    period_nanos: u64,
+    start: Instant,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq> QuotaMap<K> {
+    pub fn new(policy: RefillPolicy) -> Self {
+        Self::with_shards(policy, DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(policy: RefillPolicy, num_shards: usize) -> Self {
+        Self::with_shards_and_hasher(policy, num_shards, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, S: BuildHasher> QuotaMap<K, S> {
+    pub fn with_shards_and_hasher(policy: RefillPolicy, num_shards: usize, hasher: S) -> Self {
+        QuotaMap {
+            shards: (0..shard::shard_count(num_shards))
+                .map(|_| {
+                    Mutex::new(Shard {
+                        quotas: HashMap::new(),
+                        last_sweep: 0,
+                        sweep_at: MIN_SWEEP,
+                    })
+                })
+                .collect(),
+            hasher,
+            policy,
+            period_nanos: policy.period().as_nanos().max(1) as u64,
+            start: Instant::now(),
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    pub fn policy(&self) -> RefillPolicy {
+        self.policy
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<Shard<K>> {
+        let index = shard::shard_index(self.hasher.hash_one(key), self.shards.len());
+        &self.shards[index]
+    }
+
+    fn tick(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64 / self.period_nanos
+    }
+
+    fn sweep(&self, shard: &mut Shard<K>, tick: u64) {
+        // Nothing refills within a period, so a second sweep in the same
+        // one would find nothing to drop.
+        if shard.quotas.len() >= shard.sweep_at && tick > shard.last_sweep {
+            let (policy, full) = (&self.policy, self.policy.full());
+            shard.quotas.retain(|_, q| {
+                q.refill(policy, tick);
+                q.remaining < full
+            });
+            shard.last_sweep = tick;
+            shard.sweep_at = (shard.quotas.len() * 2).max(MIN_SWEEP);
+        }
+    }
+
+    // Takes `n` units from `key` if it has at least that many left. Either
+    // all `n` are consumed or none are.
+    pub fn try_consume(&self, key: K, n: u64) -> bool {
+        let tick = self.tick();
+        let mut shard = self.shard(&key).lock().unwrap();
+        self.sweep(&mut shard, tick);
+        let full = self.policy.full();
+        let quota = shard.quotas.entry(key).or_insert(Quota {
+            remaining: full,
+            tick,
+        });
+        quota.refill(&self.policy, tick);
+        if quota.remaining < n {
+            return false;
+        }
+        quota.remaining -= n;
+        true
+    }
+
+    pub fn remaining(&self, key: &K) -> u64 {
+        let tick = self.tick();
+        let mut shard = self.shard(key).lock().unwrap();
+        match shard.quotas.get_mut(key) {
+            Some(quota) => {
+                quota.refill(&self.policy, tick);
+                quota.remaining
+            }
+            None => self.policy.full(),
+        }
+    }
+
+    // Gives `key` its full quota back.
+    pub fn reset(&self, key: &K) {
+        self.shard(key).lock().unwrap().quotas.remove(key);
+    }
+
+    // Number of keys with less than a full quota, including keys refilled
+    // since but not yet dropped.
+    pub fn tracked_keys(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().quotas.len())
+            .sum()
+    }
+}
+
+impl<K: Hash + Eq + Send, S: BuildHasher + Send + Sync> Diagnostics for QuotaMap<K, S> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "QuotaMap"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.tracked_keys())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn refilled_keys_are_swept_once_the_shard_grows() {
+        let policy = RefillPolicy::TokenBucket {
+            capacity: 10,
+            refill: 10,
+            every: Duration::from_millis(20),
+        };
+        let quotas = QuotaMap::with_shards(policy, 1);
+        for key in 0..100 {
+            assert!(quotas.try_consume(key, 1));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(quotas.try_consume(100, 1));
+        assert_eq!(quotas.tracked_keys(), 1);
+        assert_eq!(quotas.remaining(&100), 9);
+    }
+}
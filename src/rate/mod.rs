@@ -0,0 +1,203 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::time::{Duration, Instant};
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::shard;
+use crate::sync::Mutex;
+
+const DEFAULT_SHARDS: usize = 16;
+
+// Ring of per-bucket counters covering one window. Each slot remembers the
+// tick it was last written in, so stale slots are recognised and reset
+// lazily instead of being cleared by a timer.
+struct Window {
+    counts: Vec<u64>,
+    ticks: Vec<u64>,
+    last_tick: u64,
+}
+
+impl Window {
+    fn new(buckets: usize) -> Self {
+        Window {
+            counts: vec![0; buckets],
+            ticks: vec![0; buckets],
+            last_tick: 0,
+        }
+    }
+
+    fn add(&mut self, tick: u64, n: u64) {
+        let slot = (tick % self.counts.len() as u64) as usize;
+        if self.ticks[slot] != tick {
+            self.ticks[slot] = tick;
+            self.counts[slot] = 0;
+        }
+        self.counts[slot] += n;
+        self.last_tick = tick;
+    }
+
+    fn total(&self, tick: u64) -> u64 {
+        let buckets = self.counts.len() as u64;
+        self.counts
+            .iter()
+            .zip(self.ticks.iter())
+            .filter(|(_, &t)| t <= tick && tick - t < buckets)
+            .map(|(&c, _)| c)
+            .sum()
+    }
+
+    fn is_idle(&self, tick: u64) -> bool {
+        tick.saturating_sub(self.last_tick) >= self.counts.len() as u64
+    }
+}
+
+struct Shard<K> {
+    windows: HashMap<K, Window>,
+    last_sweep: u64,
+}
+
+// Sliding-window event counter per key, e.g. for per-client throttling.
+// The window is split into `buckets` sub-intervals; counts older than the
+// window fall out one bucket at a time. Keys with no events for a whole
+// window are evicted lazily while their shard is being written.
+pub struct KeyedRateCounter<K, S = RandomState> {
+    shards: Vec<Mutex<Shard<K>>>,
+    hasher: S,
+    bucket_nanos: u64,
+    buckets: usize,
+    start: Instant,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq> KeyedRateCounter<K> {
+    pub fn new(window: Duration, buckets: usize) -> Self {
+        Self::with_shards(window, buckets, DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(window: Duration, buckets: usize, num_shards: usize) -> Self {
+        Self::with_shards_and_hasher(window, buckets, num_shards, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, S: BuildHasher> KeyedRateCounter<K, S> {
+    pub fn with_shards_and_hasher(
+        window: Duration,
+        buckets: usize,
+        num_shards: usize,
+        hasher: S,
+    ) -> Self {
+        assert!(buckets > 0, "buckets must be non-zero");
+        let bucket_nanos = (window.as_nanos() / buckets as u128).max(1) as u64;
+        KeyedRateCounter {
+            shards: (0..shard::shard_count(num_shards))
+                .map(|_| {
+                    Mutex::new(Shard {
+                        windows: HashMap::new(),
+                        last_sweep: 0,
+                    })
+                })
+                .collect(),
+            hasher,
+            bucket_nanos,
+            buckets,
+            start: Instant::now(),
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<Shard<K>> {
+        let index = shard::shard_index(self.hasher.hash_one(key), self.shards.len());
+        &self.shards[index]
+    }
+
+    fn tick(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64 / self.bucket_nanos
+    }
+
+    fn sweep(&self, shard: &mut Shard<K>, tick: u64) {
+        if tick.saturating_sub(shard.last_sweep) >= self.buckets as u64 {
+            shard.windows.retain(|_, w| !w.is_idle(tick));
+            shard.last_sweep = tick;
+        }
+    }
+
+    // Records one event for `key` and returns the count in the current window.
+    pub fn record(&self, key: K) -> u64 {
+        self.record_n(key, 1)
+    }
+
+    pub fn record_n(&self, key: K, n: u64) -> u64 {
+        let tick = self.tick();
+        let mut shard = self.shard(&key).lock().unwrap();
+        self.sweep(&mut shard, tick);
+        let buckets = self.buckets;
+        let window = shard
+            .windows
+            .entry(key)
+            .or_insert_with(|| Window::new(buckets));
+        window.add(tick, n);
+        window.total(tick)
+    }
+
+    // Records an event only if doing so keeps the key within `limit` events
+    // per window. Returns whether the event was admitted.
+    pub fn try_acquire(&self, key: K, limit: u64) -> bool {
+        let tick = self.tick();
+        let mut shard = self.shard(&key).lock().unwrap();
+        self.sweep(&mut shard, tick);
+        let buckets = self.buckets;
+        let window = shard
+            .windows
+            .entry(key)
+            .or_insert_with(|| Window::new(buckets));
+        if window.total(tick) >= limit {
+            return false;
+        }
+        window.add(tick, 1);
+        true
+    }
+
+    pub fn count(&self, key: &K) -> u64 {
+        let tick = self.tick();
+        let shard = self.shard(key).lock().unwrap();
+        shard.windows.get(key).map_or(0, |w| w.total(tick))
+    }
+
+    // Number of keys currently tracked, including idle keys not yet evicted.
+    pub fn tracked_keys(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| s.lock().unwrap().windows.len())
+            .sum()
+    }
+
+    // Eagerly drops every key without events in the last window, one shard
+    // at a time.
+    pub fn evict_idle(&self) {
+        let tick = self.tick();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.windows.retain(|_, w| !w.is_idle(tick));
+            shard.last_sweep = tick;
+        }
+    }
+}
+
+impl<K: Hash + Eq + Send, S: BuildHasher + Send + Sync> Diagnostics for KeyedRateCounter<K, S> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "KeyedRateCounter"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.tracked_keys())
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::sync::atomic::Ordering;
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::AtomicU64;
+
+// Windows are compared as signed 32-bit stamp distances.
+const MAX_WINDOW: usize = i32::MAX as usize;
+
+// Approximate "was this key among the last `window` inserts" filter, meant as
+// a cheap dedup step in front of a queue.
+//
+// Keys are hashed into a table with at least four slots per key in the
+// window. Each slot is a single atomic word holding a 32-bit fingerprint and
+// the 32-bit sequence number (stamp) of the insert that wrote it, so every
+// operation is a fixed number of loads and stores plus one `fetch_add`:
+// wait-free, no locks.
+//
+// Answers are approximate in both directions. Two keys with the same
+// fingerprint in the same slot cause a false positive (roughly 2^-31 per
+// probe). A slot overwritten by another key before the window has passed
+// causes a false negative, so a duplicate occasionally gets through; each key
+// has two candidate slots and inserts overwrite the older one to keep that
+// rare.
+pub struct RecentSet<K, S = RandomState> {
+    slots: Box<[AtomicU64]>,
+    mask: usize,
+    window: u32,
+    sequence: AtomicU64,
+    hasher: S,
+    _marker: PhantomData<fn(&K)>,
+    id: StructureId,
+}
+
+fn pack(fingerprint: u32, stamp: u32) -> u64 {
+    (fingerprint as u64) << 32 | stamp as u64
+}
+
+fn unpack(word: u64) -> (u32, u32) {
+    ((word >> 32) as u32, word as u32)
+}
+
+impl<K: Hash> RecentSet<K> {
+    pub fn new(window: usize) -> Self {
+        Self::with_hasher(window, RandomState::new())
+    }
+}
+
+impl<K: Hash, S: BuildHasher> RecentSet<K, S> {
+    pub fn with_hasher(window: usize, hasher: S) -> Self {
+        assert!(window > 0, "window must be non-zero");
+        assert!(window <= MAX_WINDOW, "window must fit in 31 bits");
+        let len = (window * 4).next_power_of_two();
+        RecentSet {
+            slots: (0..len).map(|_| AtomicU64::new(0)).collect(),
+            mask: len - 1,
+            window: window as u32,
+            sequence: AtomicU64::new(0),
+            hasher,
+            _marker: PhantomData,
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    // Fingerprint (never zero, so empty slots never match) and the two
+    // candidate slots for `key`.
+    fn probe(&self, key: &K) -> (u32, usize, usize) {
+        let hash = self.hasher.hash_one(key);
+        let fingerprint = (hash >> 32) as u32 | 1;
+        let first = hash as usize & self.mask;
+        let second = (first ^ (fingerprint as usize).wrapping_mul(0x9e37_79b9)) & self.mask;
+        (fingerprint, first, second)
+    }
+
+    // Signed distance from `stamp` to the current sequence number. Stamps
+    // written by inserts racing with this read come out negative.
+    fn age(&self, stamp: u32) -> i32 {
+        (self.sequence.load(Ordering::Relaxed) as u32).wrapping_sub(stamp) as i32
+    }
+
+    fn is_recent(&self, word: u64, fingerprint: u32) -> bool {
+        let (found, stamp) = unpack(word);
+        found == fingerprint && self.age(stamp) <= self.window as i32
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        let (fingerprint, first, second) = self.probe(key);
+        [first, second]
+            .iter()
+            .any(|&i| self.is_recent(self.slots[i].load(Ordering::Relaxed), fingerprint))
+    }
+
+    // Records `key` as seen. Returns true if it was not already among the
+    // recent inserts, i.e. the caller should go ahead and process it.
+    pub fn insert(&self, key: &K) -> bool {
+        let (fingerprint, first, second) = self.probe(key);
+        let words = [
+            self.slots[first].load(Ordering::Relaxed),
+            self.slots[second].load(Ordering::Relaxed),
+        ];
+        let seen = words.iter().any(|&w| self.is_recent(w, fingerprint));
+
+        // Refresh the slot already holding this key, otherwise take the one
+        // written longest ago.
+        let target = if unpack(words[1]).0 == fingerprint
+            || (unpack(words[0]).0 != fingerprint
+                && self.age(unpack(words[1]).1) > self.age(unpack(words[0]).1))
+        {
+            second
+        } else {
+            first
+        };
+        let stamp = self.sequence.fetch_add(1, Ordering::Relaxed) as u32;
+        self.slots[target].store(pack(fingerprint, stamp), Ordering::Relaxed);
+        !seen
+    }
+}
+
+impl<K: Hash, S: BuildHasher + Send + Sync> Diagnostics for RecentSet<K, S> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "RecentSet"
+    }
+
+    fn size(&self) -> Option<usize> {
+        None
+    }
+}
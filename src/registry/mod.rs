@@ -0,0 +1,102 @@
+// Structure IDs and an opt-in process-wide registry for diagnostics.
+//
+// Every structure gets a `StructureId` when it is constructed. Structures
+// shared through an `Arc` can additionally be registered; the registry only
+// keeps weak references, so it never extends a structure's lifetime, and
+// `dump_all_structures` reports whatever is still alive.
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+
+use crate::sync::{AtomicU64, Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StructureId(u64);
+
+impl StructureId {
+    pub(crate) fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        StructureId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for StructureId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+pub trait Diagnostics: Send + Sync {
+    fn id(&self) -> StructureId;
+
+    // Short type name used in reports, e.g. "Queue".
+    fn kind(&self) -> &'static str;
+
+    // Number of elements held, for structures that can count them cheaply.
+    fn size(&self) -> Option<usize>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructureInfo {
+    pub id: StructureId,
+    pub kind: &'static str,
+    pub size: Option<usize>,
+}
+
+impl fmt::Display for StructureInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.size {
+            Some(size) => write!(f, "{} {} size={}", self.id, self.kind, size),
+            None => write!(f, "{} {}", self.id, self.kind),
+        }
+    }
+}
+
+static REGISTRY: Mutex<Vec<Weak<dyn Diagnostics>>> = Mutex::new(Vec::new());
+
+fn registry() -> MutexGuard<'static, Vec<Weak<dyn Diagnostics>>> {
+    // Entries are only pushed and pruned, so a poisoned list is still valid.
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Adds `structure` to the registry until its last `Arc` is dropped.
+pub fn register<T: Diagnostics + 'static>(structure: &Arc<T>) -> StructureId {
+    let weak = Arc::downgrade(structure) as Weak<dyn Diagnostics>;
+    let mut entries = registry();
+    entries.retain(|e| e.strong_count() > 0);
+    entries.push(weak);
+    structure.id()
+}
+
+// Snapshot of every registered structure that is still alive, by ID.
+pub fn registered_structures() -> Vec<StructureInfo> {
+    // Upgrade under the lock but query sizes after releasing it: `size` may
+    // take the structure's own locks.
+    let live: Vec<Arc<dyn Diagnostics>> = {
+        let mut entries = registry();
+        entries.retain(|e| e.strong_count() > 0);
+        entries.iter().filter_map(Weak::upgrade).collect()
+    };
+    let mut infos: Vec<StructureInfo> = live
+        .iter()
+        .map(|s| StructureInfo {
+            id: s.id(),
+            kind: s.kind(),
+            size: s.size(),
+        })
+        .collect();
+    infos.sort_by_key(|info| info.id);
+    infos
+}
+
+// One line per registered structure that is still alive.
+pub fn dump_all_structures() -> String {
+    registered_structures()
+        .iter()
+        .map(|info| format!("{info}\n"))
+        .collect()
+}
@@ -0,0 +1,640 @@
+// Operation recording for reproducible bug reports.
+//
+// A `Recorder` wraps a `Queue` or `LockFreeHashMap` and logs every operation
+// with the recording thread, an invocation and a completion sequence number,
+// and fingerprints of the keys and values involved. The trace is a compact
+// binary log; `decode` reads it back and `replay` re-executes it on a single
+// thread against a sequential model, reporting the first operation whose
+// result the model disagrees with.
+//
+// Operations are replayed in completion order. Two operations whose
+// invocation/completion intervals overlap may have taken effect in the other
+// order, so a divergence flagged as `overlapping` is a lead, not a proof; one
+// between operations that did not overlap is a real bug in the structure.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::lockfree_map::LockFreeHashMap;
+use crate::queue::Queue;
+use crate::sync::{AtomicU64, AtomicUsize, Mutex};
+
+const MAGIC: &[u8; 4] = b"cdsr";
+const VERSION: u8 = 1;
+
+const TAG_ENQUEUE: u8 = 0;
+const TAG_DEQUEUE: u8 = 1;
+const TAG_INSERT: u8 = 2;
+const TAG_REMOVE: u8 = 3;
+const TAG_GET: u8 = 4;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Small dense thread numbers keep the trace short; `ThreadId` has no
+    // stable integer form.
+    static THREAD: u32 = NEXT_THREAD.fetch_add(1, Ordering::Relaxed) as u32;
+}
+
+// Keys and values are recorded as 64-bit fingerprints. `DefaultHasher::new`
+// uses fixed keys, so equal values fingerprint the same in every run.
+fn fingerprint<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Enqueue { value: u64 },
+    Dequeue { result: Option<u64> },
+    // `inserted` is false when the key was already present.
+    Insert { key: u64, value: u64, inserted: bool },
+    Remove { key: u64, result: Option<u64> },
+    Get { key: u64, result: Option<u64> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    // Which wrapped structure of the recorder the operation ran on.
+    pub target: u32,
+    pub thread: u32,
+    // Sequence numbers taken just before the operation started and just
+    // after it returned; they are unique across the whole recorder.
+    pub start: u64,
+    pub end: u64,
+    pub op: Op,
+}
+
+impl Record {
+    fn overlaps(&self, other: &Record) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let tag = match self.op {
+            Op::Enqueue { .. } => TAG_ENQUEUE,
+            Op::Dequeue { .. } => TAG_DEQUEUE,
+            Op::Insert { .. } => TAG_INSERT,
+            Op::Remove { .. } => TAG_REMOVE,
+            Op::Get { .. } => TAG_GET,
+        };
+        out.push(tag);
+        put_varint(out, self.target as u64);
+        put_varint(out, self.thread as u64);
+        put_varint(out, self.start);
+        // Stored as a delta: most operations complete a few steps later.
+        put_varint(out, self.end - self.start);
+        match self.op {
+            Op::Enqueue { value } => put_u64(out, value),
+            Op::Dequeue { result } => put_option(out, result),
+            Op::Insert { key, value, inserted } => {
+                put_u64(out, key);
+                put_u64(out, value);
+                out.push(inserted as u8);
+            }
+            Op::Remove { key, result } | Op::Get { key, result } => {
+                put_u64(out, key);
+                put_option(out, result);
+            }
+        }
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn put_u64(out: &mut Vec<u8>, n: u64) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_option(out: &mut Vec<u8>, n: Option<u64>) {
+    match n {
+        Some(n) => {
+            out.push(1);
+            put_u64(out, n);
+        }
+        None => out.push(0),
+    }
+}
+
+struct Inner {
+    seq: AtomicU64,
+    targets: AtomicUsize,
+    trace: Mutex<Vec<u8>>,
+}
+
+// Cloning yields another handle to the same trace.
+//
+// Appending a record takes a short lock after the operation has returned, so
+// recording slows the structures down but does not serialize them.
+#[derive(Clone)]
+pub struct Recorder {
+    inner: Arc<Inner>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        let mut trace = MAGIC.to_vec();
+        trace.push(VERSION);
+        Recorder {
+            inner: Arc::new(Inner {
+                seq: AtomicU64::new(0),
+                targets: AtomicUsize::new(0),
+                trace: Mutex::new(trace),
+            }),
+        }
+    }
+
+    pub fn queue<T: Hash>(&self, queue: Queue<T>) -> RecordedQueue<T> {
+        RecordedQueue {
+            queue,
+            target: self.next_target(),
+            recorder: self.clone(),
+        }
+    }
+
+    pub fn map<K, V, S>(&self, map: LockFreeHashMap<K, V, S>) -> RecordedMap<K, V, S>
+    where
+        K: Hash + Eq,
+        V: Hash,
+        S: BuildHasher,
+    {
+        RecordedMap {
+            map,
+            target: self.next_target(),
+            recorder: self.clone(),
+        }
+    }
+
+    fn next_target(&self) -> u32 {
+        self.inner.targets.fetch_add(1, Ordering::Relaxed) as u32
+    }
+
+    fn begin(&self) -> u64 {
+        self.inner.seq.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn finish(&self, target: u32, start: u64, op: Op) {
+        let record = Record {
+            target,
+            thread: THREAD.with(|t| *t),
+            start,
+            end: self.inner.seq.fetch_add(1, Ordering::SeqCst),
+            op,
+        };
+        let mut trace = self.inner.trace.lock().unwrap_or_else(|e| e.into_inner());
+        record.encode(&mut trace);
+    }
+
+    // A copy of the trace recorded so far.
+    pub fn trace(&self) -> Vec<u8> {
+        self.inner.trace.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&self.trace())
+    }
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A `Queue` whose operations are logged to a `Recorder`.
+pub struct RecordedQueue<T> {
+    queue: Queue<T>,
+    target: u32,
+    recorder: Recorder,
+}
+
+impl<T: Hash> RecordedQueue<T> {
+    pub fn enqueue(&self, value: T) {
+        let start = self.recorder.begin();
+        let op = Op::Enqueue {
+            value: fingerprint(&value),
+        };
+        self.queue.enqueue(value);
+        self.recorder.finish(self.target, start, op);
+    }
+
+    pub fn dequeue(&self) -> Option<T> {
+        let start = self.recorder.begin();
+        let value = self.queue.dequeue();
+        let op = Op::Dequeue {
+            result: value.as_ref().map(fingerprint),
+        };
+        self.recorder.finish(self.target, start, op);
+        value
+    }
+
+    pub fn inner(&self) -> &Queue<T> {
+        &self.queue
+    }
+}
+
+// A `LockFreeHashMap` whose operations are logged to a `Recorder`.
+pub struct RecordedMap<K, V, S> {
+    map: LockFreeHashMap<K, V, S>,
+    target: u32,
+    recorder: Recorder,
+}
+
+impl<K, V, S> RecordedMap<K, V, S>
+where
+    K: Hash + Eq + Send + 'static,
+    V: Hash + Send + 'static,
+    S: BuildHasher,
+{
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let start = self.recorder.begin();
+        let (key_print, value_print) = (fingerprint(&key), fingerprint(&value));
+        let inserted = self.map.insert(key, value);
+        let op = Op::Insert {
+            key: key_print,
+            value: value_print,
+            inserted,
+        };
+        self.recorder.finish(self.target, start, op);
+        inserted
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        let start = self.recorder.begin();
+        let value = self.map.remove(key);
+        let op = Op::Remove {
+            key: fingerprint(key),
+            result: value.as_ref().map(fingerprint),
+        };
+        self.recorder.finish(self.target, start, op);
+        value
+    }
+
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        let start = self.recorder.begin();
+        let value = self.map.get(key);
+        let op = Op::Get {
+            key: fingerprint(key),
+            result: value.as_ref().map(fingerprint),
+        };
+        self.recorder.finish(self.target, start, op);
+        value
+    }
+
+    pub fn inner(&self) -> &LockFreeHashMap<K, V, S> {
+        &self.map
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub offset: usize,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed trace at byte {}", self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn error(&self) -> DecodeError {
+        DecodeError { offset: self.pos }
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let b = *self.bytes.get(self.pos).ok_or(self.error())?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            n |= ((b & 0x7f) as u64) << shift;
+            if b < 0x80 {
+                return Ok(n);
+            }
+        }
+        Err(self.error())
+    }
+
+    fn u32_varint(&mut self) -> Result<u32, DecodeError> {
+        let n = self.varint()?;
+        u32::try_from(n).map_err(|_| self.error())
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        let bytes = self.bytes.get(self.pos..self.pos + 8).ok_or(self.error())?;
+        self.pos += 8;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn flag(&mut self) -> Result<bool, DecodeError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError {
+                offset: self.pos - 1,
+            }),
+        }
+    }
+
+    fn option(&mut self) -> Result<Option<u64>, DecodeError> {
+        Ok(if self.flag()? { Some(self.u64()?) } else { None })
+    }
+
+    fn record(&mut self) -> Result<Record, DecodeError> {
+        let tag_offset = self.pos;
+        let tag = self.byte()?;
+        let target = self.u32_varint()?;
+        let thread = self.u32_varint()?;
+        let start = self.varint()?;
+        let end = start.checked_add(self.varint()?).ok_or(self.error())?;
+        let op = match tag {
+            TAG_ENQUEUE => Op::Enqueue { value: self.u64()? },
+            TAG_DEQUEUE => Op::Dequeue {
+                result: self.option()?,
+            },
+            TAG_INSERT => Op::Insert {
+                key: self.u64()?,
+                value: self.u64()?,
+                inserted: self.flag()?,
+            },
+            TAG_REMOVE => Op::Remove {
+                key: self.u64()?,
+                result: self.option()?,
+            },
+            TAG_GET => Op::Get {
+                key: self.u64()?,
+                result: self.option()?,
+            },
+            _ => return Err(DecodeError { offset: tag_offset }),
+        };
+        Ok(Record {
+            target,
+            thread,
+            start,
+            end,
+            op,
+        })
+    }
+}
+
+// Parses a trace produced by `Recorder::trace` or `Recorder::write_to`.
+pub fn decode(bytes: &[u8]) -> Result<Vec<Record>, DecodeError> {
+    if bytes.len() < MAGIC.len() + 1 || &bytes[..MAGIC.len()] != MAGIC || bytes[MAGIC.len()] != VERSION
+    {
+        return Err(DecodeError { offset: 0 });
+    }
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len() + 1,
+    };
+    let mut records = Vec::new();
+    while reader.pos < bytes.len() {
+        records.push(reader.record()?);
+    }
+    Ok(records)
+}
+
+// The first operation whose recorded result the sequential model disagrees
+// with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    // Position in completion order.
+    pub index: usize,
+    pub record: Record,
+    // The operation as the model would have completed it; `None` if it does
+    // not apply to the kind of structure the target was first used as.
+    pub expected: Option<Op>,
+    // Whether the operation overlapped another one in time, which makes a
+    // different order of effects legitimate.
+    pub overlapping: bool,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "operation {} on target {} (thread {}) recorded {:?}, ",
+            self.index, self.record.target, self.record.thread, self.record.op
+        )?;
+        match self.expected {
+            Some(op) => write!(f, "model expected {:?}", op)?,
+            None => write!(f, "which does not apply to this target")?,
+        }
+        if self.overlapping {
+            write!(f, " (overlaps a concurrent operation)")?;
+        }
+        Ok(())
+    }
+}
+
+enum Model {
+    Queue(VecDeque<u64>),
+    Map(HashMap<u64, u64>),
+}
+
+impl Model {
+    fn for_op(op: &Op) -> Self {
+        match op {
+            Op::Enqueue { .. } | Op::Dequeue { .. } => Model::Queue(VecDeque::new()),
+            _ => Model::Map(HashMap::new()),
+        }
+    }
+
+    // Applies `op` and returns it with the result the model produced, or
+    // `None` if the operation does not apply to this kind of structure.
+    fn apply(&mut self, op: &Op) -> Option<Op> {
+        Some(match (self, *op) {
+            (Model::Queue(queue), Op::Enqueue { value }) => {
+                queue.push_back(value);
+                Op::Enqueue { value }
+            }
+            (Model::Queue(queue), Op::Dequeue { .. }) => Op::Dequeue {
+                result: queue.pop_front(),
+            },
+            (Model::Map(map), Op::Insert { key, value, .. }) => {
+                let inserted = !map.contains_key(&key);
+                if inserted {
+                    map.insert(key, value);
+                }
+                Op::Insert {
+                    key,
+                    value,
+                    inserted,
+                }
+            }
+            (Model::Map(map), Op::Remove { key, .. }) => Op::Remove {
+                key,
+                result: map.remove(&key),
+            },
+            (Model::Map(map), Op::Get { key, .. }) => Op::Get {
+                key,
+                result: map.get(&key).copied(),
+            },
+            _ => return None,
+        })
+    }
+}
+
+// Re-executes `records` in completion order against a sequential queue or
+// map model per target. Returns the number of operations checked.
+pub fn replay(records: &[Record]) -> Result<usize, Divergence> {
+    let mut ordered = records.to_vec();
+    ordered.sort_by_key(|r| r.end);
+    let mut models: HashMap<u32, Model> = HashMap::new();
+    for (index, record) in ordered.iter().enumerate() {
+        let model = models
+            .entry(record.target)
+            .or_insert_with(|| Model::for_op(&record.op));
+        let expected = model.apply(&record.op);
+        if expected != Some(record.op) {
+            let overlapping = ordered.iter().any(|r| r != record && r.overlaps(record));
+            return Err(Divergence {
+                index,
+                record: *record,
+                expected,
+                overlapping,
+            });
+        }
+    }
+    Ok(ordered.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_all(records: &[Record]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        for record in records {
+            record.encode(&mut bytes);
+        }
+        bytes
+    }
+
+    #[test]
+    fn varints_use_seven_bits_per_byte() {
+        for (n, len) in [(0, 1), (127, 1), (128, 2), (16383, 2), (16384, 3), (u64::MAX, 10)] {
+            let mut out = Vec::new();
+            put_varint(&mut out, n);
+            assert_eq!(out.len(), len, "{n}");
+            let mut reader = Reader { bytes: &out, pos: 0 };
+            assert_eq!(reader.varint(), Ok(n));
+        }
+    }
+
+    #[test]
+    fn records_round_trip_at_the_edges() {
+        let record = |target, thread, start, end, op| Record {
+            target,
+            thread,
+            start,
+            end,
+            op,
+        };
+        let records = [
+            record(0, 0, 0, 0, Op::Enqueue { value: 0 }),
+            record(127, 128, 16383, 16384, Op::Dequeue { result: None }),
+            record(u32::MAX, u32::MAX, 0, u64::MAX, Op::Dequeue { result: Some(u64::MAX) }),
+            record(1, 2, u64::MAX, u64::MAX, Op::Insert { key: u64::MAX, value: 0, inserted: false }),
+            record(1, 2, 3, 4, Op::Insert { key: 5, value: 6, inserted: true }),
+            record(1, 2, 3, 4, Op::Remove { key: 7, result: Some(8) }),
+            record(1, 2, 3, 4, Op::Get { key: 9, result: None }),
+        ];
+        let bytes = encode_all(&records);
+        assert_eq!(decode(&bytes).unwrap(), records);
+
+        // Every truncation fails at or before the cut, never past it.
+        for cut in MAGIC.len() + 2..bytes.len() {
+            if let Err(e) = decode(&bytes[..cut]) {
+                assert!(e.offset <= cut);
+            }
+        }
+        assert_eq!(decode(&bytes[..bytes.len() - 1]), Err(DecodeError { offset: bytes.len() - 1 }));
+        assert_eq!(decode(b"cdsr\x02"), Err(DecodeError { offset: 0 }));
+        let mut bad_tag = encode_all(&records[..1]);
+        bad_tag[MAGIC.len() + 1] = 9;
+        assert_eq!(decode(&bad_tag), Err(DecodeError { offset: MAGIC.len() + 1 }));
+    }
+
+    // Runs a few operations on one recorded queue and one recorded map, from
+    // a single thread.
+    fn sequential_trace() -> Vec<u8> {
+        let recorder = Recorder::new();
+        let queue = recorder.queue(Queue::new());
+        let map = recorder.map(LockFreeHashMap::new());
+        queue.enqueue("a");
+        queue.enqueue("b");
+        assert_eq!(queue.dequeue(), Some("a"));
+        assert!(map.insert(1, "one"));
+        assert!(!map.insert(1, "uno"));
+        assert_eq!(map.get(&1), Some("one"));
+        assert_eq!(queue.dequeue(), Some("b"));
+        assert_eq!(queue.dequeue(), None);
+        assert_eq!(map.remove(&1), Some("one"));
+        assert_eq!(map.get(&1), None);
+        recorder.trace()
+    }
+
+    #[test]
+    fn a_sequential_trace_replays_cleanly() {
+        let records = decode(&sequential_trace()).unwrap();
+        assert_eq!(records.len(), 10);
+        assert_eq!(replay(&records), Ok(10));
+    }
+
+    #[test]
+    fn a_tampered_trace_reports_the_divergence() {
+        let mut records = decode(&sequential_trace()).unwrap();
+        // The second dequeue claims to have returned "a" again.
+        let second = records
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| matches!(r.op, Op::Dequeue { result: Some(_) }))
+            .map(|(i, _)| i)
+            .nth(1)
+            .unwrap();
+        let first_result = match records[2].op {
+            Op::Dequeue { result } => result,
+            op => panic!("unexpected {op:?}"),
+        };
+        let honest = records[second].op;
+        records[second].op = Op::Dequeue { result: first_result };
+
+        let tampered = decode(&encode_all(&records)).unwrap();
+        let divergence = replay(&tampered).unwrap_err();
+        assert_eq!(divergence.index, second);
+        assert_eq!(divergence.expected, Some(honest));
+        assert!(!divergence.overlapping);
+        assert!(divergence.to_string().contains("model expected"));
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::Ordering;
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+
+use crate::registry::{Diagnostics, StructureId};
+
+// Upper bound on displacement attempts for a single bucket before the table
+// is rebuilt with more slots.
+const MAX_DISPLACEMENT: u64 = 1 << 16;
+// Number of times the slot count may double before `build` gives up. Real
+// key sets fit long before this; running out means two distinct keys hash
+// identically under every seed, and no table size would separate them.
+const MAX_GROWTH: u32 = 6;
+
+fn seeded_hash<K: Hash + ?Sized>(key: &K, seed: u64) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Immutable table built with hash-and-displace: every key lands in its own
+// slot, so a lookup is two hashes and a single key comparison.
+pub struct Table<K, V> {
+    displacements: Vec<u64>,
+    slots: Vec<Option<(K, V)>>,
+    len: usize,
+}
+
+impl<K: Hash + Eq, V> Table<K, V> {
+    pub fn get(&self, key: &K) -> Option<&V> {
+        if self.len == 0 {
+            return None;
+        }
+        let bucket = (seeded_hash(key, 0) % self.displacements.len() as u64) as usize;
+        let seed = self.displacements[bucket];
+        let slot = (seeded_hash(key, seed) % self.slots.len() as u64) as usize;
+        match &self.slots[slot] {
+            Some((k, v)) if k == key => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.slots.iter().flatten().map(|(k, v)| (k, v))
+    }
+}
+
+pub struct RoutingTableBuilder<K, V> {
+    entries: HashMap<K, V>,
+}
+
+impl<K: Hash + Eq, V> RoutingTableBuilder<K, V> {
+    pub fn new() -> Self {
+        RoutingTableBuilder {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        RoutingTableBuilder {
+            entries: HashMap::with_capacity(capacity),
+        }
+    }
+
+    // Later inserts for the same key replace earlier ones.
+    pub fn insert(&mut self, key: K, value: V) -> &mut Self {
+        self.entries.insert(key, value);
+        self
+    }
+
+    pub fn build(self) -> Result<Table<K, V>, BuildError> {
+        let entries: Vec<(K, V)> = self.entries.into_iter().collect();
+        let len = entries.len();
+        let mut num_slots = len + len / 4 + 1;
+
+        for growth in 0..=MAX_GROWTH {
+            if growth > 0 {
+                num_slots *= 2;
+            }
+            if let Some((displacements, order)) = Self::place(&entries, num_slots) {
+                let mut slots: Vec<Option<(K, V)>> = (0..num_slots).map(|_| None).collect();
+                for ((k, v), slot) in entries.into_iter().zip(order) {
+                    slots[slot] = Some((k, v));
+                }
+                return Ok(Table {
+                    displacements,
+                    slots,
+                    len,
+                });
+            }
+        }
+        Err(BuildError { len, num_slots })
+    }
+
+    // Returns the per-bucket displacement seeds and the slot chosen for each
+    // entry, or None if some bucket could not be placed.
+    fn place(entries: &[(K, V)], num_slots: usize) -> Option<(Vec<u64>, Vec<usize>)> {
+        let num_buckets = entries.len() / 4 + 1;
+        let mut buckets: Vec<Vec<usize>> = vec![Vec::new(); num_buckets];
+        for (i, (k, _)) in entries.iter().enumerate() {
+            buckets[(seeded_hash(k, 0) % num_buckets as u64) as usize].push(i);
+        }
+
+        // Place the largest buckets first while the table is still sparse.
+        let mut bucket_order: Vec<usize> = (0..num_buckets).collect();
+        bucket_order.sort_by_key(|&b| std::cmp::Reverse(buckets[b].len()));
+
+        let mut displacements = vec![0u64; num_buckets];
+        let mut taken = vec![false; num_slots];
+        let mut order = vec![0usize; entries.len()];
+        let mut candidate = Vec::new();
+
+        for b in bucket_order {
+            if buckets[b].is_empty() {
+                continue;
+            }
+            let mut seed = 1;
+            loop {
+                if seed > MAX_DISPLACEMENT {
+                    return None;
+                }
+                candidate.clear();
+                let fits = buckets[b].iter().all(|&i| {
+                    let slot = (seeded_hash(&entries[i].0, seed) % num_slots as u64) as usize;
+                    if taken[slot] || candidate.contains(&slot) {
+                        return false;
+                    }
+                    candidate.push(slot);
+                    true
+                });
+                if fits {
+                    break;
+                }
+                seed += 1;
+            }
+            displacements[b] = seed;
+            for (&i, &slot) in buckets[b].iter().zip(candidate.iter()) {
+                taken[slot] = true;
+                order[i] = slot;
+            }
+        }
+
+        Some((displacements, order))
+    }
+}
+
+// Returned by `RoutingTableBuilder::build` when the keys could not be placed
+// even in the largest table it tries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildError {
+    pub len: usize,
+    // Slot count of the last table tried.
+    pub num_slots: usize,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "could not place {} keys in {} slots; two keys may hash identically",
+            self.len, self.num_slots
+        )
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+impl<K: Hash + Eq, V> Default for RoutingTableBuilder<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq, V> FromIterator<(K, V)> for RoutingTableBuilder<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        RoutingTableBuilder {
+            entries: iter.into_iter().collect(),
+        }
+    }
+}
+
+// Read-mostly routing table. Readers pin the current epoch and look up the
+// published table without locking; writers build a complete new table off to
+// the side and swap it in, and the old one is freed once no pinned reader can
+// still observe it.
+pub struct RoutingTable<K, V> {
+    current: Atomic<Table<K, V>>,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq, V> RoutingTable<K, V> {
+    pub fn new() -> Self {
+        Self::from_table(
+            RoutingTableBuilder::new()
+                .build()
+                .expect("an empty table always builds"),
+        )
+    }
+
+    pub fn from_table(table: Table<K, V>) -> Self {
+        RoutingTable {
+            current: Atomic::new(table),
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.read(|table| table.get(key).cloned())
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.read(|table| table.contains_key(key))
+    }
+
+    pub fn len(&self) -> usize {
+        self.read(|table| table.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Runs `f` against the currently published table. Every lookup made
+    // inside `f` sees the same table, even if a swap happens concurrently.
+    pub fn read<R>(&self, f: impl FnOnce(&Table<K, V>) -> R) -> R {
+        let guard = epoch::pin();
+        let table = self.current.load(Ordering::Acquire, &guard);
+        // The pointer is never null and cannot be freed while `guard` is held.
+        f(unsafe { table.deref() })
+    }
+
+    // Atomically publishes `table`; the previous table is retired through the
+    // epoch collector, which may drop it later and on another thread.
+    pub fn swap(&self, table: Table<K, V>)
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        let guard = epoch::pin();
+        let old = self
+            .current
+            .swap(Owned::new(table), Ordering::AcqRel, &guard);
+        unsafe { guard.defer_destroy(old) };
+    }
+
+    // Builds and publishes a new table. On error the current table stays
+    // published.
+    pub fn rebuild(&self, builder: RoutingTableBuilder<K, V>) -> Result<(), BuildError>
+    where
+        K: Send + 'static,
+        V: Send + 'static,
+    {
+        self.swap(builder.build()?);
+        Ok(())
+    }
+}
+
+impl<K: Hash + Eq, V> Default for RoutingTable<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Drop for RoutingTable<K, V> {
+    fn drop(&mut self) {
+        // We have exclusive access, so nobody else can be reading the table.
+        unsafe {
+            let table = self.current.load(Ordering::Relaxed, epoch::unprotected());
+            drop(table.into_owned());
+        }
+    }
+}
+
+impl<K: Hash + Eq + Send + Sync, V: Send + Sync> Diagnostics for RoutingTable<K, V> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "RoutingTable"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Distinct keys that hash identically under every seed.
+    #[derive(PartialEq, Eq)]
+    struct Colliding(u32);
+
+    impl Hash for Colliding {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            0u32.hash(state);
+        }
+    }
+
+    #[test]
+    fn build_places_every_key() {
+        let table = (0..1000)
+            .map(|i| (i, i * 2))
+            .collect::<RoutingTableBuilder<_, _>>()
+            .build()
+            .unwrap();
+        assert_eq!(table.len(), 1000);
+        assert!((0..1000).all(|i| table.get(&i) == Some(&(i * 2))));
+        assert_eq!(table.get(&1000), None);
+    }
+
+    #[test]
+    fn build_fails_on_identical_hashes() {
+        let mut builder = RoutingTableBuilder::new();
+        builder.insert(Colliding(1), ()).insert(Colliding(2), ());
+        let err = builder.build().err().unwrap();
+        assert_eq!(err.len, 2);
+    }
+
+    #[test]
+    fn failed_rebuild_keeps_current_table() {
+        let routes = RoutingTable::new();
+        let mut builder = RoutingTableBuilder::new();
+        builder.insert(Colliding(1), 1);
+        routes.rebuild(builder).unwrap();
+        let mut builder = RoutingTableBuilder::new();
+        builder.insert(Colliding(1), 1).insert(Colliding(2), 2);
+        assert!(routes.rebuild(builder).is_err());
+        assert_eq!(routes.get(&Colliding(1)), Some(1));
+        assert_eq!(routes.len(), 1);
+    }
+}
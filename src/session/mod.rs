@@ -0,0 +1,282 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::time::{Duration, Instant};
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::shard;
+use crate::sync::Mutex;
+use crate::unwind::{self, PanicPolicy, Payload};
+
+const DEFAULT_SHARDS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryReason {
+    // Not accessed within the idle timeout.
+    Idle,
+    // Older than the absolute maximum lifetime, regardless of activity.
+    Lifetime,
+}
+
+struct Entry<S> {
+    session: S,
+    created: Instant,
+    last_access: Instant,
+}
+
+type ExpiryListener<K, S> = Box<dyn Fn(&K, &S, ExpiryReason) + Send + Sync>;
+
+// Session store for web servers. Every access slides the idle deadline
+// forward, but no session outlives `max_lifetime` from its creation.
+// Expired sessions are removed lazily when they are looked up and in bulk by
+// `purge_expired`, and each removal is reported to the expiry listener after
+// the shard lock has been released.
+pub struct SessionStore<K, S> {
+    shards: Vec<Mutex<HashMap<K, Entry<S>>>>,
+    hasher: RandomState,
+    idle_timeout: Duration,
+    max_lifetime: Duration,
+    listener: Option<ExpiryListener<K, S>>,
+    panic_policy: PanicPolicy,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq, S> SessionStore<K, S> {
+    pub fn new(idle_timeout: Duration, max_lifetime: Duration) -> Self {
+        Self::with_shards(idle_timeout, max_lifetime, DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(idle_timeout: Duration, max_lifetime: Duration, num_shards: usize) -> Self {
+        SessionStore {
+            shards: (0..shard::shard_count(num_shards))
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+            idle_timeout,
+            max_lifetime,
+            listener: None,
+            panic_policy: PanicPolicy::default(),
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    pub fn with_expiry_listener(
+        mut self,
+        listener: impl Fn(&K, &S, ExpiryReason) + Send + Sync + 'static,
+    ) -> Self {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    // What happens to a session whose `with` closure panics. Either way the
+    // shard lock is released unpoisoned before the panic is resumed.
+    pub fn with_panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.panic_policy = policy;
+        self
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<HashMap<K, Entry<S>>> {
+        let index = shard::shard_index(self.hasher.hash_one(key), self.shards.len());
+        &self.shards[index]
+    }
+
+    fn expiry(&self, entry: &Entry<S>, now: Instant) -> Option<ExpiryReason> {
+        if now.duration_since(entry.created) >= self.max_lifetime {
+            Some(ExpiryReason::Lifetime)
+        } else if now.duration_since(entry.last_access) >= self.idle_timeout {
+            Some(ExpiryReason::Idle)
+        } else {
+            None
+        }
+    }
+
+    // Listeners run without any shard lock held. A panicking listener does
+    // not stop the remaining notifications of a purge; the first panic is
+    // resumed once they have all been delivered.
+    fn notify(&self, key: &K, session: &S, reason: ExpiryReason) -> Result<(), Payload> {
+        match &self.listener {
+            Some(listener) => unwind::catch(|| listener(key, session, reason)),
+            None => Ok(()),
+        }
+    }
+
+    // Starts a new session, replacing (without an expiry event) any session
+    // already stored under `key`.
+    pub fn insert(&self, key: K, session: S) -> Option<S> {
+        let now = Instant::now();
+        let entry = Entry {
+            session,
+            created: now,
+            last_access: now,
+        };
+        self.shard(&key)
+            .lock()
+            .unwrap()
+            .insert(key, entry)
+            .map(|old| old.session)
+    }
+
+    // Runs `f` on a live session and renews its idle deadline. Returns `None`
+    // if the session is missing or has expired.
+    pub fn with<R>(&self, key: &K, f: impl FnOnce(&mut S) -> R) -> Option<R> {
+        let now = Instant::now();
+        let mut shard = self.shard(key).lock().unwrap();
+        let reason = self.expiry(shard.get(key)?, now);
+        match reason {
+            None => {
+                let entry = shard.get_mut(key)?;
+                entry.last_access = now;
+                match unwind::catch(|| f(&mut entry.session)) {
+                    Ok(result) => Some(result),
+                    Err(payload) => {
+                        if self.panic_policy == PanicPolicy::Discard {
+                            shard.remove(key);
+                        }
+                        drop(shard);
+                        unwind::resume(payload)
+                    }
+                }
+            }
+            Some(reason) => {
+                let (key, entry) = shard.remove_entry(key)?;
+                drop(shard);
+                if let Err(payload) = self.notify(&key, &entry.session, reason) {
+                    unwind::resume(payload);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<S>
+    where
+        S: Clone,
+    {
+        self.with(key, |s| s.clone())
+    }
+
+    // Renews the idle deadline without reading the session. Returns whether
+    // a live session was found.
+    pub fn touch(&self, key: &K) -> bool {
+        self.with(key, |_| ()).is_some()
+    }
+
+    // Time left before the session expires, by whichever deadline comes
+    // first. Does not renew the session.
+    pub fn time_to_live(&self, key: &K) -> Option<Duration> {
+        let now = Instant::now();
+        let shard = self.shard(key).lock().unwrap();
+        let entry = shard.get(key)?;
+        let idle_deadline = entry.last_access + self.idle_timeout;
+        let lifetime_deadline = entry.created + self.max_lifetime;
+        idle_deadline
+            .min(lifetime_deadline)
+            .checked_duration_since(now)
+            .filter(|d| !d.is_zero())
+    }
+
+    // Ends a session explicitly. No expiry event is emitted.
+    pub fn remove(&self, key: &K) -> Option<S> {
+        self.shard(key)
+            .lock()
+            .unwrap()
+            .remove(key)
+            .map(|entry| entry.session)
+    }
+
+    // Removes every expired session, one shard at a time, and returns how
+    // many were removed.
+    pub fn purge_expired(&self) -> usize {
+        let mut purged = 0;
+        let mut panicked = None;
+        for shard in &self.shards {
+            let now = Instant::now();
+            let expired: Vec<(K, Entry<S>)> = shard
+                .lock()
+                .unwrap()
+                .extract_if(|_, entry| self.expiry(entry, now).is_some())
+                .collect();
+            purged += expired.len();
+            for (key, entry) in expired {
+                if let Some(reason) = self.expiry(&entry, now) {
+                    if let Err(payload) = self.notify(&key, &entry.session, reason) {
+                        panicked.get_or_insert(payload);
+                    }
+                }
+            }
+        }
+        if let Some(payload) = panicked {
+            unwind::resume(payload);
+        }
+        purged
+    }
+
+    // Number of stored sessions, including expired ones not yet purged.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Hash + Eq + Send, S: Send> Diagnostics for SessionStore<K, S> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "SessionStore"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    fn store(policy: PanicPolicy) -> SessionStore<u32, Vec<u32>> {
+        let hour = Duration::from_secs(3600);
+        SessionStore::new(hour, hour).with_panic_policy(policy)
+    }
+
+    fn panic_in(store: &SessionStore<u32, Vec<u32>>, key: u32) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            store.with(&key, |session| {
+                session.push(2);
+                panic!("closure failed");
+            })
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn keep_leaves_changes_after_panic() {
+        let store = store(PanicPolicy::Keep);
+        store.insert(1, vec![1]);
+        panic_in(&store, 1);
+        // A poisoned shard lock would make these panic.
+        assert_eq!(store.get(&1), Some(vec![1, 2]));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn discard_removes_session_after_panic() {
+        let store = store(PanicPolicy::Discard);
+        store.insert(1, vec![1]);
+        store.insert(2, vec![2]);
+        panic_in(&store, 1);
+        assert_eq!(store.get(&1), None);
+        assert_eq!(store.get(&2), Some(vec![2]));
+        assert_eq!(store.insert(1, vec![3]), None);
+    }
+}
@@ -0,0 +1,22 @@
+// Shard selection for the hash-sharded structures.
+//
+// Shard counts are rounded up to a power of two so picking a shard is a
+// mask instead of a division. The hash is first multiplied by a large odd
+// constant and the index taken from the upper half of the product, which
+// depends on every bit of the hash: weak hashers whose output varies only in
+// a few bits (identity-like hashes of small integers) still spread keys
+// over all shards.
+
+// Golden-ratio constant of Fibonacci hashing.
+const MIX: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// The number of shards actually allocated for a requested count.
+pub(crate) fn shard_count(requested: usize) -> usize {
+    assert!(requested > 0, "num_shards must be non-zero");
+    requested.next_power_of_two()
+}
+
+// `num_shards` must come from `shard_count`.
+pub(crate) fn shard_index(hash: u64, num_shards: usize) -> usize {
+    (hash.wrapping_mul(MIX) >> 32) as usize & (num_shards - 1)
+}
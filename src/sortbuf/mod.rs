@@ -0,0 +1,164 @@
+use std::mem;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::{self, AtomicUsize, Mutex};
+
+// Below this many buffered items, sorting and merging on the calling thread
+// is cheaper than spawning helpers.
+const PARALLEL_THRESHOLD: usize = 1 << 14;
+
+static NEXT_THREAD_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static THREAD_SLOT: usize = NEXT_THREAD_SLOT.fetch_add(1, Ordering::Relaxed);
+}
+
+// Ingest buffer for LSM-style pipelines. Producers append to per-thread
+// stripes, so concurrent inserts rarely touch the same lock; flushing takes
+// every stripe, sorts the runs in parallel and merges them pairwise into a
+// single sorted run.
+pub struct SortedRunBuffer<T> {
+    stripes: Vec<Mutex<Vec<T>>>,
+    len: AtomicUsize,
+    id: StructureId,
+}
+
+impl<T: Ord + Send> SortedRunBuffer<T> {
+    pub fn new() -> Self {
+        let stripes = thread::available_parallelism().map_or(4, |n| n.get());
+        Self::with_stripes(stripes)
+    }
+
+    pub fn with_stripes(num_stripes: usize) -> Self {
+        assert!(num_stripes > 0, "num_stripes must be non-zero");
+        SortedRunBuffer {
+            stripes: (0..num_stripes).map(|_| Mutex::new(Vec::new())).collect(),
+            len: AtomicUsize::new(0),
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn stripe(&self) -> &Mutex<Vec<T>> {
+        let slot = THREAD_SLOT.with(|slot| *slot);
+        &self.stripes[slot % self.stripes.len()]
+    }
+
+    pub fn insert(&self, item: T) {
+        let mut stripe = self.stripe().lock().unwrap();
+        stripe.push(item);
+        // Counted under the stripe lock so a concurrent flush never
+        // subtracts an item before it was added.
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn insert_all(&self, items: impl IntoIterator<Item = T>) {
+        let mut stripe = self.stripe().lock().unwrap();
+        let before = stripe.len();
+        stripe.extend(items);
+        self.len.fetch_add(stripe.len() - before, Ordering::Relaxed);
+    }
+
+    // Approximate while producers are inserting concurrently.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Takes everything inserted so far and returns it in ascending order.
+    // Items inserted while the flush is in progress end up either in this
+    // run or in the next one, never in both.
+    pub fn flush_sorted(&self) -> Vec<T> {
+        let mut runs: Vec<Vec<T>> = self
+            .stripes
+            .iter()
+            .map(|stripe| mem::take(&mut *stripe.lock().unwrap()))
+            .filter(|run| !run.is_empty())
+            .collect();
+        let total: usize = runs.iter().map(Vec::len).sum();
+        self.len.fetch_sub(total, Ordering::Relaxed);
+
+        let parallel = total >= PARALLEL_THRESHOLD && !sync::SINGLE_THREADED;
+        if parallel {
+            thread::scope(|s| {
+                for run in runs.iter_mut() {
+                    s.spawn(move || run.sort());
+                }
+            });
+        } else {
+            runs.iter_mut().for_each(|run| run.sort());
+        }
+
+        while runs.len() > 1 {
+            let mut pairs = Vec::with_capacity(runs.len() / 2 + 1);
+            let mut iter = runs.into_iter();
+            while let Some(left) = iter.next() {
+                pairs.push((left, iter.next()));
+            }
+            runs = if parallel {
+                thread::scope(|s| {
+                    let handles: Vec<_> = pairs
+                        .into_iter()
+                        .map(|(left, right)| s.spawn(move || merge_pair(left, right)))
+                        .collect();
+                    handles.into_iter().map(|h| h.join().unwrap()).collect()
+                })
+            } else {
+                pairs
+                    .into_iter()
+                    .map(|(left, right)| merge_pair(left, right))
+                    .collect()
+            };
+        }
+
+        runs.pop().unwrap_or_default()
+    }
+}
+
+impl<T: Ord + Send> Default for SortedRunBuffer<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord + Send> Diagnostics for SortedRunBuffer<T> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "SortedRunBuffer"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+fn merge_pair<T: Ord>(left: Vec<T>, right: Option<Vec<T>>) -> Vec<T> {
+    let Some(right) = right else {
+        return left;
+    };
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    loop {
+        let take_left = match (left.peek(), right.peek()) {
+            (Some(l), Some(r)) => l <= r,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => break,
+        };
+        let next = if take_left { left.next() } else { right.next() };
+        merged.extend(next);
+    }
+    merged
+}
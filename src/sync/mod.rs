@@ -0,0 +1,328 @@
+// Synchronization primitives used throughout the crate.
+//
+// Normally these are the `std::sync` types. With the `wasm` feature on a
+// wasm target built without the `atomics` target feature there is only ever
+// one thread, so they are replaced by `Cell`/`RefCell`-backed types with the
+// same API and the same single-threaded semantics. Taking a lock that is
+// already held panics instead of deadlocking; locks are never poisoned.
+//
+// The actor module keeps using `std::sync` directly: blocking on a `Condvar`
+// needs a second thread to make progress.
+
+// True when the fallbacks below are in use; code that would spawn helper
+// threads checks it and does the work on the calling thread instead.
+pub(crate) const SINGLE_THREADED: bool =
+    cfg!(all(feature = "wasm", target_family = "wasm", not(target_feature = "atomics")));
+
+#[cfg(not(all(feature = "wasm", target_family = "wasm", not(target_feature = "atomics"))))]
+pub(crate) use std::sync::{
+    atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize},
+    Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+};
+
+#[cfg(all(feature = "wasm", target_family = "wasm", not(target_feature = "atomics")))]
+pub(crate) use self::single_threaded::*;
+
+// Mirrors the std API, so not every method is used by every type.
+#[cfg(all(feature = "wasm", target_family = "wasm", not(target_feature = "atomics")))]
+#[allow(dead_code)]
+mod single_threaded {
+    use std::cell::{Cell, Ref, RefCell, RefMut};
+    use std::ops::{Deref, DerefMut};
+    use std::sync::atomic::Ordering;
+    use std::sync::{LockResult, TryLockError, TryLockResult};
+
+    // Every type here is only sound to share because the target cannot run
+    // a second thread; that is what the cfg on this module guarantees.
+    macro_rules! single_threaded_sync {
+        ($($ty:ident),*) => {
+            $(unsafe impl<T: ?Sized + Send> Sync for $ty<T> {})*
+        };
+    }
+
+    pub struct Mutex<T: ?Sized> {
+        cell: RefCell<T>,
+    }
+
+    pub struct MutexGuard<'a, T: ?Sized> {
+        inner: RefMut<'a, T>,
+    }
+
+    impl<T> Mutex<T> {
+        pub const fn new(value: T) -> Self {
+            Mutex {
+                cell: RefCell::new(value),
+            }
+        }
+
+        pub fn into_inner(self) -> LockResult<T> {
+            Ok(self.cell.into_inner())
+        }
+    }
+
+    impl<T: ?Sized> Mutex<T> {
+        pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+            let inner = self
+                .cell
+                .try_borrow_mut()
+                .expect("Mutex locked twice on a single-threaded target");
+            Ok(MutexGuard { inner })
+        }
+
+        pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+            match self.cell.try_borrow_mut() {
+                Ok(inner) => Ok(MutexGuard { inner }),
+                Err(_) => Err(TryLockError::WouldBlock),
+            }
+        }
+
+        pub fn get_mut(&mut self) -> LockResult<&mut T> {
+            Ok(self.cell.get_mut())
+        }
+    }
+
+    impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.inner
+        }
+    }
+
+    impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.inner
+        }
+    }
+
+    pub struct RwLock<T: ?Sized> {
+        cell: RefCell<T>,
+    }
+
+    pub struct RwLockReadGuard<'a, T: ?Sized> {
+        inner: Ref<'a, T>,
+    }
+
+    pub struct RwLockWriteGuard<'a, T: ?Sized> {
+        inner: RefMut<'a, T>,
+    }
+
+    impl<T> RwLock<T> {
+        pub const fn new(value: T) -> Self {
+            RwLock {
+                cell: RefCell::new(value),
+            }
+        }
+
+        pub fn into_inner(self) -> LockResult<T> {
+            Ok(self.cell.into_inner())
+        }
+    }
+
+    impl<T: ?Sized> RwLock<T> {
+        pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+            let inner = self
+                .cell
+                .try_borrow()
+                .expect("RwLock read while write-locked on a single-threaded target");
+            Ok(RwLockReadGuard { inner })
+        }
+
+        pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+            let inner = self
+                .cell
+                .try_borrow_mut()
+                .expect("RwLock locked twice on a single-threaded target");
+            Ok(RwLockWriteGuard { inner })
+        }
+
+        pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+            match self.cell.try_borrow() {
+                Ok(inner) => Ok(RwLockReadGuard { inner }),
+                Err(_) => Err(TryLockError::WouldBlock),
+            }
+        }
+
+        pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+            match self.cell.try_borrow_mut() {
+                Ok(inner) => Ok(RwLockWriteGuard { inner }),
+                Err(_) => Err(TryLockError::WouldBlock),
+            }
+        }
+
+        pub fn get_mut(&mut self) -> LockResult<&mut T> {
+            Ok(self.cell.get_mut())
+        }
+    }
+
+    impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.inner
+        }
+    }
+
+    impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.inner
+        }
+    }
+
+    impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.inner
+        }
+    }
+
+    single_threaded_sync!(Mutex, RwLock);
+
+    // Orderings are accepted for API compatibility; with one thread every
+    // access is trivially sequentially consistent.
+    macro_rules! atomic_cell {
+        ($name:ident, $ty:ty) => {
+            pub struct $name {
+                cell: Cell<$ty>,
+            }
+
+            unsafe impl Sync for $name {}
+
+            impl $name {
+                pub const fn new(value: $ty) -> Self {
+                    $name {
+                        cell: Cell::new(value),
+                    }
+                }
+
+                pub fn load(&self, _: Ordering) -> $ty {
+                    self.cell.get()
+                }
+
+                pub fn store(&self, value: $ty, _: Ordering) {
+                    self.cell.set(value)
+                }
+
+                pub fn swap(&self, value: $ty, _: Ordering) -> $ty {
+                    self.cell.replace(value)
+                }
+
+                pub fn compare_exchange(
+                    &self,
+                    current: $ty,
+                    new: $ty,
+                    _: Ordering,
+                    _: Ordering,
+                ) -> Result<$ty, $ty> {
+                    let previous = self.cell.get();
+                    if previous == current {
+                        self.cell.set(new);
+                        Ok(previous)
+                    } else {
+                        Err(previous)
+                    }
+                }
+
+                pub fn compare_exchange_weak(
+                    &self,
+                    current: $ty,
+                    new: $ty,
+                    success: Ordering,
+                    failure: Ordering,
+                ) -> Result<$ty, $ty> {
+                    self.compare_exchange(current, new, success, failure)
+                }
+
+                pub fn get_mut(&mut self) -> &mut $ty {
+                    self.cell.get_mut()
+                }
+
+                pub fn into_inner(self) -> $ty {
+                    self.cell.into_inner()
+                }
+            }
+        };
+    }
+
+    macro_rules! atomic_int_cell {
+        ($name:ident, $ty:ty) => {
+            atomic_cell!($name, $ty);
+
+            impl $name {
+                pub fn fetch_add(&self, value: $ty, _: Ordering) -> $ty {
+                    let previous = self.cell.get();
+                    self.cell.set(previous.wrapping_add(value));
+                    previous
+                }
+
+                pub fn fetch_sub(&self, value: $ty, _: Ordering) -> $ty {
+                    let previous = self.cell.get();
+                    self.cell.set(previous.wrapping_sub(value));
+                    previous
+                }
+            }
+        };
+    }
+
+    atomic_cell!(AtomicBool, bool);
+    atomic_int_cell!(AtomicUsize, usize);
+    atomic_int_cell!(AtomicU64, u64);
+
+    pub struct AtomicPtr<T> {
+        cell: Cell<*mut T>,
+    }
+
+    unsafe impl<T> Send for AtomicPtr<T> {}
+    unsafe impl<T> Sync for AtomicPtr<T> {}
+
+    impl<T> AtomicPtr<T> {
+        pub const fn new(ptr: *mut T) -> Self {
+            AtomicPtr {
+                cell: Cell::new(ptr),
+            }
+        }
+
+        pub fn load(&self, _: Ordering) -> *mut T {
+            self.cell.get()
+        }
+
+        pub fn store(&self, ptr: *mut T, _: Ordering) {
+            self.cell.set(ptr)
+        }
+
+        pub fn swap(&self, ptr: *mut T, _: Ordering) -> *mut T {
+            self.cell.replace(ptr)
+        }
+
+        pub fn compare_exchange(
+            &self,
+            current: *mut T,
+            new: *mut T,
+            _: Ordering,
+            _: Ordering,
+        ) -> Result<*mut T, *mut T> {
+            let previous = self.cell.get();
+            if previous == current {
+                self.cell.set(new);
+                Ok(previous)
+            } else {
+                Err(previous)
+            }
+        }
+
+        pub fn compare_exchange_weak(
+            &self,
+            current: *mut T,
+            new: *mut T,
+            success: Ordering,
+            failure: Ordering,
+        ) -> Result<*mut T, *mut T> {
+            self.compare_exchange(current, new, success, failure)
+        }
+
+        pub fn get_mut(&mut self) -> &mut *mut T {
+            self.cell.get_mut()
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::f64::consts::PI;
+use std::sync::atomic::Ordering;
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::{AtomicUsize, Mutex};
+
+const DEFAULT_COMPRESSION: f64 = 100.0;
+const DEFAULT_SHARDS: usize = 16;
+// Values buffered per shard before they are folded into the shard's digest.
+const BUFFER: usize = 512;
+
+static NEXT_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Threads are spread over shards round-robin, so a fixed set of
+    // recording threads never shares a shard lock needlessly.
+    static THREAD: usize = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+// A merged t-digest: a sorted set of weighted centroids that summarizes a
+// distribution with high accuracy at the tails and bounded memory. Returned
+// by `ConcurrentTDigest::snapshot`, and mergeable with other digests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TDigest {
+    centroids: Vec<Centroid>,
+    compression: f64,
+    count: f64,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    // Larger `compression` keeps more centroids and answers more precisely;
+    // a digest holds on the order of `compression` centroids.
+    pub fn new(compression: f64) -> Self {
+        assert!(compression >= 1.0, "compression must be at least 1");
+        TDigest {
+            centroids: Vec::new(),
+            compression,
+            count: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn compression(&self) -> f64 {
+        self.compression
+    }
+
+    // Number of values summarized.
+    pub fn count(&self) -> u64 {
+        self.count as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.centroids.is_empty()
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        (!self.is_empty()).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        (!self.is_empty()).then_some(self.max)
+    }
+
+    pub fn merge(&mut self, other: &TDigest) {
+        self.absorb(other.centroids.iter().copied(), other.min, other.max);
+    }
+
+    fn add_values(&mut self, values: &[f64]) {
+        let (min, max) = values
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            });
+        let points = values.iter().map(|&mean| Centroid { mean, weight: 1.0 });
+        self.absorb(points, min, max);
+    }
+
+    // The scale function bounding how much weight a centroid at quantile
+    // `q` may hold: centroids are small near the tails and large in the
+    // middle.
+    fn scale(&self, q: f64) -> f64 {
+        self.compression / (2.0 * PI) * (2.0 * q - 1.0).asin()
+    }
+
+    fn scale_inverse(&self, k: f64) -> f64 {
+        let angle = (k * 2.0 * PI / self.compression).min(PI / 2.0);
+        (angle.sin() + 1.0) / 2.0
+    }
+
+    fn absorb(&mut self, incoming: impl Iterator<Item = Centroid>, min: f64, max: f64) {
+        let mut all: Vec<Centroid> = self.centroids.drain(..).chain(incoming).collect();
+        if all.is_empty() {
+            return;
+        }
+        all.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+        let total: f64 = all.iter().map(|c| c.weight).sum();
+
+        let mut merged = Vec::with_capacity(self.compression as usize * 2);
+        let mut current = all[0];
+        let mut weight_before = 0.0;
+        let mut q_limit = self.scale_inverse(self.scale(0.0) + 1.0);
+        for &next in &all[1..] {
+            if (weight_before + current.weight + next.weight) / total <= q_limit {
+                current.weight += next.weight;
+                current.mean += (next.mean - current.mean) * next.weight / current.weight;
+            } else {
+                weight_before += current.weight;
+                merged.push(current);
+                q_limit = self.scale_inverse(self.scale(weight_before / total) + 1.0);
+                current = next;
+            }
+        }
+        merged.push(current);
+
+        self.centroids = merged;
+        self.count = total;
+        self.min = self.min.min(min);
+        self.max = self.max.max(max);
+    }
+
+    // Estimated value at quantile `q` (0.0 to 1.0), interpolating between
+    // centroid means. `None` if the digest is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let last = self.centroids.last()?;
+        if q <= 0.0 {
+            return Some(self.min);
+        }
+        if q >= 1.0 {
+            return Some(self.max);
+        }
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t.clamp(0.0, 1.0);
+        let index = q * self.count;
+
+        // Each centroid's mean sits at the middle of its weight.
+        let mut before = 0.0;
+        let mut previous_mid = 0.0;
+        let mut previous_mean = self.min;
+        for c in &self.centroids {
+            let mid = before + c.weight / 2.0;
+            if index < mid {
+                let t = (index - previous_mid) / (mid - previous_mid);
+                return Some(lerp(previous_mean, c.mean, t));
+            }
+            before += c.weight;
+            previous_mid = mid;
+            previous_mean = c.mean;
+        }
+        let last_mid = self.count - last.weight / 2.0;
+        Some(lerp(last.mean, self.max, (index - last_mid) / (self.count - last_mid)))
+    }
+}
+
+struct Shard {
+    buffer: Vec<f64>,
+    digest: TDigest,
+}
+
+impl Shard {
+    fn flush(&mut self) {
+        if !self.buffer.is_empty() {
+            self.digest.add_values(&self.buffer);
+            self.buffer.clear();
+        }
+    }
+}
+
+// Thread-safe streaming quantile estimator, e.g. for latency percentiles
+// without keeping raw samples. Each recording thread appends to one of
+// several sharded buffers; a buffer is folded into its shard's digest when
+// it fills up, and `quantile`/`snapshot` merge all shards on read.
+pub struct ConcurrentTDigest {
+    shards: Vec<Mutex<Shard>>,
+    compression: f64,
+    id: StructureId,
+}
+
+impl ConcurrentTDigest {
+    pub fn new() -> Self {
+        Self::with_compression(DEFAULT_COMPRESSION)
+    }
+
+    pub fn with_compression(compression: f64) -> Self {
+        Self::with_compression_and_shards(compression, DEFAULT_SHARDS)
+    }
+
+    pub fn with_compression_and_shards(compression: f64, num_shards: usize) -> Self {
+        assert!(num_shards > 0, "num_shards must be non-zero");
+        ConcurrentTDigest {
+            shards: (0..num_shards)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        buffer: Vec::with_capacity(BUFFER),
+                        digest: TDigest::new(compression),
+                    })
+                })
+                .collect(),
+            compression,
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    // NaN values are ignored.
+    pub fn record(&self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        let index = THREAD.with(|t| *t) % self.shards.len();
+        let mut shard = self.shards[index].lock().unwrap();
+        shard.buffer.push(value);
+        if shard.buffer.len() >= BUFFER {
+            shard.flush();
+        }
+    }
+
+    // Folds another digest in, e.g. one received from another process.
+    pub fn merge(&self, other: &TDigest) {
+        let index = THREAD.with(|t| *t) % self.shards.len();
+        self.shards[index].lock().unwrap().digest.merge(other);
+    }
+
+    // A digest of every value recorded so far. Takes each shard lock once.
+    pub fn snapshot(&self) -> TDigest {
+        let mut digest = TDigest::new(self.compression);
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            shard.flush();
+            digest.merge(&shard.digest);
+        }
+        digest
+    }
+
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        self.snapshot().quantile(q)
+    }
+}
+
+impl Default for ConcurrentTDigest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Diagnostics for ConcurrentTDigest {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "ConcurrentTDigest"
+    }
+
+    // Memory is bounded by the compression, not by the values recorded.
+    fn size(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    const N: u32 = 100_000;
+
+    // 0..N in a scrambled order; 7919 is prime, so every value appears once.
+    fn uniform() -> impl Iterator<Item = f64> {
+        (0..N).map(|i| (i as u64 * 7919 % N as u64) as f64)
+    }
+
+    fn assert_close(digest: &TDigest, q: f64, tolerance: f64) {
+        let expected = q * (N - 1) as f64;
+        let actual = digest.quantile(q).unwrap();
+        assert!(
+            (actual - expected).abs() <= tolerance * N as f64,
+            "p{}: expected about {}, got {}",
+            q * 100.0,
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn quantiles_of_a_uniform_distribution() {
+        let digest = ConcurrentTDigest::new();
+        for value in uniform() {
+            digest.record(value);
+        }
+        let snapshot = digest.snapshot();
+        assert_eq!(snapshot.count(), N as u64);
+        assert_eq!(snapshot.min(), Some(0.0));
+        assert_eq!(snapshot.max(), Some((N - 1) as f64));
+        assert_close(&snapshot, 0.5, 0.01);
+        assert_close(&snapshot, 0.99, 0.001);
+        assert_close(&snapshot, 0.999, 0.0005);
+        assert_eq!(snapshot.quantile(0.0), Some(0.0));
+        assert_eq!(snapshot.quantile(1.0), Some((N - 1) as f64));
+        assert!(snapshot.centroids.len() <= 2 * DEFAULT_COMPRESSION as usize);
+    }
+
+    #[test]
+    fn concurrent_recording_matches_sequential() {
+        let digest = Arc::new(ConcurrentTDigest::with_compression_and_shards(100.0, 4));
+        let values: Arc<Vec<f64>> = Arc::new(uniform().collect());
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let digest = digest.clone();
+                let values = values.clone();
+                thread::spawn(move || {
+                    for &value in values.iter().skip(t).step_by(4) {
+                        digest.record(value);
+                    }
+                })
+            })
+            .collect();
+        for handle in threads {
+            handle.join().unwrap();
+        }
+        let snapshot = digest.snapshot();
+        assert_eq!(snapshot.count(), N as u64);
+        assert_close(&snapshot, 0.5, 0.01);
+        assert_close(&snapshot, 0.99, 0.001);
+    }
+
+    #[test]
+    fn merging_halves_summarizes_the_whole() {
+        let (low, high) = (ConcurrentTDigest::new(), ConcurrentTDigest::new());
+        for value in uniform() {
+            if value < (N / 2) as f64 {
+                low.record(value);
+            } else {
+                high.record(value);
+            }
+        }
+        let mut merged = low.snapshot();
+        merged.merge(&high.snapshot());
+        assert_eq!(merged.count(), N as u64);
+        assert_eq!(merged.min(), Some(0.0));
+        assert_eq!(merged.max(), Some((N - 1) as f64));
+        assert_close(&merged, 0.5, 0.01);
+        assert_close(&merged, 0.99, 0.001);
+
+        // The same through `ConcurrentTDigest::merge`.
+        low.merge(&high.snapshot());
+        assert_close(&low.snapshot(), 0.5, 0.01);
+
+        // Merging an empty digest changes nothing.
+        let before = merged.clone();
+        merged.merge(&TDigest::new(100.0));
+        assert_eq!(merged, before);
+    }
+
+    #[test]
+    fn nan_values_are_ignored() {
+        let digest = ConcurrentTDigest::new();
+        digest.record(f64::NAN);
+        assert!(digest.snapshot().is_empty());
+        assert_eq!(digest.quantile(0.5), None);
+
+        for value in [1.0, f64::NAN, 2.0, 3.0, f64::NAN] {
+            digest.record(value);
+        }
+        let snapshot = digest.snapshot();
+        assert_eq!(snapshot.count(), 3);
+        assert_eq!(snapshot.min(), Some(1.0));
+        assert_eq!(snapshot.max(), Some(3.0));
+        assert_eq!(snapshot.quantile(0.5), Some(2.0));
+    }
+}
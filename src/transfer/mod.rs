@@ -0,0 +1,189 @@
+// Moving items between structures without losing them halfway.
+//
+// A plain `queue.dequeue()` followed by `map.insert(..)` drops the item if
+// the thread panics in between (for instance inside the key function), and
+// `insert` drops it if the key is taken. The helpers here hold the dequeued
+// item until the map takes it; if the key is taken or the thread unwinds,
+// the item goes to a `PendingSlot` shared by every consumer of the queue.
+//
+// Unwinding is the only way a transfer can stop halfway: a thread cannot be
+// killed on its own, and an abort takes the whole process with it. A
+// thread that blocks forever inside `key_fn` keeps its item.
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+
+use crate::lockfree_map::LockFreeHashMap;
+use crate::queue::Queue;
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::Mutex;
+
+#[derive(PartialEq, Eq)]
+pub enum TransferError<K> {
+    // Neither the pending slot nor the queue had anything to move.
+    Empty,
+    // The map already holds the key; the item was put back at the front of
+    // the pending slot.
+    Occupied(K),
+}
+
+impl<K> fmt::Debug for TransferError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Empty => write!(f, "Empty"),
+            TransferError::Occupied(_) => write!(f, "Occupied(..)"),
+        }
+    }
+}
+
+impl<K> fmt::Display for TransferError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Empty => write!(f, "queue is empty"),
+            TransferError::Occupied(_) => write!(f, "key is already present"),
+        }
+    }
+}
+
+impl<K> std::error::Error for TransferError<K> {}
+
+// Items taken from a queue by transfers that did not complete, oldest
+// first. Every transfer takes from here before it dequeues, so items keep
+// their FIFO order and any consumer sharing the slot picks up an item
+// another one failed to move. An item whose key stays taken therefore
+// blocks the ones behind it until it is removed with `pop`.
+pub struct PendingSlot<T> {
+    items: Mutex<VecDeque<T>>,
+    id: StructureId,
+}
+
+impl<T> PendingSlot<T> {
+    pub fn new() -> Self {
+        PendingSlot {
+            items: Mutex::new(VecDeque::new()),
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    // Removes the oldest pending item, e.g. to move it to a dead-letter
+    // queue.
+    pub fn pop(&self) -> Option<T> {
+        self.items.lock().unwrap().pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn take_or_dequeue(&self, queue: &Queue<T>) -> Option<T> {
+        self.pop().or_else(|| queue.dequeue())
+    }
+
+    fn put_back(&self, item: T) {
+        self.items.lock().unwrap().push_front(item);
+    }
+}
+
+impl<T> Default for PendingSlot<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Send> Diagnostics for PendingSlot<T> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "PendingSlot"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
+
+// Owns the item between dequeue and insert, and puts it back in the
+// pending slot unless the transfer completes.
+struct Claim<'a, T> {
+    pending: &'a PendingSlot<T>,
+    item: Option<T>,
+}
+
+impl<T> Drop for Claim<'_, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.pending.put_back(item);
+        }
+    }
+}
+
+// Takes the oldest item, from `pending` or else from `queue`, and inserts
+// it into `map` under `key_fn(&item)`. Returns the key on success. If the
+// key is already present, or `key_fn` panics, the item goes back to the
+// front of `pending`, where the next transfer finds it first.
+pub fn transfer_dequeue_insert<K, T, S>(
+    queue: &Queue<T>,
+    pending: &PendingSlot<T>,
+    map: &LockFreeHashMap<K, T, S>,
+    key_fn: impl FnOnce(&T) -> K,
+) -> Result<K, TransferError<K>>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    T: Send + 'static,
+    S: BuildHasher,
+{
+    let mut claim = Claim {
+        pending,
+        item: Some(pending.take_or_dequeue(queue).ok_or(TransferError::Empty)?),
+    };
+    let key = key_fn(claim.item.as_ref().unwrap());
+    match map.try_insert(key.clone(), claim.item.take().unwrap()) {
+        Ok(()) => Ok(key),
+        Err((key, item)) => {
+            claim.item = Some(item);
+            Err(TransferError::Occupied(key))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn failed_transfers_keep_fifo_order() {
+        let queue = Queue::new();
+        let pending = PendingSlot::new();
+        let map = LockFreeHashMap::new();
+        queue.enqueue_batch([1, 2, 3]);
+        map.insert(1, 0);
+
+        let result = transfer_dequeue_insert(&queue, &pending, &map, |&v| v);
+        assert_eq!(result, Err(TransferError::Occupied(1)));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            transfer_dequeue_insert(&queue, &pending, &map, |_| -> u32 { panic!("key_fn failed") })
+        }));
+        assert!(result.is_err());
+        assert_eq!(pending.len(), 1);
+
+        // The occupied item is still first in line.
+        assert_eq!(pending.pop(), Some(1));
+        assert_eq!(transfer_dequeue_insert(&queue, &pending, &map, |&v| v), Ok(2));
+        assert_eq!(transfer_dequeue_insert(&queue, &pending, &map, |&v| v), Ok(3));
+        assert_eq!(
+            transfer_dequeue_insert(&queue, &pending, &map, |&v| v),
+            Err(TransferError::Empty)
+        );
+    }
+}
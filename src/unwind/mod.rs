@@ -0,0 +1,31 @@
+// Panic handling for user closures.
+//
+// Closures passed to the crate's structures may run while an internal lock
+// is held. Every such call site catches the unwind, restores the structure
+// to a consistent state and releases its locks without poisoning them, and
+// only then resumes the panic on the calling thread. `PanicPolicy` decides
+// what happens to the entry the closure was working on.
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    // Keep the entry as the closure left it. Mutations made before the
+    // panic stay visible.
+    #[default]
+    Keep,
+    // Remove the entry, so a half-updated value is never observed.
+    Discard,
+}
+
+pub(crate) type Payload = Box<dyn Any + Send + 'static>;
+
+// Runs `f`, turning a panic into an `Err` carrying its payload. Callers must
+// leave their data consistent before handing the payload to `resume`.
+pub(crate) fn catch<R>(f: impl FnOnce() -> R) -> Result<R, Payload> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+}
+
+pub(crate) fn resume(payload: Payload) -> ! {
+    panic::resume_unwind(payload)
+}
@@ -0,0 +1,172 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Weak};
+
+use crate::registry::{Diagnostics, StructureId};
+use crate::shard;
+use crate::sync::{Mutex, MutexGuard};
+use crate::unwind;
+
+const DEFAULT_SHARDS: usize = 16;
+// Shards below this many entries are never swept on insert.
+const MIN_SWEEP: usize = 8;
+
+struct Shard<K, V> {
+    entries: HashMap<K, Weak<V>>,
+    // Entry count at which the next insert sweeps out dead entries.
+    sweep_at: usize,
+}
+
+impl<K, V> Shard<K, V> {
+    fn sweep(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|_, value| value.strong_count() > 0);
+        self.sweep_at = (self.entries.len() * 2).max(MIN_SWEEP);
+        before - self.entries.len()
+    }
+}
+
+// Map holding its values weakly, e.g. a canonicalizing registry handing out
+// one shared `Arc` per key. An entry dies when the last `Arc` outside the
+// map is dropped; lookups then miss and the entry is removed.
+//
+// Dead entries are also swept out in bulk: a shard sweeps itself when an
+// insert finds it twice as large as after its previous sweep, which keeps
+// memory proportional to the live entries at amortized constant cost.
+// `purge` sweeps every shard on demand.
+pub struct WeakValueMap<K, V, S = RandomState> {
+    shards: Vec<Mutex<Shard<K, V>>>,
+    hasher: S,
+    id: StructureId,
+}
+
+impl<K: Hash + Eq, V> WeakValueMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(num_shards: usize) -> Self {
+        Self::with_shards_and_hasher(num_shards, RandomState::new())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> WeakValueMap<K, V, S> {
+    pub fn with_shards_and_hasher(num_shards: usize, hasher: S) -> Self {
+        WeakValueMap {
+            shards: (0..shard::shard_count(num_shards))
+                .map(|_| {
+                    Mutex::new(Shard {
+                        entries: HashMap::new(),
+                        sweep_at: MIN_SWEEP,
+                    })
+                })
+                .collect(),
+            hasher,
+            id: StructureId::next(),
+        }
+    }
+
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn shard(&self, key: &K) -> MutexGuard<'_, Shard<K, V>> {
+        let index = shard::shard_index(self.hasher.hash_one(key), self.shards.len());
+        self.shards[index].lock().unwrap()
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<V>> {
+        let mut shard = self.shard(key);
+        let value = shard.entries.get(key)?.upgrade();
+        if value.is_none() {
+            shard.entries.remove(key);
+        }
+        value
+    }
+
+    // Stores a weak reference to `value`. Returns the value previously
+    // stored under `key`, if it was still alive.
+    pub fn insert(&self, key: K, value: &Arc<V>) -> Option<Arc<V>> {
+        let mut shard = self.shard(&key);
+        let previous = shard.entries.insert(key, Arc::downgrade(value));
+        if shard.entries.len() >= shard.sweep_at {
+            shard.sweep();
+        }
+        previous.and_then(|weak| weak.upgrade())
+    }
+
+    // The live value for `key`, or a new one from `make` that is stored and
+    // returned. Concurrent callers for the same key all get the same `Arc`.
+    // `make` runs under the shard lock; if it panics, nothing is stored and
+    // the lock is released unpoisoned before the panic resumes.
+    pub fn get_or_insert_with(&self, key: K, make: impl FnOnce() -> V) -> Arc<V> {
+        let mut shard = self.shard(&key);
+        if let Some(value) = shard.entries.get(&key).and_then(Weak::upgrade) {
+            return value;
+        }
+        let value = match unwind::catch(make) {
+            Ok(value) => Arc::new(value),
+            Err(payload) => {
+                drop(shard);
+                unwind::resume(payload)
+            }
+        };
+        shard.entries.insert(key, Arc::downgrade(&value));
+        if shard.entries.len() >= shard.sweep_at {
+            shard.sweep();
+        }
+        value
+    }
+
+    // Returns the removed value if it was still alive.
+    pub fn remove(&self, key: &K) -> Option<Arc<V>> {
+        self.shard(key).entries.remove(key)?.upgrade()
+    }
+
+    // Removes every dead entry, one shard at a time, and returns how many
+    // were removed.
+    pub fn purge(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().sweep())
+            .sum()
+    }
+
+    // Number of stored entries, including dead ones not yet removed.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Hash + Eq, V> Default for WeakValueMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, S> Diagnostics for WeakValueMap<K, V, S>
+where
+    K: Hash + Eq + Send,
+    V: Send + Sync,
+    S: BuildHasher + Send + Sync,
+{
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "WeakValueMap"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len())
+    }
+}
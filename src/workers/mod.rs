@@ -0,0 +1,368 @@
+// Consumers for a `Queue` whose number follows its depth.
+//
+// A monitor thread samples `Queue::len` every `poll_interval` and starts
+// workers, up to `max_workers`, until there is one per `depth_per_worker`
+// queued items. A worker beyond `min_workers` that finds the queue empty for
+// `idle_timeout` exits, so bursts get extra consumers and quiet periods give
+// the threads back. `Queue` has no blocking dequeue, so idle workers poll it
+// every `poll_interval`.
+//
+// Only depth is watched: items carry no enqueue time, so the time they
+// spend queued is not measured.
+use std::any::Any;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::queue::Queue;
+use crate::registry::{Diagnostics, StructureId};
+use crate::sync::SINGLE_THREADED;
+
+const DEFAULT_DEPTH_PER_WORKER: usize = 64;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_millis(500);
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+type Handler<T> = Box<dyn Fn(T) + Send + Sync>;
+type Panic = Box<dyn Any + Send>;
+
+// Bounds and thresholds for a `WorkerPool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScalingPolicy {
+    min_workers: usize,
+    max_workers: usize,
+    depth_per_worker: usize,
+    idle_timeout: Duration,
+    poll_interval: Duration,
+}
+
+impl ScalingPolicy {
+    pub fn new(min_workers: usize, max_workers: usize) -> Self {
+        assert!(max_workers > 0, "max_workers must be non-zero");
+        assert!(min_workers <= max_workers, "min_workers must not exceed max_workers");
+        ScalingPolicy {
+            min_workers,
+            max_workers,
+            depth_per_worker: DEFAULT_DEPTH_PER_WORKER,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    // Queued items per running worker above which another one is started.
+    pub fn with_depth_per_worker(mut self, depth: usize) -> Self {
+        assert!(depth > 0, "depth_per_worker must be non-zero");
+        self.depth_per_worker = depth;
+        self
+    }
+
+    // How long a worker beyond the minimum waits for an item before exiting.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    // How often the monitor samples the queue depth, and how long an idle
+    // worker sleeps between polls.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn min_workers(&self) -> usize {
+        self.min_workers
+    }
+
+    pub fn max_workers(&self) -> usize {
+        self.max_workers
+    }
+
+    // Workers wanted for `depth` queued items.
+    fn target(&self, depth: usize) -> usize {
+        depth
+            .div_ceil(self.depth_per_worker)
+            .clamp(self.min_workers, self.max_workers)
+    }
+}
+
+// One worker per core at most, none kept while the queue is empty.
+impl Default for ScalingPolicy {
+    fn default() -> Self {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(0, cores)
+    }
+}
+
+struct Shared<T> {
+    queue: Arc<Queue<T>>,
+    handler: Handler<T>,
+    policy: ScalingPolicy,
+    workers: AtomicUsize,
+    stopping: AtomicBool,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    // The first panic of a handler, resumed by `shutdown`.
+    panic: Mutex<Option<Panic>>,
+}
+
+impl<T: Send + 'static> Shared<T> {
+    fn spawn_worker(self: &Arc<Self>) {
+        self.workers.fetch_add(1, Ordering::SeqCst);
+        let shared = Arc::clone(self);
+        let handle = thread::spawn(move || shared.work());
+        let mut threads = self.threads.lock().unwrap();
+        // Joins workers that already exited, keeping the first panic.
+        let (finished, running) = threads.drain(..).partition(|h: &JoinHandle<()>| h.is_finished());
+        *threads = running;
+        threads.push(handle);
+        drop(threads);
+        for handle in finished {
+            self.keep_panic(handle.join());
+        }
+    }
+
+    fn keep_panic(&self, result: thread::Result<()>) {
+        if let Err(payload) = result {
+            self.panic.lock().unwrap().get_or_insert(payload);
+        }
+    }
+
+    fn work(&self) {
+        // Gives the worker's place back however it exits, so the monitor
+        // replaces a worker whose handler panicked.
+        struct Exit<'a>(&'a AtomicUsize);
+        impl Drop for Exit<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        let exit = Exit(&self.workers);
+
+        let mut idle_since = Instant::now();
+        loop {
+            if let Some(item) = self.queue.dequeue() {
+                (self.handler)(item);
+                idle_since = Instant::now();
+                continue;
+            }
+            if self.stopping.load(Ordering::SeqCst) {
+                return;
+            }
+            if idle_since.elapsed() >= self.policy.idle_timeout && self.retire() {
+                std::mem::forget(exit);
+                return;
+            }
+            thread::sleep(self.policy.poll_interval);
+        }
+    }
+
+    // Gives up a place if more than the minimum are running.
+    fn retire(&self) -> bool {
+        let min = self.policy.min_workers;
+        self.workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n > min).then(|| n - 1))
+            .is_ok()
+    }
+
+    fn monitor(self: &Arc<Self>) {
+        while !self.stopping.load(Ordering::SeqCst) {
+            let target = self.policy.target(self.queue.len());
+            while self.workers.load(Ordering::SeqCst) < target {
+                self.spawn_worker();
+            }
+            thread::sleep(self.policy.poll_interval);
+        }
+    }
+}
+
+// Runs `handler` on the items of `queue` from between `min_workers` and
+// `max_workers` threads. Producers keep enqueuing on the queue directly.
+// Dropping the pool stops it as `shutdown` does, without resuming a
+// handler's panic.
+pub struct WorkerPool<T: Send + 'static> {
+    shared: Arc<Shared<T>>,
+    monitor: Option<JoinHandle<()>>,
+    id: StructureId,
+}
+
+// Starts `min_workers` workers and the monitor. With the single-threaded
+// `wasm` fallback no thread is started, and `shutdown` handles every item on
+// the calling thread.
+pub fn spawn<T: Send + 'static>(
+    queue: Arc<Queue<T>>,
+    policy: ScalingPolicy,
+    handler: impl Fn(T) + Send + Sync + 'static,
+) -> WorkerPool<T> {
+    let shared = Arc::new(Shared {
+        queue,
+        handler: Box::new(handler),
+        policy,
+        workers: AtomicUsize::new(0),
+        stopping: AtomicBool::new(false),
+        threads: Mutex::new(Vec::new()),
+        panic: Mutex::new(None),
+    });
+    let mut monitor = None;
+    if !SINGLE_THREADED {
+        for _ in 0..policy.min_workers {
+            shared.spawn_worker();
+        }
+        let shared = Arc::clone(&shared);
+        monitor = Some(thread::spawn(move || shared.monitor()));
+    }
+    WorkerPool {
+        shared,
+        monitor,
+        id: StructureId::next(),
+    }
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn id(&self) -> StructureId {
+        self.id
+    }
+
+    pub fn queue(&self) -> &Arc<Queue<T>> {
+        &self.shared.queue
+    }
+
+    pub fn policy(&self) -> ScalingPolicy {
+        self.shared.policy
+    }
+
+    // Workers running right now, including ones about to exit.
+    pub fn workers(&self) -> usize {
+        self.shared.workers.load(Ordering::SeqCst)
+    }
+
+    // Stops scaling, lets the workers drain the queue and waits for them,
+    // handling whatever they left behind on the calling thread. Items
+    // enqueued after this returns are not handled. If a handler panicked,
+    // nothing more is handled here and the first panic resumes instead.
+    pub fn shutdown(mut self) {
+        if let Some(payload) = self.stop() {
+            std::panic::resume_unwind(payload);
+        }
+    }
+
+    fn stop(&mut self) -> Option<Panic> {
+        let shared = &self.shared;
+        shared.stopping.store(true, Ordering::SeqCst);
+        if let Some(monitor) = self.monitor.take() {
+            shared.keep_panic(monitor.join());
+        }
+        // The monitor is gone, so no worker starts after this.
+        let threads = std::mem::take(&mut *shared.threads.lock().unwrap());
+        for handle in threads {
+            shared.keep_panic(handle.join());
+        }
+        let panic = shared.panic.lock().unwrap().take();
+        if panic.is_none() {
+            while let Some(item) = shared.queue.dequeue() {
+                (shared.handler)(item);
+            }
+        }
+        panic
+    }
+}
+
+impl<T: Send + 'static> Drop for WorkerPool<T> {
+    fn drop(&mut self) {
+        if !self.shared.stopping.load(Ordering::SeqCst) {
+            drop(self.stop());
+        }
+    }
+}
+
+impl<T: Send + 'static> fmt::Debug for WorkerPool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field("id", &self.id)
+            .field("workers", &self.workers())
+            .field("queued", &self.shared.queue.len())
+            .field("policy", &self.shared.policy)
+            .finish()
+    }
+}
+
+impl<T: Send + 'static> Diagnostics for WorkerPool<T> {
+    fn id(&self) -> StructureId {
+        self.id
+    }
+
+    fn kind(&self) -> &'static str {
+        "WorkerPool"
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.shared.queue.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    fn wait_until(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn scales_up_under_backlog_and_back_down_when_idle() {
+        let queue = Arc::new(Queue::new());
+        queue.enqueue_batch(0..400);
+        let handled = Arc::new(AtomicUsize::new(0));
+        let count = handled.clone();
+        let policy = ScalingPolicy::new(1, 4)
+            .with_depth_per_worker(10)
+            .with_idle_timeout(Duration::from_millis(20))
+            .with_poll_interval(Duration::from_millis(1));
+        let pool = spawn(queue.clone(), policy, move |_: u32| {
+            thread::sleep(Duration::from_millis(1));
+            count.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let mut peak = 0;
+        wait_until(|| {
+            peak = peak.max(pool.workers());
+            queue.is_empty()
+        });
+        assert!(peak > 1 && peak <= 4, "peak {peak}");
+        wait_until(|| pool.workers() == 1);
+        pool.shutdown();
+        assert_eq!(handled.load(Ordering::SeqCst), 400);
+    }
+
+    #[test]
+    fn shutdown_handles_every_queued_item() {
+        let queue = Arc::new(Queue::new());
+        let handled = Arc::new(AtomicUsize::new(0));
+        let count = handled.clone();
+        let pool = spawn(queue.clone(), ScalingPolicy::new(0, 2), move |_: u32| {
+            count.fetch_add(1, Ordering::SeqCst);
+        });
+        queue.enqueue_batch(0..1000);
+        pool.shutdown();
+        assert_eq!(handled.load(Ordering::SeqCst), 1000);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn handler_panics_resume_at_shutdown() {
+        let queue = Arc::new(Queue::new());
+        let policy = ScalingPolicy::new(1, 1).with_poll_interval(Duration::from_millis(1));
+        let pool = spawn(queue.clone(), policy, |item: u32| {
+            assert_ne!(item, 3, "bad item");
+        });
+        queue.enqueue_batch(0..10);
+        // The panicking worker is replaced and the rest are still handled.
+        wait_until(|| queue.is_empty());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.shutdown()));
+        assert!(result.is_err());
+    }
+}
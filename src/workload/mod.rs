@@ -0,0 +1,270 @@
+// Reproducible workloads for benchmarks and stress runs.
+//
+// A `Workload` describes a stream of operations over the keys `0..num_keys`:
+// how popular each key is (`KeyDistribution`), what fraction of operations
+// are reads, and when each operation arrives (`Arrival`). The stream is
+// generated from a seed, so the same workload replays the same operations
+// against every structure and results stay comparable. Each thread of a
+// multi-threaded run takes its own stream with `stream_for`; the streams are
+// independent of each other and of how many threads there are.
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyDistribution {
+    // Every key equally likely.
+    Uniform,
+    // Key `k` is drawn with probability proportional to 1 / (k + 1)^exponent,
+    // so key 0 is the most popular. An exponent around 1.0 matches typical
+    // cache traffic; 0.0 is uniform.
+    Zipf { exponent: f64 },
+    // A fraction `hot_keys` of the key space (the lowest keys) receives a
+    // fraction `hot_traffic` of the operations; keys within each set are
+    // equally likely.
+    Hotspot { hot_keys: f64, hot_traffic: f64 },
+}
+
+// When operations arrive, relative to the start of the run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arrival {
+    // Back to back: every operation is due immediately.
+    Closed,
+    // Evenly spaced at `per_second` operations per second.
+    Constant { per_second: f64 },
+    // Exponentially distributed gaps averaging `per_second` per second.
+    Poisson { per_second: f64 },
+    // `burst` operations at once every `period`.
+    Bursty { burst: usize, period: Duration },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Read(u64),
+    Write(u64),
+}
+
+impl Op {
+    pub fn key(&self) -> u64 {
+        match *self {
+            Op::Read(key) | Op::Write(key) => key,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    // When the operation is due, measured from the start of the run.
+    pub at: Duration,
+    pub op: Op,
+}
+
+// Sampling state shared by every stream of a workload.
+#[derive(Debug)]
+enum Sampler {
+    Uniform,
+    // Cumulative probabilities of keys 0..num_keys.
+    Zipf(Vec<f64>),
+    Hotspot { hot: u64, hot_traffic: f64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Workload {
+    num_keys: u64,
+    seed: u64,
+    read_ratio: f64,
+    arrival: Arrival,
+    distribution: KeyDistribution,
+    sampler: Arc<Sampler>,
+}
+
+impl Workload {
+    // Uniform keys, all reads, closed arrival.
+    pub fn new(num_keys: u64, seed: u64) -> Self {
+        assert!(num_keys > 0, "num_keys must be non-zero");
+        Workload {
+            num_keys,
+            seed,
+            read_ratio: 1.0,
+            arrival: Arrival::Closed,
+            distribution: KeyDistribution::Uniform,
+            sampler: Arc::new(Sampler::Uniform),
+        }
+    }
+
+    // Zipf keeps a table of `num_keys` cumulative probabilities, shared by
+    // all streams of the workload.
+    pub fn with_distribution(mut self, distribution: KeyDistribution) -> Self {
+        let sampler = match distribution {
+            KeyDistribution::Uniform => Sampler::Uniform,
+            KeyDistribution::Zipf { exponent } => {
+                assert!(exponent >= 0.0, "exponent must not be negative");
+                let mut total = 0.0;
+                let mut cdf: Vec<f64> = (0..self.num_keys)
+                    .map(|k| {
+                        total += 1.0 / ((k + 1) as f64).powf(exponent);
+                        total
+                    })
+                    .collect();
+                cdf.iter_mut().for_each(|p| *p /= total);
+                Sampler::Zipf(cdf)
+            }
+            KeyDistribution::Hotspot {
+                hot_keys,
+                hot_traffic,
+            } => {
+                assert!(
+                    (0.0..=1.0).contains(&hot_keys) && (0.0..=1.0).contains(&hot_traffic),
+                    "hot_keys and hot_traffic must be fractions"
+                );
+                let hot =
+                    ((self.num_keys as f64 * hot_keys).round() as u64).clamp(1, self.num_keys);
+                Sampler::Hotspot { hot, hot_traffic }
+            }
+        };
+        self.distribution = distribution;
+        self.sampler = Arc::new(sampler);
+        self
+    }
+
+    // Fraction of operations that are reads; the rest are writes.
+    pub fn with_read_ratio(mut self, read_ratio: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&read_ratio),
+            "read_ratio must be a fraction"
+        );
+        self.read_ratio = read_ratio;
+        self
+    }
+
+    pub fn with_arrival(mut self, arrival: Arrival) -> Self {
+        match arrival {
+            Arrival::Closed => {}
+            Arrival::Constant { per_second } | Arrival::Poisson { per_second } => {
+                assert!(per_second > 0.0, "per_second must be positive")
+            }
+            Arrival::Bursty { burst, .. } => assert!(burst > 0, "burst must be non-zero"),
+        }
+        self.arrival = arrival;
+        self
+    }
+
+    pub fn num_keys(&self) -> u64 {
+        self.num_keys
+    }
+
+    pub fn distribution(&self) -> &KeyDistribution {
+        &self.distribution
+    }
+
+    // The workload's stream for a single-threaded run.
+    pub fn stream(&self) -> Stream {
+        self.stream_for(0)
+    }
+
+    // The stream for thread `thread` of a multi-threaded run.
+    pub fn stream_for(&self, thread: u64) -> Stream {
+        Stream {
+            workload: self.clone(),
+            rng: SplitMix64::new(self.seed ^ thread.wrapping_mul(0xa076_1d64_78bd_642f)),
+            issued: 0,
+            at: Duration::ZERO,
+        }
+    }
+}
+
+// An endless, deterministic sequence of requests; bound it with `take`.
+#[derive(Debug, Clone)]
+pub struct Stream {
+    workload: Workload,
+    rng: SplitMix64,
+    issued: u64,
+    at: Duration,
+}
+
+impl Stream {
+    fn key(&mut self) -> u64 {
+        let n = self.workload.num_keys;
+        match &*self.workload.sampler {
+            Sampler::Uniform => self.rng.below(n),
+            Sampler::Zipf(cdf) => {
+                let u = self.rng.unit();
+                (cdf.partition_point(|&p| p < u) as u64).min(n - 1)
+            }
+            Sampler::Hotspot { hot, hot_traffic } => {
+                if *hot == n || self.rng.unit() < *hot_traffic {
+                    self.rng.below(*hot)
+                } else {
+                    hot + self.rng.below(n - hot)
+                }
+            }
+        }
+    }
+
+    fn next_arrival(&mut self) -> Duration {
+        let at = match self.workload.arrival {
+            Arrival::Closed => Duration::ZERO,
+            Arrival::Constant { per_second } => {
+                Duration::from_secs_f64(self.issued as f64 / per_second)
+            }
+            Arrival::Poisson { per_second } => {
+                // 1 - unit() is in (0, 1], so the logarithm is finite.
+                let gap = -(1.0 - self.rng.unit()).ln() / per_second;
+                self.at + Duration::from_secs_f64(gap)
+            }
+            Arrival::Bursty { burst, period } => {
+                let bursts = self.issued / burst as u64;
+                period.saturating_mul(bursts.try_into().unwrap_or(u32::MAX))
+            }
+        };
+        self.at = at;
+        at
+    }
+}
+
+impl Iterator for Stream {
+    type Item = Request;
+
+    fn next(&mut self) -> Option<Request> {
+        let at = self.next_arrival();
+        let key = self.key();
+        let op = if self.rng.unit() < self.workload.read_ratio {
+            Op::Read(key)
+        } else {
+            Op::Write(key)
+        };
+        self.issued += 1;
+        Some(Request { at, op })
+    }
+}
+
+// Small, fast and fully determined by its seed, which is all a workload
+// generator needs.
+#[derive(Debug, Clone)]
+struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0, 1).
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Uniform in 0..n, by multiply-shift; the bias is negligible for any
+    // key space a benchmark uses.
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next() as u128 * n as u128) >> 64) as u64
+    }
+}
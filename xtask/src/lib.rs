@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Golden files hold one `<benchmark name> <mean ns>` pair per line. They are
+// plain text so a refreshed baseline is easy to review in a diff.
+pub const DEFAULT_GOLDEN: &str = "benches/golden.txt";
+pub const DEFAULT_CRITERION_DIR: &str = "target/criterion";
+pub const DEFAULT_THRESHOLD_PCT: f64 = 10.0;
+
+#[derive(Debug)]
+pub enum BenchCheckError {
+    Io(io::Error),
+    Parse(String),
+    // No golden file was recorded yet.
+    MissingGolden(PathBuf),
+    // Not one golden benchmark has a current result to compare with, e.g.
+    // because the benches were not run or were all renamed.
+    NoComparisons,
+    Regressed(Vec<Comparison>),
+}
+
+impl fmt::Display for BenchCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BenchCheckError::Io(e) => write!(f, "i/o error: {}", e),
+            BenchCheckError::Parse(msg) => write!(f, "parse error: {}", msg),
+            BenchCheckError::MissingGolden(path) => write!(
+                f,
+                "no golden file at {}; run `cargo xtask bench-save` on the reference machine first",
+                path.display()
+            ),
+            BenchCheckError::NoComparisons => write!(
+                f,
+                "no benchmark in the golden file has a current result; run the benches or refresh the golden file with `cargo xtask bench-save`"
+            ),
+            BenchCheckError::Regressed(regressions) => {
+                writeln!(f, "{} benchmark(s) regressed:", regressions.len())?;
+                for c in regressions {
+                    writeln!(f, "  {}", c)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for BenchCheckError {}
+
+impl From<io::Error> for BenchCheckError {
+    fn from(e: io::Error) -> Self {
+        BenchCheckError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub name: String,
+    pub golden_ns: f64,
+    pub current_ns: f64,
+}
+
+impl Comparison {
+    // Positive values mean the benchmark got slower.
+    pub fn change_pct(&self) -> f64 {
+        (self.current_ns - self.golden_ns) / self.golden_ns * 100.0
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:.1} ns -> {:.1} ns ({:+.2}%)",
+            self.name,
+            self.golden_ns,
+            self.current_ns,
+            self.change_pct()
+        )
+    }
+}
+
+// Collects the mean time of the latest run of every benchmark criterion has
+// written under `criterion_dir`, keyed by benchmark id.
+pub fn read_estimates(criterion_dir: &Path) -> Result<BTreeMap<String, f64>, BenchCheckError> {
+    let mut estimates = BTreeMap::new();
+    collect_estimates(criterion_dir, criterion_dir, &mut estimates)?;
+    Ok(estimates)
+}
+
+fn collect_estimates(
+    root: &Path,
+    dir: &Path,
+    estimates: &mut BTreeMap<String, f64>,
+) -> Result<(), BenchCheckError> {
+    let file = dir.join("new").join("estimates.json");
+    if file.is_file() {
+        let name = dir
+            .strip_prefix(root)
+            .unwrap_or(dir)
+            .to_string_lossy()
+            .replace('\\', "/");
+        estimates.insert(name, parse_mean(&fs::read_to_string(&file)?, &file)?);
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() && path.file_name().is_some_and(|n| n != "report") {
+            collect_estimates(root, &path, estimates)?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_mean(json: &str, file: &Path) -> Result<f64, BenchCheckError> {
+    let value: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| BenchCheckError::Parse(format!("{}: {}", file.display(), e)))?;
+    value["mean"]["point_estimate"].as_f64().ok_or_else(|| {
+        BenchCheckError::Parse(format!("{}: missing mean.point_estimate", file.display()))
+    })
+}
+
+pub fn load_golden(path: &Path) -> Result<BTreeMap<String, f64>, BenchCheckError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(BenchCheckError::MissingGolden(path.to_path_buf()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut golden = BTreeMap::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = line
+            .rsplit_once(' ')
+            .and_then(|(name, ns)| Some((name.trim(), ns.parse::<f64>().ok()?)));
+        match parsed {
+            Some((name, ns)) => {
+                golden.insert(name.to_string(), ns);
+            }
+            None => {
+                return Err(BenchCheckError::Parse(format!(
+                    "{}:{}: expected `<name> <mean ns>`",
+                    path.display(),
+                    lineno + 1
+                )))
+            }
+        }
+    }
+    Ok(golden)
+}
+
+// Stores the latest criterion results as the new golden baseline and
+// returns the number of benchmarks written.
+pub fn save_golden(criterion_dir: &Path, golden_path: &Path) -> Result<usize, BenchCheckError> {
+    let estimates = read_estimates(criterion_dir)?;
+    let mut out = String::from("# benchmark mean_ns\n");
+    for (name, ns) in &estimates {
+        out.push_str(&format!("{} {:.3}\n", name, ns));
+    }
+    fs::write(golden_path, out)?;
+    Ok(estimates.len())
+}
+
+// Compares the latest criterion results against the golden baseline. Fails
+// with `Regressed` if any benchmark's mean time grew by more than
+// `max_regression_pct` percent. Benchmarks missing on either side are
+// skipped, so adding or retiring a benchmark does not fail the check, but
+// the check fails with `NoComparisons` if nothing was compared at all.
+pub fn bench_check(
+    criterion_dir: &Path,
+    golden_path: &Path,
+    max_regression_pct: f64,
+) -> Result<Vec<Comparison>, BenchCheckError> {
+    let golden = load_golden(golden_path)?;
+    let current = read_estimates(criterion_dir)?;
+
+    let comparisons: Vec<Comparison> = golden
+        .iter()
+        .filter_map(|(name, &golden_ns)| {
+            current.get(name).map(|&current_ns| Comparison {
+                name: name.clone(),
+                golden_ns,
+                current_ns,
+            })
+        })
+        .collect();
+    if comparisons.is_empty() {
+        return Err(BenchCheckError::NoComparisons);
+    }
+
+    let regressions: Vec<Comparison> = comparisons
+        .iter()
+        .filter(|c| c.change_pct() > max_regression_pct)
+        .cloned()
+        .collect();
+
+    if regressions.is_empty() {
+        Ok(comparisons)
+    } else {
+        Err(BenchCheckError::Regressed(regressions))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A fresh directory under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let dir = std::env::temp_dir().join(format!("xtask-test-{}-{}", std::process::id(), n));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn write(&self, relative: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(relative);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+            path
+        }
+
+        // Records a criterion result for `name` under `criterion/`.
+        fn estimate(&self, name: &str, mean_ns: f64) {
+            let json = format!(r#"{{"mean": {{"point_estimate": {}}}}}"#, mean_ns);
+            self.write(&format!("criterion/{}/new/estimates.json", name), &json);
+        }
+
+        fn criterion(&self) -> PathBuf {
+            self.0.join("criterion")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn load_golden_skips_comments_and_keeps_spaces_in_names() {
+        let dir = TempDir::new();
+        let path = dir.write("golden.txt", "# benchmark mean_ns\n\nqueue/enqueue 12.5\nmap insert/1000 3000\n");
+        let golden = load_golden(&path).unwrap();
+        assert_eq!(golden.len(), 2);
+        assert_eq!(golden["queue/enqueue"], 12.5);
+        assert_eq!(golden["map insert/1000"], 3000.0);
+    }
+
+    #[test]
+    fn load_golden_reports_bad_lines_and_missing_files() {
+        let dir = TempDir::new();
+        let path = dir.write("golden.txt", "queue/enqueue 12.5\nqueue/dequeue fast\n");
+        match load_golden(&path) {
+            Err(BenchCheckError::Parse(msg)) => assert!(msg.ends_with(":2: expected `<name> <mean ns>`"), "{msg}"),
+            other => panic!("unexpected {:?}", other),
+        }
+        let missing = dir.0.join("absent.txt");
+        let err = load_golden(&missing).unwrap_err();
+        assert!(matches!(&err, BenchCheckError::MissingGolden(p) if *p == missing));
+        assert!(err.to_string().contains("cargo xtask bench-save"));
+    }
+
+    #[test]
+    fn bench_check_flags_only_regressions_past_the_threshold() {
+        let dir = TempDir::new();
+        let golden = dir.write("golden.txt", "fast 100\nslow 100\nretired 100\n");
+        dir.estimate("fast", 105.0);
+        dir.estimate("slow", 125.0);
+        dir.estimate("added", 1.0);
+
+        match bench_check(&dir.criterion(), &golden, 10.0) {
+            Err(BenchCheckError::Regressed(regressions)) => {
+                assert_eq!(regressions.len(), 1);
+                assert_eq!(regressions[0].name, "slow");
+                assert_eq!(regressions[0].change_pct(), 25.0);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let comparisons = bench_check(&dir.criterion(), &golden, 30.0).unwrap();
+        let names: Vec<_> = comparisons.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["fast", "slow"]);
+    }
+
+    #[test]
+    fn bench_check_fails_when_nothing_is_compared() {
+        let dir = TempDir::new();
+        let golden = dir.write("golden.txt", "renamed 100\n");
+        dir.estimate("current", 100.0);
+        assert!(matches!(
+            bench_check(&dir.criterion(), &golden, 10.0),
+            Err(BenchCheckError::NoComparisons)
+        ));
+    }
+
+    #[test]
+    fn save_then_check_round_trips() {
+        let dir = TempDir::new();
+        dir.estimate("group/bench", 42.25);
+        let golden = dir.0.join("golden.txt");
+        assert_eq!(save_golden(&dir.criterion(), &golden).unwrap(), 1);
+        assert_eq!(load_golden(&golden).unwrap()["group/bench"], 42.25);
+        assert_eq!(bench_check(&dir.criterion(), &golden, 0.0).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,141 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+
+use xtask::{
+    bench_check, save_golden, BenchCheckError, DEFAULT_CRITERION_DIR, DEFAULT_GOLDEN,
+    DEFAULT_THRESHOLD_PCT,
+};
+
+const USAGE: &str = "usage:
+    cargo xtask bench-save  [--golden <path>] [--no-run]
+    cargo xtask bench-check [--golden <path>] [--threshold <pct>] [--no-run]";
+
+struct Options {
+    golden: PathBuf,
+    threshold: f64,
+    run: bool,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        golden: PathBuf::from(DEFAULT_GOLDEN),
+        threshold: DEFAULT_THRESHOLD_PCT,
+        run: true,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--golden" => {
+                options.golden = args.next().ok_or("--golden needs a path")?.into();
+            }
+            "--threshold" => {
+                options.threshold = args
+                    .next()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or("--threshold needs a number")?;
+            }
+            "--no-run" => options.run = false,
+            other => return Err(format!("unknown option `{}`", other)),
+        }
+    }
+    Ok(options)
+}
+
+fn run_benches(root: &Path) {
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let status = Command::new(cargo)
+        .args(["bench", "--package", "myqueue"])
+        .current_dir(root)
+        .status()
+        .expect("failed to run cargo bench");
+    if !status.success() {
+        exit(status.code().unwrap_or(1));
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let Some((command, rest)) = args.split_first() else {
+        eprintln!("{}", USAGE);
+        exit(2);
+    };
+    let options = parse_options(rest).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        exit(2);
+    });
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).parent().unwrap();
+    let criterion_dir = root.join(DEFAULT_CRITERION_DIR);
+    let golden = root.join(&options.golden);
+
+    match command.as_str() {
+        "bench-save" => {
+            if options.run {
+                run_benches(root);
+            }
+            match save_golden(&criterion_dir, &golden) {
+                Ok(n) => println!("saved {} benchmark(s) to {}", n, golden.display()),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        "bench-check" => {
+            // Checked up front so a missing baseline fails before the benches
+            // run for minutes.
+            if !golden.is_file() {
+                eprintln!("{}", BenchCheckError::MissingGolden(golden));
+                exit(1);
+            }
+            if options.run {
+                run_benches(root);
+            }
+            match bench_check(&criterion_dir, &golden, options.threshold) {
+                Ok(comparisons) => {
+                    for c in comparisons {
+                        println!("ok  {}", c);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn options_default_to_the_golden_file_and_threshold() {
+        let options = parse_options(&[]).unwrap();
+        assert_eq!(options.golden, PathBuf::from(DEFAULT_GOLDEN));
+        assert_eq!(options.threshold, DEFAULT_THRESHOLD_PCT);
+        assert!(options.run);
+    }
+
+    #[test]
+    fn options_are_parsed_and_checked() {
+        let options = parse_options(&args(&["--golden", "g.txt", "--threshold", "2.5", "--no-run"])).unwrap();
+        assert_eq!(options.golden, PathBuf::from("g.txt"));
+        assert_eq!(options.threshold, 2.5);
+        assert!(!options.run);
+
+        assert!(parse_options(&args(&["--threshold", "lots"])).is_err());
+        assert!(parse_options(&args(&["--golden"])).is_err());
+        assert_eq!(parse_options(&args(&["--fast"])).err().unwrap(), "unknown option `--fast`");
+    }
+}